@@ -7,7 +7,7 @@ use syn::{
     parse_macro_input,
     punctuated::Punctuated,
     token::Comma,
-    Attribute, FnArg, ItemFn,
+    Attribute, Data, DeriveInput, Expr, Fields, FnArg, Index, ItemFn, Path,
 };
 
 #[derive(Debug)]
@@ -110,3 +110,154 @@ pub fn worker(args: TokenStream, input: TokenStream) -> TokenStream {
 
     TokenStream::from(expanded)
 }
+
+/// Checks declared on a field with `#[job_data(...)]`.
+#[derive(Default)]
+struct FieldChecks {
+    min: Option<Expr>,
+    max: Option<Expr>,
+    non_empty: bool,
+}
+
+impl FieldChecks {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut checks = FieldChecks::default();
+
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("job_data")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("range") {
+                    meta.parse_nested_meta(|bound| {
+                        if bound.path.is_ident("min") {
+                            checks.min = Some(bound.value()?.parse()?);
+                            Ok(())
+                        } else if bound.path.is_ident("max") {
+                            checks.max = Some(bound.value()?.parse()?);
+                            Ok(())
+                        } else {
+                            Err(bound.error("expected `min` or `max`"))
+                        }
+                    })
+                } else if meta.path.is_ident("non_empty") {
+                    checks.non_empty = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `range(...)` or `non_empty`"))
+                }
+            })?;
+        }
+
+        Ok(checks)
+    }
+
+    fn expand(&self, name: &str, field: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+        let mut checks = vec![];
+
+        if let Some(min) = &self.min {
+            checks.push(quote! {
+                if #field < #min {
+                    return Err(::anyhow::anyhow!(
+                        "`{}` must be at least {:?}, got {:?}", #name, #min, #field
+                    ));
+                }
+            });
+        }
+
+        if let Some(max) = &self.max {
+            checks.push(quote! {
+                if #field > #max {
+                    return Err(::anyhow::anyhow!(
+                        "`{}` must be at most {:?}, got {:?}", #name, #max, #field
+                    ));
+                }
+            });
+        }
+
+        if self.non_empty {
+            checks.push(quote! {
+                if #field.is_empty() {
+                    return Err(::anyhow::anyhow!("`{}` must not be empty", #name));
+                }
+            });
+        }
+
+        quote! { #(#checks)* }
+    }
+}
+
+/// Implements `hornet::job::JobData` for a job payload, validating it before
+/// it's processed by a worker built with `validate_data`.
+///
+/// Fields accept `#[job_data(range(min = .., max = ..))]` (either bound can
+/// be omitted) and `#[job_data(non_empty)]`. The type accepts
+/// `#[job_data(validate = path::to::fn)]`, a `fn(&Self) -> anyhow::Result<()>`
+/// run after the field checks.
+#[proc_macro_derive(JobData, attributes(job_data))]
+pub fn derive_job_data(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match expand_job_data(input) {
+        Ok(expanded) => TokenStream::from(expanded),
+        Err(err) => TokenStream::from(err.to_compile_error()),
+    }
+}
+
+fn expand_job_data(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut custom: Option<Path> = None;
+
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("job_data"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("validate") {
+                custom = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `validate = path::to::fn`"))
+            }
+        })?;
+    }
+
+    let mut checks = vec![];
+
+    if let Data::Struct(data) = &input.data {
+        match &data.fields {
+            Fields::Named(fields) => {
+                for field in fields.named.iter() {
+                    let ident = field.ident.as_ref().unwrap();
+
+                    checks.push(
+                        FieldChecks::parse(&field.attrs)?
+                            .expand(&ident.to_string(), quote! { self.#ident }),
+                    );
+                }
+            }
+            Fields::Unnamed(fields) => {
+                for (i, field) in fields.unnamed.iter().enumerate() {
+                    let index = Index::from(i);
+
+                    checks.push(
+                        FieldChecks::parse(&field.attrs)?
+                            .expand(&i.to_string(), quote! { self.#index }),
+                    );
+                }
+            }
+            Fields::Unit => {}
+        }
+    }
+
+    let custom = custom.map(|path| quote! { #path(self)?; });
+
+    Ok(quote! {
+        impl #impl_generics ::hornet::job::JobData for #name #ty_generics #where_clause {
+            fn validate(&self) -> ::anyhow::Result<()> {
+                #(#checks)*
+                #custom
+                Ok(())
+            }
+        }
+    })
+}
@@ -0,0 +1,163 @@
+use crate::{
+    job::{BackoffOptions, BackoffStrategy, JobOptions},
+    log,
+};
+use rand::Rng;
+use std::{collections::HashMap, time::Duration};
+
+/// Backoff strategy registered on a worker with `Worker::backoff_strategy`,
+/// used for the jobs whose `backoff.type` is its name. It's given the number
+/// of attempts made so far and the error of the last one, and returns the
+/// delay before the next attempt, or `None` to fail the job without retrying
+/// it, e.g. to honor a `Retry-After` header or retry only in business hours.
+pub type CustomBackoffFn = fn(u32, &anyhow::Error) -> Option<Duration>;
+
+pub(crate) type CustomStrategies = HashMap<String, CustomBackoffFn>;
+
+/// Delay before the next attempt of a job with the given backoff, given the
+/// number of attempts made so far including the one that just failed. The
+/// jitter is drawn from `rng`, so a seeded one gives reproducible delays, e.g.
+/// to preview when a job will be retried. Custom strategies are not known
+/// here and get no delay.
+pub fn compute(backoff: &BackoffOptions, attempts_made: u32, rng: &mut impl Rng) -> Duration {
+    let delay = match backoff.strategy {
+        BackoffStrategy::Fixed => backoff.delay,
+        BackoffStrategy::Exponential => {
+            let exponent = attempts_made.saturating_sub(1).min(63);
+
+            backoff.delay.saturating_mul(1u64 << exponent)
+        }
+        BackoffStrategy::Custom(_) => 0,
+    };
+
+    Duration::from_millis(apply_jitter(delay, backoff.jitter, rng))
+}
+
+/// Delay in milliseconds before retrying a job that failed with `err`, or
+/// `None` if a custom strategy decided not to retry it. Fails for a custom
+/// strategy the worker doesn't know, so the job isn't retried in a tight loop.
+pub(crate) fn retry_delay(
+    opts: &JobOptions,
+    attempts_made: u32,
+    err: &anyhow::Error,
+    custom: &CustomStrategies,
+) -> anyhow::Result<Option<u64>> {
+    match opts.backoff.as_ref().map(|backoff| &backoff.strategy) {
+        Some(BackoffStrategy::Custom(name)) => match custom.get(name) {
+            Some(strategy) => {
+                Ok(strategy(attempts_made, err).map(|delay| delay.as_millis() as u64))
+            }
+            None => {
+                log::warning!("Unknown backoff strategy {}, not retrying", name);
+
+                Err(anyhow::anyhow!(
+                    "Unknown backoff strategy {}, register it with Worker::backoff_strategy",
+                    name
+                ))
+            }
+        },
+        _ => Ok(Some(opts.backoff_delay(attempts_made))),
+    }
+}
+
+/// Spreads `delay` uniformly over `delay ± delay * jitter`.
+fn apply_jitter(delay: u64, jitter: f64, rng: &mut impl Rng) -> u64 {
+    let jitter = jitter.clamp(0.0, 1.0);
+
+    if jitter == 0.0 || delay == 0 {
+        return delay;
+    }
+
+    let offset = (rng.gen::<f64>() * 2.0 - 1.0) * jitter;
+
+    (delay as f64 * (1.0 + offset)).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn backoff(strategy: BackoffStrategy, jitter: f64) -> BackoffOptions {
+        BackoffOptions {
+            strategy,
+            delay: 1000,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn computes_delays_without_jitter() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let fixed = backoff(BackoffStrategy::Fixed, 0.0);
+        let exponential = backoff(BackoffStrategy::Exponential, 0.0);
+
+        assert_eq!(compute(&fixed, 3, &mut rng), Duration::from_secs(1));
+        assert_eq!(compute(&exponential, 1, &mut rng), Duration::from_secs(1));
+        assert_eq!(compute(&exponential, 4, &mut rng), Duration::from_secs(8));
+        assert_eq!(
+            compute(&exponential, 200, &mut rng),
+            Duration::from_millis(u64::MAX)
+        );
+        assert_eq!(
+            compute(
+                &backoff(BackoffStrategy::Custom("x".into()), 0.0),
+                1,
+                &mut rng
+            ),
+            Duration::ZERO
+        );
+    }
+
+    fn retry_after(attempts: u32, err: &anyhow::Error) -> Option<Duration> {
+        let secs = err.to_string().strip_prefix("retry after ")?.parse().ok()?;
+
+        (attempts < 3).then(|| Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn uses_custom_strategies_by_name() {
+        let custom = CustomStrategies::from([("retry-after".to_string(), retry_after as _)]);
+        let opts = |strategy: &str| JobOptions {
+            backoff: Some(backoff(BackoffStrategy::Custom(strategy.to_string()), 0.0)),
+            ..Default::default()
+        };
+        let err = anyhow::anyhow!("retry after 5");
+
+        assert_eq!(
+            retry_delay(&opts("retry-after"), 1, &err, &custom).unwrap(),
+            Some(5000)
+        );
+        assert_eq!(
+            retry_delay(&opts("retry-after"), 3, &err, &custom).unwrap(),
+            None
+        );
+        assert_eq!(
+            retry_delay(&opts("retry-after"), 1, &anyhow::anyhow!("boom"), &custom).unwrap(),
+            None
+        );
+        assert!(retry_delay(&opts("unknown"), 1, &err, &custom).is_err());
+        assert_eq!(
+            retry_delay(&JobOptions::default(), 1, &err, &custom).unwrap(),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn draws_jitter_from_the_given_rng() {
+        let exponential = backoff(BackoffStrategy::Exponential, 0.5);
+        let delays = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+
+            (1..=5)
+                .map(|attempts| compute(&exponential, attempts, &mut rng))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(delays(7), delays(7));
+        assert_ne!(delays(7), delays(8));
+        assert!(delays(7).iter().all(|delay| (Duration::from_millis(500)
+            ..=Duration::from_millis(24_000))
+            .contains(delay)));
+    }
+}
@@ -0,0 +1,319 @@
+use anyhow::Result;
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    connection::TimeoutClient,
+    scripts::{
+        move_to_active::{MoveToActive, MoveToActiveArgs, MoveToActiveReturn},
+        move_to_delayed::{MoveToDelayed, MoveToDelayedReturn},
+        move_to_finished::{
+            MoveToFinished, MoveToFinishedArgs, MoveToFinishedReturn, MoveToFinishedTarget,
+        },
+        remove_job::RemoveJob,
+        retry_job::{RetryJob, RetryJobReturn},
+    },
+};
+
+lazy_static! {
+    static ref MOVE_TO_ACTIVE: MoveToActive = MoveToActive::new();
+    static ref MOVE_TO_DELAYED: MoveToDelayed = MoveToDelayed::new();
+    static ref MOVE_TO_FINISHED: MoveToFinished = MoveToFinished::new();
+    static ref RETRY_JOB: RetryJob = RetryJob::new();
+    static ref REMOVE_JOB: RemoveJob = RemoveJob::new();
+}
+
+/// Scripts a worker's processor tasks run to fetch jobs and hand them back
+/// once processed. `RedisCommands` runs them against Redis, tests swap it
+/// for `MockCommands` to check the worker's logic (retries, fetching the
+/// next job...) without a server.
+pub(crate) trait Commands<Data>: Send + Sync {
+    fn move_to_active(
+        &self,
+        prefix: &str,
+        client: &mut TimeoutClient,
+        args: MoveToActiveArgs,
+    ) -> Result<MoveToActiveReturn<Data>>;
+
+    /// Moves the job to `target`, with `return_value` being its encoded
+    /// return value or its failed reason.
+    fn move_to_finished(
+        &self,
+        prefix: &str,
+        client: &mut TimeoutClient,
+        job_id: &str,
+        return_value: &[u8],
+        target: MoveToFinishedTarget,
+        args: MoveToFinishedArgs,
+    ) -> Result<MoveToFinishedReturn<Data>>;
+
+    fn retry_job(
+        &self,
+        prefix: &str,
+        client: &mut TimeoutClient,
+        job_id: &str,
+        token: &str,
+    ) -> Result<RetryJobReturn>;
+
+    /// Moves the job to the delayed set, to be retried in `delay` ms.
+    fn move_to_delayed(
+        &self,
+        prefix: &str,
+        client: &mut TimeoutClient,
+        job_id: &str,
+        token: &str,
+        delay: u64,
+    ) -> Result<MoveToDelayedReturn>;
+
+    /// Removes the job, returning whether it was removed, e.g. before
+    /// processing it with `DeliveryMode::AtMostOnce`.
+    fn remove_job(
+        &self,
+        prefix: &str,
+        client: &mut TimeoutClient,
+        job_id: &str,
+        remove_children: bool,
+    ) -> Result<bool>;
+}
+
+pub(crate) struct RedisCommands;
+
+impl<Data: DeserializeOwned> Commands<Data> for RedisCommands {
+    fn move_to_active(
+        &self,
+        prefix: &str,
+        client: &mut TimeoutClient,
+        args: MoveToActiveArgs,
+    ) -> Result<MoveToActiveReturn<Data>> {
+        MOVE_TO_ACTIVE.run(prefix, client, args)
+    }
+
+    fn move_to_finished(
+        &self,
+        prefix: &str,
+        client: &mut TimeoutClient,
+        job_id: &str,
+        return_value: &[u8],
+        target: MoveToFinishedTarget,
+        args: MoveToFinishedArgs,
+    ) -> Result<MoveToFinishedReturn<Data>> {
+        MOVE_TO_FINISHED.run(prefix, client, job_id, return_value, target, args)
+    }
+
+    fn retry_job(
+        &self,
+        prefix: &str,
+        client: &mut TimeoutClient,
+        job_id: &str,
+        token: &str,
+    ) -> Result<RetryJobReturn> {
+        RETRY_JOB.run(prefix, client, job_id, token)
+    }
+
+    fn move_to_delayed(
+        &self,
+        prefix: &str,
+        client: &mut TimeoutClient,
+        job_id: &str,
+        token: &str,
+        delay: u64,
+    ) -> Result<MoveToDelayedReturn> {
+        MOVE_TO_DELAYED.run(prefix, client, job_id, token, delay)
+    }
+
+    fn remove_job(
+        &self,
+        prefix: &str,
+        client: &mut TimeoutClient,
+        job_id: &str,
+        remove_children: bool,
+    ) -> Result<bool> {
+        REMOVE_JOB.run(prefix, client, job_id, remove_children)
+    }
+}
+
+#[cfg(test)]
+pub(crate) use mock::{Call, MockCommands};
+
+#[cfg(test)]
+mod mock {
+    use std::{
+        collections::{HashMap, HashSet, VecDeque},
+        sync::Mutex,
+    };
+
+    use super::*;
+
+    /// Command run on a `MockCommands`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub(crate) enum Call {
+        MoveToActive,
+        MoveToFinished {
+            job_id: String,
+            return_value: Vec<u8>,
+            target: MoveToFinishedTarget,
+        },
+        RetryJob {
+            job_id: String,
+        },
+        MoveToDelayed {
+            job_id: String,
+            delay: u64,
+        },
+        RemoveJob {
+            job_id: String,
+        },
+    }
+
+    /// Serves the queued jobs, in order, to `move_to_active` and records the
+    /// commands it's given. Jobs queued with `next` are returned by
+    /// `move_to_finished` instead when it's asked to fetch the next job.
+    /// Replies queued with `job_on` are only served to the queue they're
+    /// queued on, one per poll. Jobs marked with `pending_dependencies` can't
+    /// be finished, as parents with children left.
+    pub(crate) struct MockCommands<Data> {
+        jobs: Mutex<VecDeque<(MoveToActiveReturn<Data>, bool)>>,
+        queues: Mutex<HashMap<String, VecDeque<MoveToActiveReturn<Data>>>>,
+        pending: Mutex<HashSet<String>>,
+        calls: Mutex<Vec<Call>>,
+    }
+
+    impl<Data> MockCommands<Data> {
+        pub fn new() -> Self {
+            MockCommands {
+                jobs: Mutex::default(),
+                queues: Mutex::default(),
+                pending: Mutex::default(),
+                calls: Mutex::default(),
+            }
+        }
+
+        pub fn job(self, job: MoveToActiveReturn<Data>) -> Self {
+            self.jobs.lock().unwrap().push_back((job, false));
+            self
+        }
+
+        pub fn job_on(self, prefix: &str, job: MoveToActiveReturn<Data>) -> Self {
+            self.queues
+                .lock()
+                .unwrap()
+                .entry(prefix.to_string())
+                .or_default()
+                .push_back(job);
+            self
+        }
+
+        pub fn next(self, job: MoveToActiveReturn<Data>) -> Self {
+            self.jobs.lock().unwrap().push_back((job, true));
+            self
+        }
+
+        pub fn pending_dependencies(self, job_id: &str) -> Self {
+            self.pending.lock().unwrap().insert(job_id.to_string());
+            self
+        }
+
+        pub fn calls(&self) -> Vec<Call> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn record(&self, call: Call) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    impl<Data: Send> Commands<Data> for MockCommands<Data> {
+        fn move_to_active(
+            &self,
+            prefix: &str,
+            _client: &mut TimeoutClient,
+            _args: MoveToActiveArgs,
+        ) -> Result<MoveToActiveReturn<Data>> {
+            self.record(Call::MoveToActive);
+
+            if let Some(replies) = self.queues.lock().unwrap().get_mut(prefix) {
+                return Ok(replies.pop_front().unwrap_or(MoveToActiveReturn::None));
+            }
+
+            let mut jobs = self.jobs.lock().unwrap();
+
+            Ok(match jobs.front() {
+                Some((_, false)) => jobs.pop_front().unwrap().0,
+                _ => MoveToActiveReturn::None,
+            })
+        }
+
+        fn move_to_finished(
+            &self,
+            _prefix: &str,
+            _client: &mut TimeoutClient,
+            job_id: &str,
+            return_value: &[u8],
+            target: MoveToFinishedTarget,
+            args: MoveToFinishedArgs,
+        ) -> Result<MoveToFinishedReturn<Data>> {
+            self.record(Call::MoveToFinished {
+                job_id: job_id.to_string(),
+                return_value: return_value.to_vec(),
+                target,
+            });
+
+            if self.pending.lock().unwrap().contains(job_id) {
+                return Ok(MoveToFinishedReturn::JobHasPendingDependencies);
+            }
+
+            let mut jobs = self.jobs.lock().unwrap();
+
+            Ok(match jobs.front() {
+                Some((_, true)) if args.fetch_next => {
+                    MoveToFinishedReturn::Next(jobs.pop_front().unwrap().0)
+                }
+                _ => MoveToFinishedReturn::Ok,
+            })
+        }
+
+        fn retry_job(
+            &self,
+            _prefix: &str,
+            _client: &mut TimeoutClient,
+            job_id: &str,
+            _token: &str,
+        ) -> Result<RetryJobReturn> {
+            self.record(Call::RetryJob {
+                job_id: job_id.to_string(),
+            });
+
+            Ok(RetryJobReturn::Ok)
+        }
+
+        fn move_to_delayed(
+            &self,
+            _prefix: &str,
+            _client: &mut TimeoutClient,
+            job_id: &str,
+            _token: &str,
+            delay: u64,
+        ) -> Result<MoveToDelayedReturn> {
+            self.record(Call::MoveToDelayed {
+                job_id: job_id.to_string(),
+                delay,
+            });
+
+            Ok(MoveToDelayedReturn::Ok)
+        }
+
+        fn remove_job(
+            &self,
+            _prefix: &str,
+            _client: &mut TimeoutClient,
+            job_id: &str,
+            _remove_children: bool,
+        ) -> Result<bool> {
+            self.record(Call::RemoveJob {
+                job_id: job_id.to_string(),
+            });
+
+            Ok(true)
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{
+    connection::TimeoutClient,
+    job::JobOutcome,
+    lock_manager::ActiveJobs,
+    log,
+    scripts::move_to_finished::{
+        FinishedJob, MoveToFinished, MoveToFinishedReturn, MoveToFinishedTarget,
+    },
+};
+use lazy_static::lazy_static;
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+lazy_static! {
+    static ref MOVE_TO_FINISHED: MoveToFinished = MoveToFinished::new();
+}
+
+/// A successfully processed job waiting to be moved to completed.
+pub(crate) struct PendingCompletion {
+    pub job: FinishedJob,
+    pub outcome: JobOutcome,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BatchOptions {
+    pub max_size: usize,
+    pub flush_interval: Duration,
+}
+
+/// Buffers completed jobs so they are moved to completed in a single round
+/// trip, once `max_size` of them are pending or every `flush_interval`.
+/// Buffered jobs stay in `active_jobs` so their locks keep being extended
+/// until they are flushed. Clones share the same buffer.
+#[derive(Clone)]
+pub(crate) struct CompletionBatcher {
+    pending: Arc<Mutex<Vec<PendingCompletion>>>,
+    flusher: Arc<Flusher>,
+}
+
+struct Flusher {
+    opts: BatchOptions,
+    prefix: String,
+    client: TimeoutClient,
+    active_jobs: ActiveJobs,
+    on_completed: Option<fn(&JobOutcome)>,
+    after_process: Option<fn(&JobOutcome)>,
+    #[cfg(feature = "metrics")]
+    queue_name: String,
+}
+
+impl CompletionBatcher {
+    pub fn new(
+        opts: BatchOptions,
+        prefix: String,
+        client: TimeoutClient,
+        active_jobs: ActiveJobs,
+        on_completed: Option<fn(&JobOutcome)>,
+        after_process: Option<fn(&JobOutcome)>,
+        #[cfg(feature = "metrics")] queue_name: String,
+    ) -> Self {
+        CompletionBatcher {
+            pending: Arc::new(Mutex::new(Vec::new())),
+            flusher: Arc::new(Flusher {
+                opts: BatchOptions {
+                    max_size: opts.max_size.max(1),
+                    ..opts
+                },
+                prefix,
+                client,
+                active_jobs,
+                on_completed,
+                after_process,
+                #[cfg(feature = "metrics")]
+                queue_name,
+            }),
+        }
+    }
+
+    /// Buffers the job, flushing the buffer if it's full.
+    pub fn push(&self, completion: PendingCompletion) {
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.push(completion);
+            pending.len() >= self.flusher.opts.max_size
+        };
+
+        if full {
+            self.flush();
+        }
+    }
+
+    /// Moves every buffered job to completed right away.
+    pub fn flush(&self) {
+        self.flusher.flush(&self.pending);
+    }
+
+    /// Starts a task flushing the buffer every `flush_interval`. It stops once
+    /// every clone of the batcher is dropped.
+    pub fn spawn_flusher(&self) {
+        let pending = Arc::downgrade(&self.pending);
+        let flusher = self.flusher.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(flusher.opts.flush_interval);
+
+            loop {
+                interval.tick().await;
+
+                let Some(pending) = pending.upgrade() else {
+                    return;
+                };
+
+                flusher.flush(&pending);
+            }
+        });
+    }
+}
+
+impl Flusher {
+    fn flush(&self, pending: &Mutex<Vec<PendingCompletion>>) {
+        let batch = mem::take(&mut *pending.lock().unwrap());
+
+        if batch.is_empty() {
+            return;
+        }
+
+        let (jobs, outcomes): (Vec<FinishedJob>, Vec<JobOutcome>) = batch
+            .into_iter()
+            .map(|completion| (completion.job, completion.outcome))
+            .unzip();
+
+        let res = MOVE_TO_FINISHED.run_batch(
+            &self.prefix,
+            &mut self.client.clone(),
+            MoveToFinishedTarget::Completed,
+            &jobs,
+        );
+
+        for job in jobs.iter() {
+            self.active_jobs.untrack(&job.job_id);
+        }
+
+        match res {
+            Ok(results) => {
+                for (outcome, res) in outcomes.iter().zip(results) {
+                    match res {
+                        MoveToFinishedReturn::Ok => {
+                            #[cfg(feature = "metrics")]
+                            crate::metrics::job_completed(&self.queue_name, outcome);
+
+                            if let Some(on_completed) = self.on_completed {
+                                on_completed(outcome);
+                            }
+                        }
+                        // Parents are completed once their last child is
+                        MoveToFinishedReturn::JobHasPendingDependencies => {}
+                        res => {
+                            log::warning!("Error moving job to completed: {:?}", res);
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                log::warning!("Error moving {} jobs to completed: {:?}", jobs.len(), err);
+            }
+        }
+
+        if let Some(after_process) = self.after_process {
+            for outcome in outcomes.iter() {
+                after_process(outcome);
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{
+    connection::{redacted_addr, with_protocol, ProtocolVersion, TimeoutClient},
+    worker::WorkerOptions,
+};
+use anyhow::Result;
+use redis::Client;
+
+/// Redis client and default options shared by every `Queue` and `Worker` of a
+/// process, built with `Queue::from_context` and `Worker::from_context`, so
+/// that the connection settings are only configured once. They also share a
+/// pool of connections for their commands, instead of each opening its own.
+#[derive(Clone)]
+pub struct HornetContext {
+    client: Client,
+    /// Shared by the clones of the context
+    commands: TimeoutClient,
+    worker_options: WorkerOptions,
+}
+
+impl std::fmt::Debug for HornetContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HornetContext")
+            .field("redis", &redacted_addr(&self.client))
+            .field("worker_options", &self.worker_options)
+            .finish()
+    }
+}
+
+impl HornetContext {
+    pub fn new(redis_url: &str) -> Result<Self> {
+        let client = Client::open(redis_url)?;
+
+        Ok(HornetContext {
+            commands: TimeoutClient::new(client.clone(), None),
+            client,
+            worker_options: WorkerOptions::default(),
+        })
+    }
+
+    /// Protocol used to talk to Redis. RESP2 unless the url selects RESP3.
+    pub fn protocol(mut self, protocol: ProtocolVersion) -> Self {
+        self.client = with_protocol(&self.client, protocol);
+        self.reset_commands();
+        self
+    }
+
+    /// Options of the workers built from this context. Their
+    /// `command_timeout` applies to the queues' commands too, as they share
+    /// the same connections.
+    pub fn worker_options(mut self, opts: WorkerOptions) -> Self {
+        self.worker_options = opts;
+        self.reset_commands();
+        self
+    }
+
+    fn reset_commands(&mut self) {
+        self.commands =
+            TimeoutClient::new(self.client.clone(), self.worker_options.command_timeout);
+    }
+
+    pub(crate) fn client(&self) -> Client {
+        self.client.clone()
+    }
+
+    /// Connections shared by the queues and workers built from the context.
+    pub(crate) fn command_client(&self) -> TimeoutClient {
+        self.commands.clone()
+    }
+
+    pub(crate) fn default_worker_options(&self) -> WorkerOptions {
+        self.worker_options.clone()
+    }
+}
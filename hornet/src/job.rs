@@ -1,8 +1,565 @@
-use serde::Deserialize;
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    marker::PhantomData,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-#[derive(Debug, Deserialize)]
+use anyhow::{anyhow, bail, Result};
+use lazy_static::lazy_static;
+use redis::{Client, Commands};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{value::RawValue, Value};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    backoff,
+    queue_events::QueueEvents,
+    queue_keys::QueueKeys,
+    result_store::{delete_released, recorded_reference, rehydrate, ResultStore},
+    scripts::{
+        add_log::{AddLog, AddLogReturn},
+        get_state::GetState,
+        remove_job::RemoveJob,
+    },
+};
+
+pub use hornet_macros::JobData;
+
+lazy_static! {
+    static ref ADD_LOG: AddLog = AddLog::new();
+    static ref GET_STATE: GetState = GetState::new();
+    static ref REMOVE_JOB: RemoveJob = RemoveJob::new();
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct JobOptions {
+    #[serde(rename = "jobId", default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(default)]
     pub attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<BackoffOptions>,
+    /// Maximum number of log lines kept for the job, all of them if unset and
+    /// none if 0
+    #[serde(
+        rename = "keepLogs",
+        alias = "kl",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub keep_logs: Option<u32>,
+    /// Retention applied by the worker once the job completes, all completed
+    /// jobs being kept if unset
+    #[serde(
+        rename = "removeOnComplete",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub remove_on_complete: Option<KeepJobs>,
+    /// Same as `remove_on_complete`, once the job fails for good
+    #[serde(
+        rename = "removeOnFail",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub remove_on_fail: Option<KeepJobs>,
+    /// Milliseconds after which the job is removed if it has not started
+    /// processing yet, for jobs that become irrelevant after a while. The
+    /// expiry is lifted once a worker picks the job up. Its id is left in the
+    /// wait list or delayed set until a worker skips it or
+    /// `Queue::get_job_counts` sweeps it.
+    #[serde(rename = "ttl", default, skip_serializing_if = "Option::is_none")]
+    pub ttl_ms: Option<u64>,
+    #[serde(rename = "de", default, skip_serializing_if = "Option::is_none")]
+    pub deduplication: Option<DeduplicationOptions>,
+    /// Number of stack traces, one per failed attempt, kept on the job,
+    /// `DEFAULT_STACK_TRACE_LIMIT` if unset
+    #[serde(
+        rename = "stackTraceLimit",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub stack_trace_limit: Option<u32>,
+    /// Maximum size in bytes of the job's serialized data, overriding
+    /// `Queue::size_limit`. Larger jobs are rejected with `JobTooLarge`.
+    #[serde(rename = "sizeLimit", default, skip_serializing_if = "Option::is_none")]
+    pub size_limit: Option<usize>,
+    /// Milliseconds the job waits in the delayed set before it can be
+    /// processed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay: Option<u64>,
+    /// Concurrency units the job occupies on the worker processing it, 1 if
+    /// unset, e.g. 10 for a transcode among emails. Capped at the worker's
+    /// concurrency. The job is fetched before its units are free, and waits
+    /// for them in the active state, its lock being kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    /// Timestamp in ms at which the job is due, overriding `delay`, see
+    /// `delay_until`
+    #[serde(skip)]
+    pub due_at: Option<u64>,
+    /// Tenant the job belongs to, prefixing its id as `<namespace>/<id>`, see
+    /// `WorkerOptions::namespace`. Without a `job_id`, a random one is used.
+    #[serde(skip)]
+    pub namespace: Option<String>,
+    /// Whether `job_id` was taken from the queue's counter, allowing it to be
+    /// an integer, see `reserved_id`
+    #[serde(skip)]
+    pub id_reserved: bool,
+}
+
+/// Id taken from a queue's counter without adding a job, see
+/// `Queue::reserve_id`. No job generated by the queue gets it, so it can be
+/// handed out before the job is added with `JobOptions::reserved_id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReservedId(pub(crate) String);
+
+impl ReservedId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Stack traces kept on a job whose options don't set `stack_trace_limit`.
+pub const DEFAULT_STACK_TRACE_LIMIT: u32 = 10;
+
+/// Error returned when adding a job whose options BullMQ would reject or
+/// misinterpret, see `JobOptions::validate`. It can be told apart with
+/// `err.downcast_ref::<InvalidJobOptions>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidJobOptions {
+    /// Name of the option, as in BullMQ, e.g. `jobId`
+    pub option: &'static str,
+    pub reason: String,
+}
+
+impl std::fmt::Display for InvalidJobOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid job option `{}`: {}", self.option, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidJobOptions {}
+
+/// Debounces jobs: a job added while another one with the same `id` was
+/// added less than `ttl` ms ago is not enqueued, `Queue::add` returns the
+/// handle of the earlier job instead. Without a `ttl`, the id is taken
+/// until that job completes, fails or is removed. `JobHandle::remove` frees it
+/// even before the `ttl` elapses.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeduplicationOptions {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+/// How many finished jobs to keep. Jobs older than `age` seconds are removed,
+/// and at most `count` are kept (0 removes the job right away, a negative
+/// count keeps them all). BullMQ also accepts a boolean (`true` removes the
+/// job, `false` keeps it) or a plain count.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RawKeepJobs")]
+pub struct KeepJobs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawKeepJobs {
+    Remove(bool),
+    Count(i32),
+    Options {
+        #[serde(default)]
+        count: Option<i32>,
+        #[serde(default)]
+        age: Option<u64>,
+    },
+}
+
+impl From<RawKeepJobs> for KeepJobs {
+    fn from(raw: RawKeepJobs) -> Self {
+        match raw {
+            RawKeepJobs::Remove(true) => KeepJobs::count(0),
+            RawKeepJobs::Remove(false) => KeepJobs::all(),
+            RawKeepJobs::Count(count) => KeepJobs::count(count),
+            RawKeepJobs::Options { count, age } => KeepJobs { count, age },
+        }
+    }
+}
+
+impl KeepJobs {
+    pub fn all() -> Self {
+        KeepJobs::count(-1)
+    }
+
+    pub fn count(count: i32) -> Self {
+        KeepJobs {
+            count: Some(count),
+            age: None,
+        }
+    }
+
+    /// Keeps jobs finished less than `age` seconds ago.
+    pub fn age(age: u64) -> Self {
+        KeepJobs {
+            count: None,
+            age: Some(age),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackoffStrategy {
+    Fixed,
+    Exponential,
+    Custom(String),
+}
+
+/// Delay applied between retries. BullMQ accepts either a plain number of
+/// milliseconds (fixed backoff) or `{ type, delay, jitter }`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RawBackoffOptions", into = "RawBackoffOptions")]
+pub struct BackoffOptions {
+    pub strategy: BackoffStrategy,
+    pub delay: u64,
+    /// Fraction, between 0 and 1, by which the computed delay is randomly
+    /// increased or decreased so that jobs failing together don't all retry
+    /// at the same time.
+    pub jitter: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawBackoffOptions {
+    Delay(u64),
+    Options {
+        #[serde(rename = "type")]
+        strategy: String,
+        #[serde(default)]
+        delay: u64,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        jitter: f64,
+    },
+}
+
+fn is_zero(jitter: &f64) -> bool {
+    *jitter == 0.0
+}
+
+impl From<RawBackoffOptions> for BackoffOptions {
+    fn from(raw: RawBackoffOptions) -> Self {
+        match raw {
+            RawBackoffOptions::Delay(delay) => BackoffOptions {
+                strategy: BackoffStrategy::Fixed,
+                delay,
+                jitter: 0.0,
+            },
+            RawBackoffOptions::Options {
+                strategy,
+                delay,
+                jitter,
+            } => BackoffOptions {
+                strategy: match strategy.as_str() {
+                    "fixed" => BackoffStrategy::Fixed,
+                    "exponential" => BackoffStrategy::Exponential,
+                    _ => BackoffStrategy::Custom(strategy),
+                },
+                delay,
+                jitter,
+            },
+        }
+    }
+}
+
+impl From<BackoffOptions> for RawBackoffOptions {
+    fn from(backoff: BackoffOptions) -> Self {
+        RawBackoffOptions::Options {
+            strategy: match backoff.strategy {
+                BackoffStrategy::Fixed => "fixed".to_string(),
+                BackoffStrategy::Exponential => "exponential".to_string(),
+                BackoffStrategy::Custom(strategy) => strategy,
+            },
+            delay: backoff.delay,
+            jitter: backoff.jitter,
+        }
+    }
+}
+
+impl BackoffOptions {
+    /// Delay in milliseconds before the next attempt, given the number of
+    /// attempts made so far including the one that just failed. See
+    /// `backoff::compute` for reproducible delays.
+    pub fn delay_for(&self, attempts_made: u32) -> u64 {
+        backoff::compute(self, attempts_made, &mut rand::thread_rng()).as_millis() as u64
+    }
+}
+
+impl JobOptions {
+    /// Retention applied when the job completes, keeping it by default.
+    pub fn keep_completed(&self) -> KeepJobs {
+        self.remove_on_complete.unwrap_or_else(KeepJobs::all)
+    }
+
+    /// Retention applied when the job fails for good, keeping it by default.
+    pub fn keep_failed(&self) -> KeepJobs {
+        self.remove_on_fail.unwrap_or_else(KeepJobs::all)
+    }
+
+    /// Schedules the job at the given timestamp in ms, e.g. 9am tomorrow for
+    /// a reminder. The delay is computed from the clock when the job is
+    /// added, scoring it in the delayed set at that very timestamp. A
+    /// timestamp already past by then adds the job right away.
+    pub fn delay_until(mut self, timestamp_ms: u64) -> Self {
+        self.due_at = Some(timestamp_ms);
+        self
+    }
+
+    /// Puts the job in a namespace, see `namespace`.
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    pub fn weight(mut self, weight: u32) -> Self {
+        self.weight = Some(weight);
+        self
+    }
+
+    /// Units the job occupies on a worker with `capacity` of them, see
+    /// `weight`.
+    pub(crate) fn units(&self, capacity: u32) -> u32 {
+        self.weight.unwrap_or(1).clamp(1, capacity.max(1))
+    }
+
+    /// Adds the job under an id taken beforehand with `Queue::reserve_id`.
+    pub fn reserved_id(mut self, id: ReservedId) -> Self {
+        self.job_id = Some(id.0);
+        self.id_reserved = true;
+        self
+    }
+
+    /// The options of a job added at `timestamp`, with `due_at` turned into
+    /// the matching `delay` and the ids prefixed with the namespace.
+    pub(crate) fn resolved(&self, timestamp: u64) -> JobOptions {
+        let mut opts = JobOptions {
+            delay: self.delay_at(timestamp),
+            due_at: None,
+            namespace: None,
+            ..self.clone()
+        };
+
+        if let Some(namespace) = &self.namespace {
+            let job_id = opts
+                .job_id
+                .take()
+                .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+            opts.job_id = Some(format!("{}/{}", namespace, job_id));
+
+            // Tenants may use the same deduplication ids
+            if let Some(deduplication) = &mut opts.deduplication {
+                deduplication.id = format!("{}/{}", namespace, deduplication.id);
+            }
+        }
+
+        opts
+    }
+
+    /// Delay of the job if added at `timestamp`, `None` if it's due by then.
+    fn delay_at(&self, timestamp: u64) -> Option<u64> {
+        let delay = match self.due_at {
+            Some(due_at) => Some(due_at.saturating_sub(timestamp)),
+            None => self.delay,
+        };
+
+        delay.filter(|delay| *delay > 0)
+    }
+
+    /// Whether the job goes to the delayed set when added at `timestamp`.
+    pub(crate) fn is_delayed_at(&self, timestamp: u64) -> bool {
+        self.delay_at(timestamp).is_some()
+    }
+
+    /// Appends the trace of a failed attempt to the job's `stacktrace`,
+    /// dropping the oldest ones beyond `stack_trace_limit`.
+    pub fn push_stacktrace(&self, stacktrace: &mut Vec<String>, trace: String) {
+        let limit = self.stack_trace_limit.unwrap_or(DEFAULT_STACK_TRACE_LIMIT) as usize;
+
+        stacktrace.push(trace);
+        stacktrace.drain(..stacktrace.len().saturating_sub(limit));
+    }
+
+    pub fn backoff_delay(&self, attempts_made: u32) -> u64 {
+        self.backoff
+            .as_ref()
+            .map(|backoff| backoff.delay_for(attempts_made))
+            .unwrap_or(0)
+    }
+
+    /// Checks the options before they are sent to Redis, where the scripts
+    /// would either reject them with a cryptic error or store a job that
+    /// doesn't behave as expected. Called by every `Queue::add` variant.
+    pub fn validate(&self) -> Result<(), InvalidJobOptions> {
+        let invalid = |option, reason: &str| {
+            Err(InvalidJobOptions {
+                option,
+                reason: reason.to_string(),
+            })
+        };
+
+        if let Some(job_id) = &self.job_id {
+            if job_id.is_empty() {
+                return invalid("jobId", "must not be empty");
+            }
+
+            // They would collide with the ids generated by the queue, unless
+            // the queue generated them
+            if self.namespace.is_none() && !self.id_reserved && job_id.parse::<u64>().is_ok() {
+                return invalid("jobId", "must not be an integer");
+            }
+
+            // Used as separator in the job's keys, e.g. `<id>:lock`
+            if job_id.contains(':') {
+                return invalid("jobId", "must not contain `:`");
+            }
+        }
+
+        if let Some(namespace) = &self.namespace {
+            if namespace.is_empty() {
+                return invalid("namespace", "must not be empty");
+            }
+
+            // `/` ends the namespace in the job's id, which must not contain `:`
+            if namespace.contains(['/', ':']) {
+                return invalid("namespace", "must not contain `/` or `:`");
+            }
+        }
+
+        if let Some(backoff) = &self.backoff {
+            if self.attempts == 0 {
+                return invalid("backoff", "requires `attempts` to be set");
+            }
+
+            if !(0.0..=1.0).contains(&backoff.jitter) {
+                return invalid("backoff", "`jitter` must be between 0 and 1");
+            }
+        }
+
+        if self.ttl_ms == Some(0) {
+            return invalid("ttl", "must be positive");
+        }
+
+        if self.weight == Some(0) {
+            return invalid("weight", "must be positive");
+        }
+
+        if self
+            .deduplication
+            .as_ref()
+            .is_some_and(|deduplication| deduplication.id.is_empty())
+        {
+            return invalid("de", "`id` must not be empty");
+        }
+
+        Ok(())
+    }
+}
+
+/// Return value stored as is instead of as JSON, e.g. a protobuf message, for
+/// handlers returning binary data. It can be read back with
+/// `Queue::get_return_value_bytes`, or `JobHandle::wait` once `returning` it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawReturnValue(pub Vec<u8>);
+
+impl Serialize for RawReturnValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+/// Lets it be waited for, `JobHandle::wait` taking the stored bytes as is
+/// rather than deserializing them.
+impl<'de> Deserialize<'de> for RawReturnValue {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(RawReturnValue)
+    }
+}
+
+/// Job data kept as the raw JSON read from the job hash instead of being
+/// parsed into an owned value, e.g. `Worker<RawJobData, _>`. For large
+/// payloads of which the handler only reads a few fields, `parse` builds a
+/// view borrowing from it (`&str` fields...), skipping the rest without
+/// allocating.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RawJobData(Box<RawValue>);
+
+impl RawJobData {
+    /// Deserializes the data, borrowing from it where `T` allows.
+    pub fn parse<'a, T: Deserialize<'a>>(&'a self) -> Result<T> {
+        Ok(serde_json::from_str(self.0.get())?)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.get()
+    }
+}
+
+/// Payload of a job able to check itself before being processed, usually
+/// implemented with `#[derive(JobData)]`. Workers built with
+/// `Worker::validate_data` move jobs failing the check straight to failed,
+/// without retrying them.
+pub trait JobData {
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Children of a flow's parent job, see `Queue::get_dependencies`. Children
+/// are identified by the key of their job hash, e.g. `bull:child-queue:3`, as
+/// they can belong to other queues.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dependencies {
+    /// Children that completed, from the parent's `processed` hash
+    pub processed: Vec<String>,
+    /// Children still pending, from the parent's `dependencies` set
+    pub unprocessed: Vec<String>,
+}
+
+/// Parent of a job added as part of a flow, stored by BullMQ in the job's
+/// `parent` field as `{"id": ..., "queueKey": ...}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParentRef {
+    pub id: String,
+    /// Prefixed key of the parent's queue, e.g. `bull:parent-queue`
+    #[serde(rename = "queueKey")]
+    pub queue_key: String,
+}
+
+impl ParentRef {
+    /// Builds the reference from the job's `parentKey` field, e.g.
+    /// `bull:parent-queue:1`.
+    pub fn from_key(parent_key: &str) -> Option<Self> {
+        let (queue_key, id) = parent_key.rsplit_once(':')?;
+
+        Some(ParentRef {
+            id: id.to_string(),
+            queue_key: queue_key.to_string(),
+        })
+    }
+
+    /// Key of the parent's job hash.
+    pub fn key(&self) -> String {
+        format!("{}:{}", self.queue_key, self.id)
+    }
 }
 
 #[derive(Debug)]
@@ -15,8 +572,391 @@ pub struct Job<Data> {
     pub delay: u128,
     pub priority: u32,
     pub processed_on: u128,
+    /// Timestamp in ms at which the job completed or failed for good, `None`
+    /// while it's not finished
+    pub finished_on: Option<u128>,
     pub attempts_started: u32,
     pub attempts_made: Option<u32>,
+    pub parent: Option<ParentRef>,
+    /// Errors of the failed attempts, most recent last, see
+    /// `JobOptions::stack_trace_limit`
+    pub stacktrace: Vec<String>,
+    /// Key prefix of the job's queue, e.g. `bull:my-queue:`
+    pub(crate) prefix: String,
+    /// Set by the process function, see `set_idempotency_key`
+    pub(crate) idempotency_key: Arc<Mutex<Option<String>>>,
+    /// Idempotency key and encoded return value recorded by an earlier
+    /// attempt that succeeded
+    pub(crate) recorded_result: Option<(String, Vec<u8>)>,
+    pub(crate) cancellation_token: CancellationToken,
+    /// Taken by `ack_handle`, set with `WorkerOptions::manual_ack`
+    pub(crate) ack: Arc<Mutex<Option<AckHandle>>>,
+}
+
+/// Outcome of a job processed with `WorkerOptions::manual_ack`, sent by its
+/// `AckHandle`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Ack {
+    Ack,
+    Nack(String),
+}
+
+/// Finalizes a job processed by a worker with `WorkerOptions::manual_ack`,
+/// see `Job::ack_handle`. Dropping it without calling `ack` or `nack` nacks
+/// the job.
+#[derive(Debug)]
+pub struct AckHandle {
+    job_id: String,
+    sender: oneshot::Sender<Ack>,
+}
+
+impl AckHandle {
+    /// Handle of the job, and the receiver its worker waits on.
+    pub(crate) fn new(job_id: &str) -> (Self, oneshot::Receiver<Ack>) {
+        let (sender, receiver) = oneshot::channel();
+
+        (
+            AckHandle {
+                job_id: job_id.to_string(),
+                sender,
+            },
+            receiver,
+        )
+    }
+
+    pub fn job_id(&self) -> &str {
+        &self.job_id
+    }
+
+    /// Moves the job to completed, with the value its handler returned.
+    pub fn ack(self) {
+        let _ = self.sender.send(Ack::Ack);
+    }
+
+    /// Fails the attempt with `reason`, the job being retried if it has
+    /// attempts left, as if its handler had returned an error.
+    pub fn nack(self, reason: &str) {
+        let _ = self.sender.send(Ack::Nack(reason.to_string()));
+    }
+}
+
+impl<Data> Job<Data> {
+    /// Time the last attempt took, from the moment it was moved to active to
+    /// the job's completion or final failure. `None` while the job is not
+    /// finished.
+    pub fn duration(&self) -> Option<Duration> {
+        let finished_on = self.finished_on?;
+
+        Some(Duration::from_millis(
+            finished_on.saturating_sub(self.processed_on) as u64,
+        ))
+    }
+
+    /// Registers a key identifying the job's side effects, e.g.
+    /// `charge:order-42`, for handlers that must not run twice. Once the
+    /// process function succeeds, the worker records the key and the return
+    /// value on the job's hash before moving the job to completed. If the job
+    /// is fetched again in between, e.g. because its worker died or lost its
+    /// lock and the job was recovered as stalled, it's moved to completed with
+    /// the recorded return value instead of being processed again. A crash
+    /// between the side effect and the end of the process function can still
+    /// run it twice. The record is cleared once the job is completed, so
+    /// retrying it with `Queue::retry_job` runs the handler again.
+    pub fn set_idempotency_key(&self, key: &str) {
+        *self.idempotency_key.lock().unwrap() = Some(key.to_string());
+    }
+
+    /// Token cancelled when the worker processing the job is closed, e.g.
+    /// by `Worker::run_until_ctrl_c` during a deploy. Long running handlers
+    /// can check it between steps and stop at a safe point, returning an
+    /// error to have the job retried, instead of holding the shutdown until
+    /// they are done. Handlers that ignore it are still waited for.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation_token.clone()
+    }
+
+    /// Handle to ack or nack the job once its result is committed, e.g.
+    /// after the transaction recording it, when the worker runs with
+    /// `WorkerOptions::manual_ack`. It can be moved out of the handler, to
+    /// another task or thread. `None` without `manual_ack` or if it was
+    /// already taken.
+    pub fn ack_handle(&self) -> Option<AckHandle> {
+        self.ack.lock().unwrap().take()
+    }
+
+    /// Appends a line to the job's logs, which can be read back with
+    /// `Queue::get_job_logs`. Returns the number of lines kept for the job.
+    pub fn log(&self, client: &mut Client, message: &str) -> Result<usize> {
+        match ADD_LOG.run(&self.prefix, client, &self.id, message, self.opts.keep_logs)? {
+            AddLogReturn::Count(count) => Ok(count),
+            AddLogReturn::MissingKey => Err(anyhow!("Job {} does not exist", self.id)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Completed,
+    Failed,
+    Delayed,
+    Active,
+    Wait,
+    Paused,
+    Prioritized,
+    WaitingChildren,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Completed => "completed",
+            JobState::Failed => "failed",
+            JobState::Delayed => "delayed",
+            JobState::Active => "active",
+            JobState::Wait => "wait",
+            JobState::Paused => "paused",
+            JobState::Prioritized => "prioritized",
+            JobState::WaitingChildren => "waiting-children",
+        }
+    }
+}
+
+/// Reference to a job of a queue, returned by `Queue::add` and
+/// `Queue::get_job`. `Data` and `Return` are the types the job's data and
+/// return value are decoded as by `get` and `wait`, see `returning` to pick
+/// the latter.
+pub struct JobHandle<Data = Value, Return = Value> {
+    id: String,
+    prefix: String,
+    client: Client,
+    deduplicated: bool,
+    result_store: Option<Arc<dyn ResultStore>>,
+    types: PhantomData<fn() -> (Data, Return)>,
+}
+
+impl<Data, Return> JobHandle<Data, Return> {
+    pub(crate) fn new(id: String, prefix: String, client: Client) -> Self {
+        JobHandle {
+            id,
+            prefix,
+            client,
+            deduplicated: false,
+            result_store: None,
+            types: PhantomData,
+        }
+    }
+
+    pub(crate) fn deduplicated(mut self) -> Self {
+        self.deduplicated = true;
+        self
+    }
+
+    /// Store `wait` reads offloaded return values from, and `remove` deletes
+    /// them from.
+    pub(crate) fn result_store(mut self, store: Option<Arc<dyn ResultStore>>) -> Self {
+        self.result_store = store;
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Whether `Queue::add` returned an earlier job with the same
+    /// deduplication id instead of adding a new one.
+    pub fn was_deduplicated(&self) -> bool {
+        self.deduplicated
+    }
+
+    /// Same handle, decoding the job's return value as `R` in `wait`.
+    pub fn returning<R>(self) -> JobHandle<Data, R> {
+        JobHandle {
+            id: self.id,
+            prefix: self.prefix,
+            client: self.client,
+            deduplicated: self.deduplicated,
+            result_store: self.result_store,
+            types: PhantomData,
+        }
+    }
+
+    /// Returns the state the job is currently in, `None` if it's in none of
+    /// them (e.g. it was removed). Jobs of a paused queue are in `Wait`.
+    pub fn state(&self) -> Result<Option<JobState>> {
+        GET_STATE.run(&self.prefix, &mut self.client.clone(), &self.id)
+    }
+
+    pub fn is_completed(&self) -> Result<bool> {
+        Ok(self.state()? == Some(JobState::Completed))
+    }
+
+    pub fn is_failed(&self) -> Result<bool> {
+        Ok(self.state()? == Some(JobState::Failed))
+    }
+
+    pub fn is_active(&self) -> Result<bool> {
+        Ok(self.state()? == Some(JobState::Active))
+    }
+
+    pub fn is_delayed(&self) -> Result<bool> {
+        Ok(self.state()? == Some(JobState::Delayed))
+    }
+
+    /// Blocks until a worker starts processing the job, e.g. to tell users
+    /// their job started before it's finished, see `wait` for the latter. Jobs
+    /// already active or finished return right away, and so do jobs in no
+    /// state as they were likely removed on completion. Fails if the job
+    /// doesn't start within `timeout`.
+    pub fn wait_active(&self, timeout: Duration) -> Result<()> {
+        // Subscribe before reading the state so a job starting in between is
+        // not missed
+        let mut events = QueueEvents::from_client(&self.prefix, self.client.clone())?;
+
+        if let Some(JobState::Active | JobState::Completed | JobState::Failed) | None =
+            self.state()?
+        {
+            return Ok(());
+        }
+
+        match events.wait_for(&self.id, &["active", "completed", "failed"], timeout)? {
+            Some(_) => Ok(()),
+            None => Err(anyhow!("Timed out waiting for job {} to start", self.id)),
+        }
+    }
+
+    /// Removes the job, its children and its logs. Fails if the job or one of
+    /// its children is being processed.
+    pub fn remove(&self) -> Result<()> {
+        let mut client = self.client.clone();
+
+        if !REMOVE_JOB.run(&self.prefix, &mut client, &self.id, true)? {
+            bail!("Job {} could not be removed because it is locked", self.id);
+        }
+
+        // Along with the values of its removed children in the same queue
+        delete_released(self.result_store.as_deref(), &mut client, &self.prefix);
+
+        Ok(())
+    }
+}
+
+impl<Data: DeserializeOwned, Return> JobHandle<Data, Return> {
+    /// Reads the whole job, `None` if it doesn't exist anymore.
+    pub fn get(&self) -> Result<Option<Job<Data>>> {
+        let fields: HashMap<String, Vec<u8>> = self
+            .client
+            .clone()
+            .hgetall(QueueKeys::job(&self.id).with_prefix(&self.prefix))?;
+
+        if fields.is_empty() {
+            return Ok(None);
+        }
+
+        let mut builder = JobBuilder::new().id(self.id.clone());
+
+        for (key, value) in fields.iter() {
+            builder = builder.field(key, value)?;
+        }
+
+        let mut job = builder.build();
+        job.prefix = self.prefix.clone();
+
+        Ok(Some(job))
+    }
+}
+
+impl<Data, Return: DeserializeOwned + 'static> JobHandle<Data, Return> {
+    /// Blocks until the job is completed, returning its deserialized return
+    /// value, or its bytes as stored for a `RawReturnValue`. A failed job is returned as an error with its failed reason, and
+    /// so is a job that doesn't finish within `timeout`.
+    pub fn wait(&self, timeout: Duration) -> Result<Return> {
+        let events = QueueEvents::from_client(&self.prefix, self.client.clone())?;
+
+        self.wait_with(events, timeout)
+    }
+
+    /// Same as `wait`, reading events from `events`, which must have been
+    /// created before the job could finish. A job that is in no state, e.g.
+    /// because it was removed on completion, is waited for through its events.
+    pub(crate) fn wait_with(&self, mut events: QueueEvents, timeout: Duration) -> Result<Return> {
+        let key = QueueKeys::job(&self.id).with_prefix(&self.prefix);
+
+        match self.state()? {
+            Some(JobState::Completed) => {
+                let return_value: Option<Vec<u8>> =
+                    self.client.clone().hget(&key, "returnvalue")?;
+
+                return self.decode_return_value(return_value.unwrap_or(b"null".to_vec()));
+            }
+            Some(JobState::Failed) => {
+                let reason: Option<String> = self.client.clone().hget(&key, "failedReason")?;
+
+                bail!("Job {} failed: {}", self.id, reason.unwrap_or_default());
+            }
+            _ => {}
+        }
+
+        match events.wait_for(&self.id, &["completed", "failed"], timeout)? {
+            Some(event) if event.event == "completed" => {
+                self.decode_return_value(event.return_value.unwrap_or(b"null".to_vec()))
+            }
+            Some(event) => Err(anyhow!(
+                "Job {} failed: {}",
+                self.id,
+                event.get("failedReason").unwrap_or_default()
+            )),
+            None => Err(anyhow!("Timed out waiting for job {} to finish", self.id)),
+        }
+    }
+
+    /// Reads back a return value as the worker stored it: offloaded to the
+    /// result store or not, raw bytes for a `RawReturnValue` and JSON for
+    /// anything else.
+    fn decode_return_value(&self, value: Vec<u8>) -> Result<Return> {
+        let reference = recorded_reference(&mut self.client.clone(), &self.prefix, &self.id)?;
+        let value = rehydrate(self.result_store.as_deref(), value, reference)?;
+
+        if TypeId::of::<Return>() == TypeId::of::<RawReturnValue>() {
+            let raw: Box<dyn Any> = Box::new(RawReturnValue(value));
+
+            return Ok(*raw.downcast().unwrap());
+        }
+
+        Ok(serde_json::from_slice(&value)?)
+    }
+}
+
+/// Timing information for a job that finished processing, either successfully
+/// or with an error.
+#[derive(Debug, Clone)]
+pub struct JobOutcome {
+    pub job_id: String,
+    pub name: String,
+    /// Time the job spent in the queue before a worker picked it up
+    /// (`processed_on - timestamp`).
+    pub wait_time: Duration,
+    /// Time spent running the process function.
+    pub process_time: Duration,
+    /// Unix timestamp (ms) at which the worker finished processing the job.
+    pub finished_on: u128,
+    pub failed_reason: Option<String>,
+    /// Attempts made so far, this one included. Above 1 the job was retried,
+    /// and a completed one only succeeded after failing.
+    pub attempts_made: u32,
+    /// Backoff before the next attempt of a failed job that will be retried,
+    /// `None` if it won't be.
+    pub retry_in: Option<Duration>,
+}
+
+impl JobOutcome {
+    pub fn is_completed(&self) -> bool {
+        self.failed_reason.is_none()
+    }
+
+    /// Whether the job failed but will be retried.
+    pub fn will_retry(&self) -> bool {
+        self.retry_in.is_some()
+    }
 }
 
 pub struct JobBuilder<Data> {
@@ -28,8 +968,14 @@ pub struct JobBuilder<Data> {
     delay: Option<u128>,
     priority: Option<u32>,
     processed_on: Option<u128>,
+    finished_on: Option<u128>,
     attempts_started: Option<u32>,
     attempts_made: Option<u32>,
+    parent: Option<ParentRef>,
+    parent_key: Option<String>,
+    stacktrace: Option<Vec<String>>,
+    recorded_key: Option<String>,
+    recorded_return_value: Option<Vec<u8>>,
 }
 
 impl<Data> JobBuilder<Data> {
@@ -43,8 +989,14 @@ impl<Data> JobBuilder<Data> {
             delay: None,
             priority: None,
             processed_on: None,
+            finished_on: None,
             attempts_started: None,
             attempts_made: None,
+            parent: None,
+            parent_key: None,
+            stacktrace: None,
+            recorded_key: None,
+            recorded_return_value: None,
         }
     }
 
@@ -89,6 +1041,11 @@ impl<Data> JobBuilder<Data> {
         self
     }
 
+    pub fn finished_on(mut self, finished_on: u128) -> Self {
+        self.finished_on = Some(finished_on);
+        self
+    }
+
     pub fn attempts_started(mut self, attempts_started: u32) -> Self {
         self.attempts_started = Some(attempts_started);
         self
@@ -99,18 +1056,557 @@ impl<Data> JobBuilder<Data> {
         self
     }
 
+    pub fn parent(mut self, parent: ParentRef) -> Self {
+        self.parent = Some(parent);
+        self
+    }
+
+    /// Used for the parent when the `parent` field itself is missing.
+    pub fn parent_key(mut self, parent_key: String) -> Self {
+        self.parent_key = Some(parent_key);
+        self
+    }
+
+    /// Sets the job field stored under `key` in the job's hash. Unknown fields
+    /// are ignored.
+    pub fn field(self, key: &str, value: &[u8]) -> Result<Self>
+    where
+        Data: DeserializeOwned,
+    {
+        Ok(match key {
+            "name" => self.name(String::from_utf8(value.to_vec())?),
+            "data" => self.data(decode_field(key, value)?),
+            "opts" => JobBuilder {
+                opts: Some(decode_field(key, value)?),
+                ..self
+            },
+            "timestamp" => self.timestamp(parse_field(value)?),
+            "delay" => self.delay(parse_field(value)?),
+            "priority" => self.priority(parse_field(value)?),
+            "processedOn" => self.processed_on(parse_field(value)?),
+            "finishedOn" => self.finished_on(parse_field(value)?),
+            "ats" => self.attempts_started(parse_field(value)?),
+            "atm" => self.attempts_made(parse_field(value)?),
+            // Set by BullMQ flows
+            "parent" => match serde_json::from_slice(value) {
+                Ok(parent) => self.parent(parent),
+                Err(_) => self,
+            },
+            "parentKey" => self.parent_key(String::from_utf8(value.to_vec())?),
+            "stacktrace" => JobBuilder {
+                stacktrace: serde_json::from_slice(value).ok(),
+                ..self
+            },
+            // Set by `Job::set_idempotency_key`
+            "idempotencyKey" => JobBuilder {
+                recorded_key: Some(String::from_utf8(value.to_vec())?),
+                ..self
+            },
+            "idempotentReturnValue" => JobBuilder {
+                recorded_return_value: Some(value.to_vec()),
+                ..self
+            },
+            _ => self,
+        })
+    }
+
+    /// Builds the job. Only the id, name and data are required, the other
+    /// fields default to zero as not every producer writes them.
+    ///
+    /// # Panics
+    ///
+    /// If the id, name or data is missing, see `try_build`.
     pub fn build(self) -> Job<Data> {
-        Job {
-            id: self.id.unwrap(),
-            name: self.name.unwrap(),
-            data: self.data.unwrap(),
-            opts: self.opts.unwrap(),
-            timestamp: self.timestamp.unwrap(),
-            delay: self.delay.unwrap(),
-            priority: self.priority.unwrap(),
-            processed_on: self.processed_on.unwrap(),
-            attempts_started: self.attempts_started.unwrap(),
+        self.try_build().unwrap()
+    }
+
+    /// Same as `build`, returning an error if the id, name or data is
+    /// missing, e.g. for a job hash written by an unknown producer.
+    pub fn try_build(self) -> Result<Job<Data>> {
+        let (Some(id), Some(name), Some(data)) = (self.id, self.name, self.data) else {
+            bail!("Job is missing its id, name or data");
+        };
+        let parent = self
+            .parent
+            .or_else(|| self.parent_key.as_deref().and_then(ParentRef::from_key));
+
+        Ok(Job {
+            id,
+            name,
+            data,
+            opts: self.opts.unwrap_or_default(),
+            timestamp: self.timestamp.unwrap_or_default(),
+            delay: self.delay.unwrap_or_default(),
+            priority: self.priority.unwrap_or_default(),
+            processed_on: self.processed_on.unwrap_or_default(),
+            finished_on: self.finished_on,
+            attempts_started: self.attempts_started.unwrap_or_default(),
             attempts_made: self.attempts_made,
+            parent,
+            stacktrace: self.stacktrace.unwrap_or_default(),
+            prefix: String::new(),
+            idempotency_key: Arc::default(),
+            recorded_result: self.recorded_key.zip(self.recorded_return_value),
+            cancellation_token: CancellationToken::new(),
+            ack: Arc::default(),
+        })
+    }
+}
+
+fn parse_field<T: FromStr>(value: &[u8]) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(std::str::from_utf8(value)?.parse()?)
+}
+
+/// Bytes of a field shown in `decode_field` errors.
+const RAW_PREVIEW_LEN: usize = 64;
+
+/// Deserializes the JSON job field stored under `key`. Errors name the field
+/// and preview its raw bytes, so a producer encoding it otherwise (e.g. as
+/// msgpack) is obvious from the failed reason.
+pub(crate) fn decode_field<T: DeserializeOwned>(key: &str, value: &[u8]) -> Result<T> {
+    serde_json::from_slice(value).map_err(|err| {
+        anyhow!(
+            "Invalid `{}` field: {} (raw: {})",
+            key,
+            err,
+            preview_bytes(value)
+        )
+    })
+}
+
+/// Start of `bytes`, as a string if they are UTF-8 and in hex otherwise,
+/// followed by their length if it was truncated.
+fn preview_bytes(bytes: &[u8]) -> String {
+    let shown = &bytes[..bytes.len().min(RAW_PREVIEW_LEN)];
+
+    // Truncation may split a character, the lossy conversion only drops it
+    let preview = match std::str::from_utf8(bytes) {
+        Ok(_) => format!(
+            "{:?}",
+            String::from_utf8_lossy(shown).trim_end_matches('\u{FFFD}')
+        ),
+        Err(_) => shown.iter().map(|byte| format!("{:02x}", byte)).collect(),
+    };
+
+    if shown.len() < bytes.len() {
+        format!("{}... ({} bytes)", preview, bytes.len())
+    } else {
+        preview
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previews_undecodable_fields() {
+        let err = |value: &[u8]| decode_field::<u32>("data", value).unwrap_err().to_string();
+
+        assert_eq!(
+            err(br#""text""#),
+            r#"Invalid `data` field: invalid type: string "text", expected u32 at line 1 column 6 (raw: "\"text\"")"#
+        );
+        // Msgpack map {"a": 1}
+        assert_eq!(
+            err(&[0x81, 0xa1, 0x61, 0x01]),
+            "Invalid `data` field: expected value at line 1 column 1 (raw: 81a16101)"
+        );
+        assert!(err(&[b'x'; 100]).ends_with(&format!(r#""{}"... (100 bytes))"#, "x".repeat(64))));
+    }
+
+    #[test]
+    fn parses_backoff_options() {
+        let opts: JobOptions =
+            serde_json::from_str(r#"{"attempts":3,"backoff":{"type":"exponential","delay":1000}}"#)
+                .unwrap();
+
+        assert_eq!(
+            opts.backoff,
+            Some(BackoffOptions {
+                strategy: BackoffStrategy::Exponential,
+                delay: 1000,
+                jitter: 0.0,
+            })
+        );
+
+        let opts: JobOptions = serde_json::from_str(r#"{"attempts":3,"backoff":500}"#).unwrap();
+
+        assert_eq!(opts.backoff_delay(2), 500);
+
+        let opts: JobOptions = serde_json::from_str(r#"{"attempts":3}"#).unwrap();
+
+        assert_eq!(opts.backoff_delay(1), 0);
+    }
+
+    #[test]
+    fn serializes_deduplication_options() {
+        let opts = JobOptions {
+            deduplication: Some(DeduplicationOptions {
+                id: "user-stats:42".to_string(),
+                ttl: Some(5000),
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            serde_json::to_value(&opts).unwrap()["de"],
+            serde_json::json!({ "id": "user-stats:42", "ttl": 5000 })
+        );
+    }
+
+    #[test]
+    fn parses_keep_jobs() {
+        let opts: JobOptions = serde_json::from_str(
+            r#"{"removeOnComplete":true,"removeOnFail":{"age":604800,"count":1000}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(opts.keep_completed(), KeepJobs::count(0));
+        assert_eq!(
+            opts.keep_failed(),
+            KeepJobs {
+                count: Some(1000),
+                age: Some(604800)
+            }
+        );
+
+        let opts: JobOptions = serde_json::from_str(r#"{"removeOnComplete":10}"#).unwrap();
+
+        assert_eq!(opts.keep_completed(), KeepJobs::count(10));
+        assert_eq!(opts.keep_failed(), KeepJobs::all());
+    }
+
+    #[test]
+    fn keeps_retention_through_the_add_path() {
+        let opts = JobOptions {
+            remove_on_complete: Some(KeepJobs::count(5)),
+            remove_on_fail: Some(KeepJobs::age(3600)),
+            ..Default::default()
+        };
+
+        // Sent msgpacked to addStandardJob, which stores it as JSON
+        let stored: serde_json::Value =
+            rmp_serde::from_slice(&rmp_serde::to_vec_named(&opts).unwrap()).unwrap();
+        let opts: JobOptions = serde_json::from_value(stored).unwrap();
+
+        assert_eq!(opts.keep_completed(), KeepJobs::count(5));
+        assert_eq!(opts.keep_failed(), KeepJobs::age(3600));
+    }
+
+    #[test]
+    fn schedules_jobs_at_a_timestamp() {
+        let opts = JobOptions::default().delay_until(10_000);
+
+        assert_eq!(opts.resolved(4_000).delay, Some(6_000));
+        assert_eq!(opts.resolved(4_000).due_at, None);
+        assert_eq!(opts.resolved(10_000).delay, None);
+        assert_eq!(opts.resolved(12_000).delay, None);
+        assert!(opts.is_delayed_at(9_999));
+        assert!(!opts.is_delayed_at(10_000));
+
+        let opts = JobOptions {
+            delay: Some(500),
+            ..Default::default()
+        };
+
+        assert_eq!(opts.resolved(4_000).delay, Some(500));
+        assert_eq!(opts.delay_until(4_200).resolved(4_000).delay, Some(200));
+    }
+
+    #[test]
+    fn prefixes_ids_with_the_namespace() {
+        let opts = JobOptions {
+            job_id: Some("42".to_string()),
+            deduplication: Some(DeduplicationOptions {
+                id: "report".to_string(),
+                ttl: None,
+            }),
+            ..Default::default()
+        }
+        .namespace("acme");
+        let resolved = opts.resolved(0);
+
+        assert!(opts.validate().is_ok());
+        assert_eq!(resolved.job_id.as_deref(), Some("acme/42"));
+        assert_eq!(resolved.deduplication.unwrap().id, "acme/report");
+        assert_eq!(resolved.namespace, None);
+
+        let generated = JobOptions::default().namespace("acme").resolved(0).job_id;
+
+        assert!(generated.unwrap().starts_with("acme/"));
+        assert_eq!(JobOptions::default().resolved(0).job_id, None);
+        assert_eq!(
+            JobOptions::default()
+                .namespace("acme/eu")
+                .validate()
+                .unwrap_err()
+                .reason,
+            "must not contain `/` or `:`"
+        );
+    }
+
+    #[test]
+    fn validates_options() {
+        let backoff = BackoffOptions {
+            strategy: BackoffStrategy::Exponential,
+            delay: 1000,
+            jitter: 0.0,
+        };
+        let reason = |opts: JobOptions| opts.validate().unwrap_err().to_string();
+
+        assert!(JobOptions::default().validate().is_ok());
+        assert!(JobOptions {
+            job_id: Some("order-42".to_string()),
+            attempts: 3,
+            backoff: Some(backoff.clone()),
+            ..Default::default()
         }
+        .validate()
+        .is_ok());
+
+        assert_eq!(
+            reason(JobOptions {
+                job_id: Some("42".to_string()),
+                ..Default::default()
+            }),
+            "Invalid job option `jobId`: must not be an integer"
+        );
+        assert!(JobOptions::default()
+            .reserved_id(ReservedId("42".to_string()))
+            .validate()
+            .is_ok());
+        assert_eq!(
+            reason(JobOptions {
+                job_id: Some("order:42".to_string()),
+                ..Default::default()
+            }),
+            "Invalid job option `jobId`: must not contain `:`"
+        );
+        assert_eq!(
+            reason(JobOptions {
+                backoff: Some(backoff.clone()),
+                ..Default::default()
+            }),
+            "Invalid job option `backoff`: requires `attempts` to be set"
+        );
+        assert_eq!(
+            reason(JobOptions {
+                attempts: 3,
+                backoff: Some(BackoffOptions {
+                    jitter: 1.5,
+                    ..backoff.clone()
+                }),
+                ..Default::default()
+            }),
+            "Invalid job option `backoff`: `jitter` must be between 0 and 1"
+        );
+        assert_eq!(
+            reason(JobOptions {
+                ttl_ms: Some(0),
+                ..Default::default()
+            }),
+            "Invalid job option `ttl`: must be positive"
+        );
+        assert_eq!(
+            reason(JobOptions::default().weight(0)),
+            "Invalid job option `weight`: must be positive"
+        );
+    }
+
+    #[test]
+    fn exponential_backoff_delays_retries() {
+        let backoff = BackoffOptions {
+            strategy: BackoffStrategy::Exponential,
+            delay: 1000,
+            jitter: 0.0,
+        };
+
+        assert_eq!(backoff.delay_for(1), 1000);
+        assert_eq!(backoff.delay_for(2), 2000);
+        assert_eq!(backoff.delay_for(3), 4000);
+    }
+
+    #[test]
+    fn jitter_spreads_delays() {
+        let opts: JobOptions = serde_json::from_str(
+            r#"{"attempts":3,"backoff":{"type":"exponential","delay":1000,"jitter":0.5}}"#,
+        )
+        .unwrap();
+
+        let delays: Vec<u64> = (0..100).map(|_| opts.backoff_delay(2)).collect();
+
+        assert!(delays.iter().all(|d| (1000..=3000).contains(d)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+
+    #[derive(JobData)]
+    #[job_data(validate = check_adult)]
+    struct Person {
+        #[job_data(non_empty)]
+        name: String,
+        #[job_data(range(max = 150))]
+        age: u8,
+    }
+
+    fn check_adult(person: &Person) -> Result<()> {
+        if person.age < 18 {
+            return Err(anyhow!("{} is not an adult", person.name));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn derives_job_data_validation() {
+        let person = |name: &str, age| Person {
+            name: name.to_string(),
+            age,
+        };
+
+        assert!(person("John", 30).validate().is_ok());
+        assert_eq!(
+            person("", 30).validate().unwrap_err().to_string(),
+            "`name` must not be empty"
+        );
+        assert_eq!(
+            person("John", 200).validate().unwrap_err().to_string(),
+            "`age` must be at most 150, got 200"
+        );
+        assert_eq!(
+            person("John", 12).validate().unwrap_err().to_string(),
+            "John is not an adult"
+        );
+    }
+
+    #[test]
+    fn builds_job_from_hash_fields() {
+        let fields: [(&str, &[u8]); 7] = [
+            ("name", b"send-email"),
+            ("data", br#"{"to":"a@b.c"}"#),
+            ("timestamp", b"1700000000000"),
+            ("processedOn", b"1700000001000"),
+            ("finishedOn", b"1700000001250"),
+            ("atm", b"2"),
+            ("returnvalue", b"null"),
+        ];
+        let mut builder: JobBuilder<Value> = JobBuilder::new().id("1".to_string());
+
+        for (key, value) in fields {
+            builder = builder.field(key, value).unwrap();
+        }
+
+        let job = builder.build();
+
+        assert_eq!(job.name, "send-email");
+        assert_eq!(job.data["to"], "a@b.c");
+        assert_eq!(job.timestamp, 1700000000000);
+        assert_eq!(job.attempts_made, Some(2));
+        assert_eq!(job.finished_on, Some(1700000001250));
+        assert_eq!(job.duration(), Some(Duration::from_millis(250)));
+
+        let unfinished = JobBuilder::<Value>::new()
+            .id("2".to_string())
+            .name("send-email".to_string())
+            .data(Value::Null)
+            .build();
+        assert_eq!(unfinished.duration(), None);
+
+        assert!(JobBuilder::<Value>::new().field("delay", b"soon").is_err());
+    }
+
+    #[test]
+    fn borrows_from_raw_job_data() {
+        #[derive(Deserialize)]
+        struct Header<'a> {
+            kind: &'a str,
+        }
+
+        let job = JobBuilder::<RawJobData>::new()
+            .id("1".to_string())
+            .name("import".to_string())
+            .field("data", br#"{"kind":"csv","rows":[[1,2],[3,4]]}"#)
+            .unwrap()
+            .build();
+
+        let header: Header = job.data.parse().unwrap();
+
+        assert_eq!(header.kind, "csv");
+        assert_eq!(job.data.as_str(), r#"{"kind":"csv","rows":[[1,2],[3,4]]}"#);
+        assert_eq!(
+            serde_json::to_string(&job.data).unwrap(),
+            r#"{"kind":"csv","rows":[[1,2],[3,4]]}"#
+        );
+        assert!(JobBuilder::<RawJobData>::new().field("data", b"{").is_err());
+    }
+
+    #[test]
+    fn keeps_the_last_stack_traces() {
+        let mut stacktrace = vec![];
+        let opts = JobOptions {
+            stack_trace_limit: Some(2),
+            ..Default::default()
+        };
+
+        for attempt in 1..=3 {
+            opts.push_stacktrace(&mut stacktrace, format!("attempt {}", attempt));
+        }
+
+        assert_eq!(stacktrace, ["attempt 2", "attempt 3"]);
+
+        for attempt in 1..=12 {
+            JobOptions::default().push_stacktrace(&mut stacktrace, attempt.to_string());
+        }
+
+        assert_eq!(stacktrace.len(), DEFAULT_STACK_TRACE_LIMIT as usize);
+        assert_eq!(stacktrace.last().unwrap(), "12");
+
+        let job = JobBuilder::<Value>::new()
+            .id("1".to_string())
+            .name("send-email".to_string())
+            .data(Value::Null)
+            .field("stacktrace", br#"["Error: timed out"]"#)
+            .unwrap()
+            .build();
+
+        assert_eq!(job.stacktrace, ["Error: timed out"]);
+    }
+
+    #[test]
+    fn reads_recorded_idempotency_keys() {
+        let build = |fields: &[(&str, &[u8])]| {
+            let mut builder: JobBuilder<Value> = JobBuilder::new()
+                .id("1".to_string())
+                .name("charge".to_string())
+                .data(Value::Null);
+
+            for (key, value) in fields {
+                builder = builder.field(key, value).unwrap();
+            }
+
+            builder.build()
+        };
+
+        assert_eq!(build(&[]).recorded_result, None);
+        assert_eq!(
+            build(&[
+                ("idempotencyKey", b"charge:42"),
+                ("idempotentReturnValue", br#"{"paid":true}"#),
+            ])
+            .recorded_result,
+            Some(("charge:42".to_string(), br#"{"paid":true}"#.to_vec()))
+        );
+
+        let job = build(&[]);
+
+        job.set_idempotency_key("charge:42");
+
+        assert_eq!(
+            job.idempotency_key.lock().unwrap().as_deref(),
+            Some("charge:42")
+        );
     }
 }
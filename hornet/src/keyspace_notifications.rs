@@ -0,0 +1,141 @@
+use crate::{connection::reconnect_backoff, log};
+use anyhow::Result;
+use redis::Client;
+use std::{
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// Events of the queue's `wait` list and `prioritized` set meaning a job can
+/// be fetched
+const WAKEUP_EVENTS: [&str; 3] = ["lpush", "rpush", "zadd"];
+/// How often the subscriber checks whether its worker is gone
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Starts a thread subscribed to the keyspace notifications of the `wait`
+/// list and `prioritized` set of each queue, notifying the returned `Notify`
+/// every time a job is pushed to one of them. The server must have
+/// `notify-keyspace-events` set to (at least) `Klz`. It stops once the
+/// returned `Notify` is dropped. `on_resubscribed` is called every time it
+/// subscribes again after losing its connection.
+pub(crate) fn spawn(
+    prefixes: Vec<String>,
+    client: Client,
+    on_resubscribed: Option<fn()>,
+) -> Arc<Notify> {
+    let wakeups = Arc::new(Notify::new());
+    let weak = Arc::downgrade(&wakeups);
+
+    thread::spawn(move || {
+        let db = client.get_connection_info().redis.db;
+        let channels: Vec<String> = prefixes
+            .iter()
+            .flat_map(|prefix| {
+                ["wait", "prioritized"].map(|key| format!("__keyspace@{}__:{}{}", db, prefix, key))
+            })
+            .collect();
+        let mut failures = 0;
+
+        warn_if_disabled(&client);
+
+        loop {
+            if failures > 0 {
+                thread::sleep(reconnect_backoff(failures));
+            }
+
+            match listen(&client, &channels, &weak, &mut failures, on_resubscribed) {
+                Ok(()) => return,
+                Err(err) => {
+                    failures += 1;
+                    log::warning!("Error listening to keyspace notifications: {:?}", err);
+                }
+            }
+        }
+    });
+
+    wakeups
+}
+
+/// Notifies `wakeups` on every push until it's dropped or the connection
+/// fails.
+fn listen(
+    client: &Client,
+    channels: &[String],
+    wakeups: &Weak<Notify>,
+    failures: &mut u32,
+    on_resubscribed: Option<fn()>,
+) -> Result<()> {
+    let mut connection = client.get_connection()?;
+    let mut pubsub = connection.as_pubsub();
+
+    pubsub.subscribe(channels)?;
+    pubsub.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    if let (true, Some(on_resubscribed)) = (*failures > 0, on_resubscribed) {
+        on_resubscribed();
+    }
+
+    *failures = 0;
+
+    // Jobs may have been pushed while not subscribed
+    let mut pushed = true;
+
+    loop {
+        let Some(wakeups) = wakeups.upgrade() else {
+            return Ok(());
+        };
+
+        if pushed {
+            wakeups.notify_one();
+        }
+
+        drop(wakeups);
+
+        pushed = match pubsub.get_message() {
+            Ok(message) => WAKEUP_EVENTS.contains(&message.get_payload::<String>()?.as_str()),
+            Err(err) if err.is_timeout() => false,
+            Err(err) => return Err(err.into()),
+        };
+    }
+}
+
+/// Logs a warning if the server doesn't send the notifications needed. Servers
+/// that don't allow `CONFIG GET`, e.g. managed ones, are assumed to be set up.
+fn warn_if_disabled(client: &Client) {
+    let flags = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query::<(String, String)>(&mut client.clone());
+
+    if let Ok((_, flags)) = flags {
+        if !notifies_pushes(&flags) {
+            log::warning!(
+                "Keyspace notifications are disabled (notify-keyspace-events is \"{}\"), \
+                 set it to \"Klz\" for workers to be notified of new jobs",
+                flags
+            );
+        }
+    }
+}
+
+/// Whether `notify-keyspace-events` flags include keyspace events of lists
+/// and sorted sets.
+fn notifies_pushes(flags: &str) -> bool {
+    flags.contains('K') && (flags.contains('A') || (flags.contains('l') && flags.contains('z')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_notification_flags() {
+        assert!(notifies_pushes("Klz"));
+        assert!(notifies_pushes("KEA"));
+        assert!(!notifies_pushes(""));
+        assert!(!notifies_pushes("Elz"));
+        assert!(!notifies_pushes("Kl"));
+    }
+}
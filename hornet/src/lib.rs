@@ -1,6 +1,27 @@
 extern crate rmp;
+// Lets `#[derive(JobData)]` be used within the crate
+extern crate self as hornet;
 
+pub mod backoff;
+pub(crate) mod commands;
+pub(crate) mod completion_batcher;
+pub mod connection;
+pub mod context;
 pub mod job;
+pub(crate) mod keyspace_notifications;
+pub(crate) mod lock_manager;
+pub(crate) mod log;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod prefix;
+pub mod queue;
+pub mod queue_events;
 pub(crate) mod queue_keys;
+pub mod queue_meta;
+pub(crate) mod rate_limiter;
+pub mod result_store;
 pub(crate) mod scripts;
 pub mod worker;
+#[cfg(feature = "debug-events")]
+pub mod worker_events;
+pub mod worker_registry;
@@ -0,0 +1,141 @@
+use crate::{
+    connection::TimeoutClient,
+    log,
+    result_store::{delete_released, ResultStore},
+    scripts::{extend_lock::ExtendLock, move_stalled_jobs_to_wait::MoveStalledJobsToWait},
+    worker_registry::{self, WorkerInfo, HEARTBEAT_TTL},
+};
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    static ref EXTEND_LOCK: ExtendLock = ExtendLock::new();
+    static ref MOVE_STALLED_JOBS_TO_WAIT: MoveStalledJobsToWait = MoveStalledJobsToWait::new();
+}
+
+/// Jobs being processed by a worker, along with the token locking each one.
+#[derive(Clone, Default)]
+pub(crate) struct ActiveJobs(Arc<Mutex<HashMap<String, String>>>);
+
+impl ActiveJobs {
+    pub fn track(&self, job_id: &str, token: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(job_id.to_string(), token.to_string());
+    }
+
+    pub fn untrack(&self, job_id: &str) {
+        self.0.lock().unwrap().remove(job_id);
+    }
+
+    fn downgrade(&self) -> Weak<Mutex<HashMap<String, String>>> {
+        Arc::downgrade(&self.0)
+    }
+}
+
+pub(crate) struct LockManagerOptions {
+    pub lock_duration: u64,
+    /// `None` to skip checking for stalled jobs
+    pub stalled_interval: Option<Duration>,
+    pub max_stalled_count: u32,
+    pub on_stalled: Option<fn(&str)>,
+    /// Registered in the queue's `workers` hash on every tick
+    pub worker: WorkerInfo,
+    /// Store the offloaded return values of the removed jobs are deleted from
+    /// on every tick
+    pub result_store: Option<Arc<dyn ResultStore>>,
+}
+
+/// Starts a thread that keeps the locks of `jobs` fresh, sends the worker's
+/// heartbeats and periodically moves the queue's stalled jobs back to wait. A
+/// thread is used rather than a task so that handlers blocking the runtime
+/// can't delay lock renewals. It stops, unregistering the worker, once `jobs`
+/// is dropped.
+pub(crate) fn spawn(
+    prefix: String,
+    mut client: TimeoutClient,
+    jobs: &ActiveJobs,
+    mut opts: LockManagerOptions,
+) {
+    let jobs = jobs.downgrade();
+    let tick = Duration::from_millis(opts.lock_duration / 2);
+
+    thread::spawn(move || {
+        let mut last_stalled_check: Option<Instant> = None;
+
+        loop {
+            let Some(jobs) = jobs.upgrade() else {
+                let _ = worker_registry::unregister(&prefix, &mut client, &opts.worker.id);
+                return;
+            };
+
+            let locked: Vec<(String, String)> = jobs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, token)| (id.clone(), token.clone()))
+                .collect();
+
+            drop(jobs);
+
+            for (job_id, token) in locked {
+                match EXTEND_LOCK.run(&prefix, &mut client, &job_id, &token, opts.lock_duration) {
+                    Ok(true) => {}
+                    res => log::warning!("Error extending lock of job {}: {:?}", job_id, res),
+                }
+            }
+
+            if let Err(err) =
+                worker_registry::heartbeat(&prefix, &mut client, &mut opts.worker, HEARTBEAT_TTL)
+            {
+                log::warning!("Error sending worker heartbeat: {:?}", err);
+            }
+
+            delete_released(opts.result_store.as_deref(), &mut client, &prefix);
+
+            if let Some(interval) = opts.stalled_interval {
+                let due = !matches!(last_stalled_check, Some(at) if at.elapsed() < interval);
+
+                if due {
+                    last_stalled_check = Some(Instant::now());
+                    check_stalled_jobs(&prefix, &mut client, interval, &opts);
+                }
+            }
+
+            thread::sleep(tick);
+        }
+    });
+}
+
+fn check_stalled_jobs(
+    prefix: &str,
+    client: &mut TimeoutClient,
+    interval: Duration,
+    opts: &LockManagerOptions,
+) {
+    match MOVE_STALLED_JOBS_TO_WAIT.run(
+        prefix,
+        client,
+        opts.max_stalled_count,
+        interval.as_millis() as u64,
+    ) {
+        Ok(res) => {
+            for job_id in res.failed {
+                log::warning!("Job {} stalled more than allowable limit", job_id);
+            }
+
+            for job_id in res.stalled {
+                if let Some(on_stalled) = opts.on_stalled {
+                    on_stalled(&job_id);
+                }
+            }
+        }
+        Err(err) => log::warning!("Error checking stalled jobs: {:?}", err),
+    }
+}
@@ -0,0 +1,24 @@
+//! Logging through the `tracing` crate with the `tracing` feature. Without it
+//! nothing is logged: the library never writes to stdout.
+
+/// Errors the worker recovers from, e.g. a job it failed to move.
+macro_rules! warning {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+/// Routine steps, e.g. a job being retried.
+macro_rules! debug {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+pub(crate) use {debug, warning};
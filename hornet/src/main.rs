@@ -1,5 +1,5 @@
 use anyhow::Result;
-use hornet::{job::Job, worker::Worker};
+use hornet::{job::Job, prefix::Prefix, worker::Worker};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -8,7 +8,7 @@ struct ProcessorData {
     age: u8,
 }
 
-fn test_processor(job: &Job<ProcessorData>) -> Result<String> {
+fn test_processor(job: Job<ProcessorData>) -> Result<String> {
     println!("Processing: {:?}", job);
 
     Ok("Done".to_string())
@@ -19,6 +19,7 @@ async fn main() {
     let mut worker = Worker::new(
         "new-queue".to_string(),
         "redis://localhost:6379".to_string(),
+        Prefix::default(),
         1,
         test_processor,
     );
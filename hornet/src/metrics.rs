@@ -0,0 +1,67 @@
+//! Job metrics recorded through the `metrics` crate, so they can be scraped
+//! with any recorder, e.g. `metrics-exporter-prometheus`.
+
+use crate::job::JobOutcome;
+use metrics::{counter, gauge, histogram};
+use std::time::Duration;
+
+pub const JOBS_COMPLETED_TOTAL: &str = "jobs_completed_total";
+/// Jobs that failed for good, out of attempts or not retryable. Attempts
+/// failing before a retry are counted by `JOB_RETRIES_TOTAL` instead
+pub const JOBS_FAILED_TOTAL: &str = "jobs_failed_total";
+pub const JOB_DURATION_SECONDS: &str = "job_duration_seconds";
+pub const ACTIVE_JOBS: &str = "active_jobs";
+/// Failed attempts of jobs that will be retried
+pub const JOB_RETRIES_TOTAL: &str = "job_retries_total";
+/// Jobs completed after at least one failed attempt, a high ratio of which
+/// points at flaky handlers
+pub const JOBS_RECOVERED_TOTAL: &str = "jobs_recovered_total";
+/// Backoff applied before retrying failed jobs
+pub const JOB_BACKOFF_SECONDS: &str = "job_backoff_seconds";
+/// Time spent running Lua scripts, labelled by `script`, to tell Redis time
+/// apart from handler time
+pub const SCRIPT_DURATION_SECONDS: &str = "script_duration_seconds";
+
+pub(crate) fn job_started(queue_name: &str) {
+    gauge!(ACTIVE_JOBS, "queue" => queue_name.to_string()).increment(1.0);
+}
+
+pub(crate) fn job_completed(queue_name: &str, outcome: &JobOutcome) {
+    counter!(JOBS_COMPLETED_TOTAL, "queue" => queue_name.to_string()).increment(1);
+    record_duration(queue_name, outcome);
+
+    if outcome.attempts_made > 1 {
+        counter!(JOBS_RECOVERED_TOTAL, "queue" => queue_name.to_string()).increment(1);
+    }
+}
+
+pub(crate) fn job_failed(queue_name: &str, outcome: &JobOutcome) {
+    record_duration(queue_name, outcome);
+
+    match outcome.retry_in {
+        Some(retry_in) => {
+            counter!(JOB_RETRIES_TOTAL, "queue" => queue_name.to_string()).increment(1);
+            histogram!(JOB_BACKOFF_SECONDS, "queue" => queue_name.to_string())
+                .record(retry_in.as_secs_f64());
+        }
+        None => {
+            counter!(JOBS_FAILED_TOTAL, "queue" => queue_name.to_string()).increment(1);
+        }
+    }
+}
+
+/// Called once the job left the processor, whatever the result.
+pub(crate) fn job_ended(queue_name: &str) {
+    gauge!(ACTIVE_JOBS, "queue" => queue_name.to_string()).decrement(1.0);
+}
+
+/// Called after every script invocation, or batch of invocations sent in a
+/// pipeline, whether it succeeded or not.
+pub(crate) fn script_invoked(script: &'static str, duration: Duration) {
+    histogram!(SCRIPT_DURATION_SECONDS, "script" => script).record(duration.as_secs_f64());
+}
+
+fn record_duration(queue_name: &str, outcome: &JobOutcome) {
+    histogram!(JOB_DURATION_SECONDS, "queue" => queue_name.to_string())
+        .record(outcome.process_time.as_secs_f64());
+}
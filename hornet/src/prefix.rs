@@ -0,0 +1,181 @@
+use anyhow::{bail, Result};
+use redis::ConnectionLike;
+use std::str::FromStr;
+
+/// Prefix of the keys of a queue, `bull` by default as in BullMQ. A queue's
+/// keys are `<prefix>:<queue name>:<key>`, e.g. `bull:emails:wait`.
+///
+/// On Redis Cluster, all the keys a script touches must be in the same slot,
+/// which takes a hash tag, e.g. `{emails}`: only the part between the braces
+/// is hashed. See `has_hash_tag`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prefix(String);
+
+/// Error returned when parsing a prefix that would break key construction or
+/// slot placement, see `Prefix::new`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidPrefix {
+    pub prefix: String,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for InvalidPrefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid prefix `{}`: {}", self.prefix, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidPrefix {}
+
+impl Prefix {
+    /// Checks the prefix: it must not be empty, end with the `:` separator
+    /// added when building keys, or contain whitespace or control characters.
+    /// Braces must form a single, non-empty hash tag, as Redis would
+    /// otherwise hash the whole key and spread the queue's keys over slots.
+    pub fn new(prefix: &str) -> Result<Self, InvalidPrefix> {
+        let invalid = |reason| {
+            Err(InvalidPrefix {
+                prefix: prefix.to_string(),
+                reason,
+            })
+        };
+
+        if prefix.is_empty() {
+            return invalid("must not be empty");
+        }
+
+        if prefix.ends_with(':') {
+            return invalid("must not end with `:`, it's added when building keys");
+        }
+
+        if prefix.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return invalid("must not contain whitespace or control characters");
+        }
+
+        let opening = prefix.find('{');
+        let closing = prefix.find('}');
+
+        match (opening, closing) {
+            (None, None) => {}
+            (Some(opening), Some(closing)) if opening < closing => {
+                if closing == opening + 1 {
+                    return invalid("must not contain an empty hash tag");
+                }
+
+                if prefix[closing + 1..].contains(['{', '}']) {
+                    return invalid("must contain a single hash tag");
+                }
+            }
+            _ => return invalid("has unbalanced braces"),
+        }
+
+        Ok(Prefix(prefix.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether the prefix holds a hash tag, keeping the keys of the queues
+    /// using it in a single Redis Cluster slot.
+    pub fn has_hash_tag(&self) -> bool {
+        self.0.contains('{')
+    }
+
+    /// Prefix of the keys of the given queue, e.g. `bull:emails:`.
+    pub fn queue_key_prefix(&self, queue_name: &str) -> String {
+        format!("{}:{}:", self.0, queue_name)
+    }
+}
+
+/// Fails if the server is part of a Redis Cluster and the prefix has no hash
+/// tag, as the queue's keys would then be spread over slots and its scripts
+/// rejected with `CROSSSLOT` errors.
+pub(crate) fn check_cluster_slots(con: &mut dyn ConnectionLike, prefix: &Prefix) -> Result<()> {
+    let info: String = redis::cmd("INFO").arg("cluster").query(con)?;
+
+    check_slots(&info, prefix)
+}
+
+fn check_slots(cluster_info: &str, prefix: &Prefix) -> Result<()> {
+    let cluster_enabled = cluster_info
+        .lines()
+        .any(|line| line.trim() == "cluster_enabled:1");
+
+    if cluster_enabled && !prefix.has_hash_tag() {
+        bail!(
+            "Prefix `{}` has no hash tag, which Redis Cluster needs to keep a queue's keys in a single slot, e.g. `{{{}}}`",
+            prefix,
+            prefix
+        );
+    }
+
+    Ok(())
+}
+
+impl Default for Prefix {
+    fn default() -> Self {
+        Prefix("bull".to_string())
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = InvalidPrefix;
+
+    fn from_str(prefix: &str) -> Result<Self, Self::Err> {
+        Prefix::new(prefix)
+    }
+}
+
+impl std::fmt::Display for Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_prefixes() {
+        let reason = |prefix: &str| Prefix::new(prefix).unwrap_err().reason;
+
+        assert_eq!(Prefix::default().queue_key_prefix("emails"), "bull:emails:");
+        assert_eq!(
+            "myapp:{bull}".parse::<Prefix>().unwrap().as_str(),
+            "myapp:{bull}"
+        );
+        assert!(Prefix::new("{bull}").unwrap().has_hash_tag());
+        assert!(!Prefix::new("bull").unwrap().has_hash_tag());
+
+        assert_eq!(reason(""), "must not be empty");
+        assert_eq!(
+            reason("bull:"),
+            "must not end with `:`, it's added when building keys"
+        );
+        assert_eq!(
+            reason("my bull"),
+            "must not contain whitespace or control characters"
+        );
+        assert_eq!(reason("{}bull"), "must not contain an empty hash tag");
+        assert_eq!(reason("{a}{b}"), "must contain a single hash tag");
+        assert_eq!(reason("}bull{"), "has unbalanced braces");
+        assert_eq!(reason("{bull"), "has unbalanced braces");
+    }
+
+    #[test]
+    fn requires_a_hash_tag_on_clusters() {
+        let standalone = "# Cluster\r\ncluster_enabled:0\r\n";
+        let cluster = "# Cluster\r\ncluster_enabled:1\r\n";
+
+        assert!(check_slots(standalone, &Prefix::default()).is_ok());
+        assert!(check_slots(cluster, &Prefix::new("{bull}").unwrap()).is_ok());
+        assert_eq!(
+            check_slots(cluster, &Prefix::default())
+                .unwrap_err()
+                .to_string(),
+            "Prefix `bull` has no hash tag, which Redis Cluster needs to keep a queue's keys in a single slot, e.g. `{bull}`"
+        );
+    }
+}
@@ -0,0 +1,2525 @@
+use crate::{
+    connection::{redacted_addr, with_protocol, ProtocolVersion, TimeoutClient},
+    context::HornetContext,
+    job::{Dependencies, JobHandle, JobOptions, JobState, ReservedId},
+    prefix::{check_cluster_slots, Prefix},
+    queue_events::{set_events_maxlen, QueueEvents},
+    queue_meta::QueueMeta,
+    result_store::{delete_released, recorded_reference, rehydrate, ResultStore},
+    scripts::{
+        add_bulk::{AddBulk, AddBulkReturn, BulkEntry},
+        add_delayed_job::AddDelayedJob,
+        add_standard_job::{AddJobReturn, AddStandardJob},
+        change_priority::{ChangePriority, ChangePriorityReturn},
+        clean_jobs_in_set::CleanJobsInSet,
+        load_scripts,
+        obliterate::{Obliterate, ObliterateReturn},
+        pause::Pause,
+        remove_expired_jobs::RemoveExpiredJobs,
+        reprocess_job::{ReprocessJob, ReprocessJobReturn},
+        Invocation,
+    },
+    worker::token_worker_id,
+    worker_registry::{self, WorkerInfo, HEARTBEAT_TTL},
+};
+use anyhow::{anyhow, bail, Result};
+use lazy_static::lazy_static;
+use redis::{streams::StreamMaxlen, Client, Commands, FromRedisValue, LposOptions};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+lazy_static! {
+    static ref ADD_STANDARD_JOB: AddStandardJob = AddStandardJob::new();
+    static ref ADD_BULK: AddBulk = AddBulk::new();
+    static ref ADD_DELAYED_JOB: AddDelayedJob = AddDelayedJob::new();
+    static ref CHANGE_PRIORITY: ChangePriority = ChangePriority::new();
+    static ref OBLITERATE: Obliterate = Obliterate::new();
+    static ref CLEAN_JOBS_IN_SET: CleanJobsInSet = CleanJobsInSet::new();
+    static ref PAUSE: Pause = Pause::new();
+    static ref REMOVE_EXPIRED_JOBS: RemoveExpiredJobs = RemoveExpiredJobs::new();
+    static ref REPROCESS_JOB: ReprocessJob = ReprocessJob::new();
+}
+
+const DEFAULT_BATCH_SIZE: u32 = 1000;
+/// Delayed jobs are scored `timestamp * 0x1000 + counter`, so jobs due at the
+/// same ms keep their insertion order
+const DELAYED_SCORE_FACTOR: u128 = 0x1000;
+/// How often `add_with_limit_blocking` checks whether the backlog went down
+const BACKLOG_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Error returned when adding a job to a queue whose backlog is at its limit.
+/// It can be told apart with `err.downcast_ref::<QueueFull>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueFull {
+    pub waiting: u64,
+    pub max_wait_len: u64,
+}
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Queue is full: {} jobs waiting, limit is {}",
+            self.waiting, self.max_wait_len
+        )
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// Error returned when adding a job whose serialized data is larger than its
+/// `sizeLimit`, see `Queue::size_limit`. It can be told apart with
+/// `err.downcast_ref::<JobTooLarge>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobTooLarge {
+    pub name: String,
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for JobTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The size of job {} ({} bytes) exceeds the limit {} bytes",
+            self.name, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for JobTooLarge {}
+
+/// Error returned by a transactional `Queue::add_bulk` when one of the jobs
+/// can't be added, in which case none is. It can be told apart with
+/// `err.downcast_ref::<BulkRejected>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkRejected {
+    /// Index of the job in the bulk
+    pub index: usize,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for BulkRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "No job of the bulk was added, job {} {}",
+            self.index, self.reason
+        )
+    }
+}
+
+impl std::error::Error for BulkRejected {}
+
+/// Error returned by `Queue::clean_all` when cleaning fails midway, with the
+/// ids of the jobs removed before. It can be told apart with
+/// `err.downcast_ref::<PartiallyCleaned>()`.
+#[derive(Debug)]
+pub struct PartiallyCleaned {
+    pub cleaned: CleanedJobs,
+    pub source: anyhow::Error,
+}
+
+impl std::fmt::Display for PartiallyCleaned {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Cleaning failed after removing {} jobs: {}",
+            self.cleaned.len(),
+            self.source
+        )
+    }
+}
+
+impl std::error::Error for PartiallyCleaned {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// Job added by `Queue::add_bulk`.
+#[derive(Debug, Clone)]
+pub struct BulkJob<Data> {
+    pub name: String,
+    pub data: Data,
+    pub opts: JobOptions,
+}
+
+impl<Data> BulkJob<Data> {
+    pub fn new(name: &str, data: Data) -> Self {
+        BulkJob {
+            name: name.to_string(),
+            data,
+            opts: JobOptions::default(),
+        }
+    }
+
+    pub fn opts(mut self, opts: JobOptions) -> Self {
+        self.opts = opts;
+        self
+    }
+}
+
+/// A job being processed, along with who holds its lock, see
+/// `Queue::get_active_jobs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveJob {
+    pub job_id: String,
+    /// Token the job is locked with, `None` if the lock expired, e.g. the
+    /// worker died and the job is about to be recovered as stalled
+    pub token: Option<String>,
+    /// Worker holding the lock, `None` if it's not connected anymore
+    pub worker: Option<WorkerInfo>,
+    /// Time left before the lock expires unless it's extended
+    pub lock_ttl: Option<Duration>,
+    /// Timestamp in ms of when the job was moved to active
+    pub processed_on: Option<u128>,
+}
+
+/// Number of jobs in each state, see `Queue::get_job_counts`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct JobCounts {
+    pub waiting: u64,
+    pub paused: u64,
+    pub active: u64,
+    pub delayed: u64,
+    pub prioritized: u64,
+    pub completed: u64,
+    pub failed: u64,
+    #[serde(rename = "waitingChildren")]
+    pub waiting_children: u64,
+}
+
+/// Snapshot of a queue and its workers, see `Queue::status`. Serializes to
+/// JSON as is, e.g. to back the status page of an admin dashboard.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueStatus {
+    pub name: String,
+    pub counts: JobCounts,
+    pub paused: bool,
+    /// Timestamp in ms at which the soonest delayed job is due
+    #[serde(rename = "nextDelayedAt")]
+    pub next_delayed_at: Option<u128>,
+    pub workers: Vec<WorkerInfo>,
+}
+
+/// Ids of the jobs removed by `Queue::clean_all`, by state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CleanedJobs {
+    pub completed: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+impl CleanedJobs {
+    pub fn len(&self) -> usize {
+        self.completed.len() + self.failed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl JobCounts {
+    fn get_mut(&mut self, state: &JobState) -> &mut u64 {
+        match state {
+            JobState::Wait => &mut self.waiting,
+            JobState::Paused => &mut self.paused,
+            JobState::Active => &mut self.active,
+            JobState::Delayed => &mut self.delayed,
+            JobState::Prioritized => &mut self.prioritized,
+            JobState::Completed => &mut self.completed,
+            JobState::Failed => &mut self.failed,
+            JobState::WaitingChildren => &mut self.waiting_children,
+        }
+    }
+}
+
+pub struct Queue {
+    queue_name: String,
+    prefix: Prefix,
+    client: Client,
+    /// Pool the queue's commands are sent through, shared with the other
+    /// queues and workers built from the same `HornetContext`
+    commands: TimeoutClient,
+    batch_size: u32,
+    events_maxlen: Option<u64>,
+    events_maxlen_set: AtomicBool,
+    /// Whether the prefix was checked against the server's cluster mode
+    prefix_checked: AtomicBool,
+    result_store: Option<Arc<dyn ResultStore>>,
+    size_limit: Option<usize>,
+}
+
+impl std::fmt::Debug for Queue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Queue")
+            .field("queue_name", &self.queue_name)
+            .field("prefix", &self.get_prefixed_key(""))
+            .field("redis", &redacted_addr(&self.client))
+            .field("batch_size", &self.batch_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Queue {
+    /// Builds a queue whose keys start with `prefix`, e.g. `Prefix::default()`
+    /// or a prefix parsed with `Prefix::new`. On Redis Cluster, adding a job
+    /// fails unless the prefix holds a hash tag.
+    pub fn new(queue_name: String, redis_url: String, prefix: impl Into<Prefix>) -> Self {
+        let client = Client::open(redis_url).unwrap();
+        let commands = TimeoutClient::new(client.clone(), None);
+
+        Self::from_client(queue_name, client, commands).prefix(prefix.into())
+    }
+
+    /// Builds a queue using the context's client, sharing its connections.
+    pub fn from_context(ctx: &HornetContext, queue_name: String) -> Self {
+        Self::from_client(queue_name, ctx.client(), ctx.command_client())
+    }
+
+    fn from_client(queue_name: String, client: Client, commands: TimeoutClient) -> Self {
+        Queue {
+            queue_name,
+            prefix: Prefix::default(),
+            client,
+            commands,
+            batch_size: DEFAULT_BATCH_SIZE,
+            events_maxlen: None,
+            events_maxlen_set: AtomicBool::new(false),
+            prefix_checked: AtomicBool::new(false),
+            result_store: None,
+            size_limit: None,
+        }
+    }
+
+    /// Protocol used to talk to Redis. RESP2 unless the url selects RESP3.
+    pub fn protocol(mut self, protocol: ProtocolVersion) -> Self {
+        self.client = with_protocol(&self.client, protocol);
+        self.commands = TimeoutClient::new(self.client.clone(), None);
+        self
+    }
+
+    /// Prefix of the queue's keys, `bull` by default. Workers must use the
+    /// same one, see `WorkerOptions::prefix`.
+    pub fn prefix(mut self, prefix: Prefix) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Maximum number of jobs removed by a single script invocation in
+    /// `obliterate` and `clean`. Smaller batches keep each call short so Redis
+    /// is never blocked for long, at the cost of more round trips.
+    pub fn batch_size(mut self, batch_size: u32) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Approximate maximum length of the queue's `events` stream, stored in
+    /// the queue's meta key the first time a job is added. Older events are
+    /// trimmed as new ones are added. Unset, the value stored by a worker or
+    /// another producer is kept, `DEFAULT_EVENTS_MAXLEN` if there's none.
+    pub fn events_maxlen(mut self, events_maxlen: u64) -> Self {
+        self.events_maxlen = Some(events_maxlen);
+        self
+    }
+
+    /// Store return values offloaded by workers (see
+    /// `WorkerOptions::result_store`) are read back from, by
+    /// `get_return_value_bytes` and the handles of the queue's jobs.
+    pub fn result_store(mut self, store: Arc<dyn ResultStore>) -> Self {
+        self.result_store = Some(store);
+        self
+    }
+
+    /// Maximum size in bytes of the serialized data of the jobs added to the
+    /// queue, unless they set their own `JobOptions::size_limit`. Larger jobs
+    /// are rejected with `JobTooLarge` before anything is written to Redis.
+    pub fn size_limit(mut self, limit: usize) -> Self {
+        self.size_limit = Some(limit);
+        self
+    }
+
+    /// Adds a job to the queue, returning a handle to it.
+    pub fn add<Data: Serialize>(
+        &self,
+        name: &str,
+        data: &Data,
+        opts: JobOptions,
+    ) -> Result<JobHandle<Data>> {
+        self.add_bytes(name, &serde_json::to_vec(data)?, opts)
+    }
+
+    /// Same as `add`, with a payload that is already serialized, e.g. the
+    /// data of a job read from another queue, stored as is instead of going
+    /// through serde. Workers parse it as JSON, so it must be valid JSON.
+    pub fn add_raw(&self, name: &str, data: &[u8], opts: JobOptions) -> Result<JobHandle> {
+        self.add_bytes(name, data, opts)
+    }
+
+    fn add_bytes<Data>(
+        &self,
+        name: &str,
+        data: &[u8],
+        opts: JobOptions,
+    ) -> Result<JobHandle<Data>> {
+        self.check_job(name, data, &opts)?;
+        self.insert_job(name, data, opts)
+    }
+
+    /// Checks the job's options and size before it's added.
+    fn check_job(&self, name: &str, data: &[u8], opts: &JobOptions) -> Result<()> {
+        opts.validate()?;
+
+        if let Some(limit) = opts.size_limit.or(self.size_limit) {
+            if data.len() > limit {
+                return Err(JobTooLarge {
+                    name: name.to_string(),
+                    size: data.len(),
+                    limit,
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds several jobs in a single round trip, returning the result of each
+    /// job in the same order. Every job is checked before any is sent, so an
+    /// invalid one rejects the whole batch.
+    ///
+    /// Otherwise the jobs are pipelined, which is the fastest, and a job
+    /// failing in Redis doesn't stop the others: its error is returned in its
+    /// place. With `transactional` set, either all the jobs are added or none
+    /// is: they're added by a single script, which first checks that none has
+    /// a custom id already taken (by an existing job or another job of the
+    /// bulk) or a missing parent, and a `BulkRejected` error is returned
+    /// otherwise. Deduplicated jobs don't reject the bulk.
+    pub fn add_bulk<Data: Serialize>(
+        &self,
+        jobs: &[BulkJob<Data>],
+        transactional: bool,
+    ) -> Result<Vec<Result<JobHandle<Data>>>> {
+        let data = jobs
+            .iter()
+            .map(|job| serde_json::to_vec(&job.data))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (job, data) in jobs.iter().zip(&data) {
+            self.check_job(&job.name, data, &job.opts)?;
+        }
+
+        self.ensure_prefix_fits_cluster()?;
+        self.ensure_events_maxlen()?;
+
+        let prefix = self.get_prefixed_key("");
+        let mut client = self.commands.clone();
+
+        if transactional {
+            let entries: Vec<BulkEntry> = jobs
+                .iter()
+                .zip(&data)
+                .map(|(job, data)| BulkEntry {
+                    name: &job.name,
+                    data,
+                    opts: &job.opts,
+                })
+                .collect();
+
+            let rejected = |index, reason| BulkRejected { index, reason }.into();
+
+            return match ADD_BULK
+                .prepare(&prefix, &entries, now_ms())?
+                .invoke::<AddBulkReturn>(&mut client)?
+            {
+                AddBulkReturn::Added(added) => {
+                    Ok(added.into_iter().map(|res| self.added_job(res)).collect())
+                }
+                AddBulkReturn::MissingParentKey(index) => {
+                    Err(rejected(index, "has a missing parent"))
+                }
+                AddBulkReturn::DuplicatedJobId(index) => {
+                    Err(rejected(index, "has a custom id already taken"))
+                }
+            };
+        }
+
+        let invocations = jobs
+            .iter()
+            .zip(&data)
+            .map(|(job, data)| prepare_add(&prefix, &job.name, data, &job.opts))
+            .collect::<Result<Vec<_>>>()?;
+
+        load_scripts(&invocations, &mut client)?;
+
+        let mut pipe = redis::pipe();
+
+        for invocation in &invocations {
+            pipe.invoke_script(invocation.inner());
+        }
+
+        Ok(pipe
+            .query::<Vec<redis::Value>>(&mut client)?
+            .into_iter()
+            .map(|reply| {
+                let res = reply
+                    .extract_error()
+                    .and_then(AddJobReturn::from_owned_redis_value)?;
+
+                self.added_job(res)
+            })
+            .collect())
+    }
+
+    /// Adds the job without validating its options.
+    fn insert_job<Data>(
+        &self,
+        name: &str,
+        data: &[u8],
+        opts: JobOptions,
+    ) -> Result<JobHandle<Data>> {
+        self.ensure_prefix_fits_cluster()?;
+        self.ensure_events_maxlen()?;
+
+        let res = prepare_add(&self.get_prefixed_key(""), name, data, &opts)?
+            .invoke::<AddJobReturn>(&mut self.commands.clone())?;
+
+        self.added_job(res)
+    }
+
+    /// Stores `events_maxlen`, if set, in the queue's meta key before the
+    /// first job is added.
+    fn ensure_events_maxlen(&self) -> Result<()> {
+        let Some(events_maxlen) = self.events_maxlen else {
+            return Ok(());
+        };
+
+        if !self.events_maxlen_set.load(Ordering::Relaxed) {
+            set_events_maxlen(
+                &mut self.commands.clone(),
+                &self.get_prefixed_key(""),
+                events_maxlen,
+            )?;
+            self.events_maxlen_set.store(true, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Checks, before the first job is added, that the prefix keeps the
+    /// queue's keys in a single slot if the server is part of a cluster.
+    fn ensure_prefix_fits_cluster(&self) -> Result<()> {
+        if !self.prefix_checked.load(Ordering::Relaxed) {
+            check_cluster_slots(&mut self.commands.clone(), &self.prefix)?;
+            self.prefix_checked.store(true, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    fn added_job<Data>(&self, res: AddJobReturn) -> Result<JobHandle<Data>> {
+        match res {
+            AddJobReturn::Id(id) => Ok(self.job_handle(id)),
+            AddJobReturn::Deduplicated(id) => Ok(self.job_handle(id).deduplicated()),
+            AddJobReturn::MissingParentKey => Err(anyhow!("Missing parent key")),
+        }
+    }
+
+    /// Adds a job unless `max_wait_len` or more jobs are already waiting to be
+    /// processed, in which case a `QueueFull` error is returned. The check is
+    /// not atomic with the addition, so concurrent producers can overshoot the
+    /// limit slightly.
+    pub fn add_with_limit<Data: Serialize>(
+        &self,
+        name: &str,
+        data: &Data,
+        opts: JobOptions,
+        max_wait_len: u64,
+    ) -> Result<JobHandle<Data>> {
+        let waiting = self.get_waiting_count()?;
+
+        if waiting >= max_wait_len {
+            return Err(QueueFull {
+                waiting,
+                max_wait_len,
+            }
+            .into());
+        }
+
+        self.add(name, data, opts)
+    }
+
+    /// Same as `add_with_limit`, but blocks until fewer than `max_wait_len`
+    /// jobs are waiting, returning a `QueueFull` error if that takes longer
+    /// than `timeout`. Meant to slow producers down to the workers' pace.
+    pub fn add_with_limit_blocking<Data: Serialize>(
+        &self,
+        name: &str,
+        data: &Data,
+        opts: JobOptions,
+        max_wait_len: u64,
+        timeout: Duration,
+    ) -> Result<JobHandle<Data>> {
+        let started_at = Instant::now();
+
+        loop {
+            let waiting = self.get_waiting_count()?;
+
+            if waiting < max_wait_len {
+                return self.add(name, data, opts);
+            }
+
+            if started_at.elapsed() >= timeout {
+                return Err(QueueFull {
+                    waiting,
+                    max_wait_len,
+                }
+                .into());
+            }
+
+            std::thread::sleep(
+                BACKLOG_POLL_INTERVAL.min(timeout.saturating_sub(started_at.elapsed())),
+            );
+        }
+    }
+
+    /// Number of jobs waiting to be processed: in the wait list, in the paused
+    /// list if the queue is paused, or prioritized. Delayed jobs are not
+    /// counted.
+    pub fn get_waiting_count(&self) -> Result<u64> {
+        let (wait, paused, prioritized): (u64, u64, u64) = redis::pipe()
+            .llen(self.get_prefixed_key("wait"))
+            .llen(self.get_prefixed_key("paused"))
+            .zcard(self.get_prefixed_key("prioritized"))
+            .query(&mut self.commands.clone())?;
+
+        Ok(wait + paused + prioritized)
+    }
+
+    /// Id the next job added without a custom id will get, read from the
+    /// counter BullMQ keeps in the queue's `id` key. Only a hint for
+    /// diagnostics, other producers may take it in the meantime.
+    pub fn peek_next_id(&self) -> Result<u64> {
+        let last: Option<u64> = self.commands.clone().get(self.get_prefixed_key("id"))?;
+
+        Ok(last.unwrap_or(0) + 1)
+    }
+
+    /// Takes the next id from the queue's counter without adding a job, e.g.
+    /// to hand out a reference to a job before its payload is ready. The job
+    /// is then added with `JobOptions::reserved_id`.
+    pub fn reserve_id(&self) -> Result<ReservedId> {
+        let id: u64 = self.commands.clone().incr(self.get_prefixed_key("id"), 1)?;
+
+        Ok(ReservedId(id.to_string()))
+    }
+
+    /// Number of jobs that will be processed before the job, e.g. to show
+    /// "you are #7 in line": 0 for the next one. Jobs in the wait (or paused)
+    /// list come first, oldest first, then prioritized jobs by priority.
+    /// `None` if the job isn't waiting, e.g. it's delayed, active or done.
+    pub fn get_job_position(&self, job_id: &str) -> Result<Option<usize>> {
+        let wait_key = self.get_prefixed_key("wait");
+        let paused_key = self.get_prefixed_key("paused");
+
+        let (wait_index, wait, paused_index, paused, rank): (
+            Option<usize>,
+            usize,
+            Option<usize>,
+            usize,
+            Option<usize>,
+        ) = redis::pipe()
+            .atomic()
+            .lpos(&wait_key, job_id, LposOptions::default())
+            .llen(&wait_key)
+            .lpos(&paused_key, job_id, LposOptions::default())
+            .llen(&paused_key)
+            .zrank(self.get_prefixed_key("prioritized"), job_id)
+            .query(&mut self.commands.clone())?;
+
+        // Jobs are pushed to the head of the lists and taken from the tail
+        Ok(match (wait_index, paused_index, rank) {
+            (Some(index), _, _) => Some(wait - 1 - index),
+            (_, Some(index), _) => Some(paused - 1 - index),
+            (_, _, Some(rank)) => Some(wait + paused + rank),
+            _ => None,
+        })
+    }
+
+    /// Timestamp in ms at which the soonest delayed job is due, `None` if no
+    /// job is delayed.
+    pub fn next_delayed_at(&self) -> Result<Option<u128>> {
+        let soonest: Vec<(String, f64)> =
+            self.commands
+                .clone()
+                .zrange_withscores(self.get_prefixed_key("delayed"), 0, 0)?;
+
+        Ok(soonest.first().map(|(_, score)| delayed_timestamp(*score)))
+    }
+
+    /// Adds a job and blocks until a worker completes it, returning its
+    /// deserialized return value. A failed job is returned as an error with its
+    /// failed reason, and so is a job that doesn't finish within `timeout`.
+    pub fn add_and_wait<Data: Serialize, Return: DeserializeOwned + 'static>(
+        &self,
+        name: &str,
+        data: &Data,
+        opts: JobOptions,
+        timeout: Duration,
+    ) -> Result<Return> {
+        // Subscribe before adding so a job that finishes right away is not missed
+        let events = QueueEvents::from_client(&self.get_prefixed_key(""), self.client.clone())?;
+
+        self.add(name, data, opts)?
+            .returning()
+            .wait_with(events, timeout)
+    }
+
+    /// Changes the priority of an existing job. Waiting jobs are re-queued
+    /// according to the new priority; 0 moves the job back to the plain
+    /// `wait` list.
+    pub fn change_priority(&self, job_id: &str, priority: u32) -> Result<()> {
+        match CHANGE_PRIORITY.run(
+            &self.get_prefixed_key(""),
+            &mut self.commands.clone(),
+            job_id,
+            priority,
+            false,
+        )? {
+            ChangePriorityReturn::Ok => Ok(()),
+            ChangePriorityReturn::MissingKey => Err(anyhow!("Job {} does not exist", job_id)),
+        }
+    }
+
+    /// Reads the queue's `meta` hash.
+    pub fn get_meta(&self) -> Result<QueueMeta> {
+        let hash: HashMap<String, String> = self
+            .commands
+            .clone()
+            .hgetall(self.get_prefixed_key("meta"))?;
+
+        Ok(QueueMeta::from_hash(hash))
+    }
+
+    /// Writes the writable fields of `meta` to the queue's `meta` hash,
+    /// removing the ones set to `None`. `paused` is ignored, use `pause` and
+    /// `resume` instead.
+    pub fn set_meta(&self, meta: &QueueMeta) -> Result<()> {
+        let key = self.get_prefixed_key("meta");
+        let mut pipe = redis::pipe();
+
+        pipe.atomic();
+
+        for (field, value) in meta.writable_fields() {
+            match value {
+                Some(value) => pipe.hset(&key, field, value).ignore(),
+                None => pipe.hdel(&key, field).ignore(),
+            };
+        }
+
+        pipe.query::<()>(&mut self.commands.clone())?;
+
+        Ok(())
+    }
+
+    /// Pauses the queue: workers stop fetching its jobs, which wait in the
+    /// `paused` list until it's resumed. Emits a `paused` event.
+    pub fn pause(&self) -> Result<()> {
+        PAUSE.run(&self.get_prefixed_key(""), &mut self.commands.clone(), true)
+    }
+
+    /// Resumes a paused queue, emitting a `resumed` event.
+    pub fn resume(&self) -> Result<()> {
+        PAUSE.run(
+            &self.get_prefixed_key(""),
+            &mut self.commands.clone(),
+            false,
+        )
+    }
+
+    /// Whether the queue is paused, see `pause`.
+    pub fn is_paused(&self) -> Result<bool> {
+        Ok(self
+            .client
+            .clone()
+            .hexists(self.get_prefixed_key("meta"), "paused")?)
+    }
+
+    /// Sets the queue-level concurrency: workers lower their own to it, within
+    /// a few seconds. `None` lifts the limit.
+    pub fn set_global_concurrency(&self, concurrency: Option<u32>) -> Result<()> {
+        let key = self.get_prefixed_key("meta");
+        let mut client = self.commands.clone();
+
+        let _: () = match concurrency {
+            Some(concurrency) => client.hset(key, "concurrency", concurrency)?,
+            None => client.hdel(key, "concurrency")?,
+        };
+
+        Ok(())
+    }
+
+    /// Pauses the queue and removes all of its jobs and keys, `batch_size` jobs
+    /// at a time. Fails if there are active jobs, unless `force` is set.
+    pub fn obliterate(&self, force: bool) -> Result<()> {
+        let prefix = self.get_prefixed_key("");
+        let mut client = self.commands.clone();
+
+        self.pause()?;
+
+        loop {
+            match OBLITERATE.run(&prefix, &mut client, self.batch_size, force)? {
+                ObliterateReturn::Done => {
+                    delete_released(self.result_store.as_deref(), &mut client, &prefix);
+
+                    return Ok(());
+                }
+                ObliterateReturn::HasMore => {
+                    delete_released(self.result_store.as_deref(), &mut client, &prefix);
+                }
+                ObliterateReturn::NotPaused => {
+                    return Err(anyhow!("Cannot obliterate non-paused queue"))
+                }
+                ObliterateReturn::HasActiveJobs => {
+                    return Err(anyhow!("Cannot obliterate queue with active jobs"))
+                }
+            }
+        }
+    }
+
+    /// Removes jobs in `state` older than `grace` milliseconds, returning their
+    /// ids. At most `limit` jobs are removed (0 means no limit), in batches of
+    /// `batch_size`.
+    pub fn clean(&self, grace: u64, limit: u32, state: JobState) -> Result<Vec<String>> {
+        let mut removed: Vec<String> = Vec::new();
+
+        self.clean_into(grace, limit, state, &mut removed)?;
+
+        Ok(removed)
+    }
+
+    /// Same as `clean`, adding the ids to `removed` as their batches are
+    /// removed, so they're kept if a later batch fails.
+    fn clean_into(
+        &self,
+        grace: u64,
+        limit: u32,
+        state: JobState,
+        removed: &mut Vec<String>,
+    ) -> Result<()> {
+        let prefix = self.get_prefixed_key("");
+        let mut client = self.commands.clone();
+
+        loop {
+            let batch = match limit {
+                0 => self.batch_size,
+                _ => self.batch_size.min(limit - removed.len() as u32),
+            };
+
+            let ids = CLEAN_JOBS_IN_SET.run(&prefix, &mut client, &state, grace, batch)?;
+            let exhausted = (ids.len() as u32) < batch;
+
+            delete_released(self.result_store.as_deref(), &mut client, &prefix);
+
+            removed.extend(ids);
+
+            if exhausted || (limit > 0 && removed.len() as u32 >= limit) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Same as `clean` on both completed and failed jobs, e.g. for a nightly
+    /// retention sweep. `limit` applies to each state. If cleaning fails
+    /// midway, the error is a `PartiallyCleaned` holding the ids of the jobs
+    /// already removed.
+    pub fn clean_all(&self, grace: u64, limit: u32) -> Result<CleanedJobs> {
+        let mut cleaned = CleanedJobs::default();
+        let res = self
+            .clean_into(grace, limit, JobState::Completed, &mut cleaned.completed)
+            .and_then(|()| self.clean_into(grace, limit, JobState::Failed, &mut cleaned.failed));
+
+        match res {
+            Ok(()) => Ok(cleaned),
+            Err(source) => Err(PartiallyCleaned { cleaned, source }.into()),
+        }
+    }
+
+    /// Trims the queue's `events` stream down to its `maxlen` latest events,
+    /// returning how many were removed. Unlike `events_maxlen`, trimming is
+    /// exact and immediate, e.g. to reclaim the memory of a stream that grew
+    /// large before a cap was set.
+    pub fn trim_events(&self, maxlen: usize) -> Result<usize> {
+        let removed: usize = self.commands.clone().xtrim(
+            self.get_prefixed_key("events"),
+            StreamMaxlen::Equals(maxlen),
+        )?;
+
+        Ok(removed)
+    }
+
+    /// Moves a completed or failed job back to the wait list so it gets
+    /// processed again. Fails if the job does not exist or is not currently in
+    /// `from_state`.
+    pub fn retry_job(&self, job_id: &str, from_state: JobState) -> Result<()> {
+        if !matches!(from_state, JobState::Completed | JobState::Failed) {
+            bail!(
+                "Only completed or failed jobs can be retried, got {}",
+                from_state.as_str()
+            );
+        }
+
+        match REPROCESS_JOB.run(
+            &self.get_prefixed_key(""),
+            &mut self.commands.clone(),
+            job_id,
+            &from_state,
+        )? {
+            ReprocessJobReturn::Ok => Ok(()),
+            ReprocessJobReturn::MissingKey => Err(anyhow!("Job {} does not exist", job_id)),
+            ReprocessJobReturn::JobNotInState => Err(anyhow!(
+                "Job {} is not in the {} state",
+                job_id,
+                from_state.as_str()
+            )),
+        }
+    }
+
+    /// Returns the workers currently connected to the queue, i.e. that sent a
+    /// heartbeat in the last minute, oldest first.
+    pub fn get_workers(&self) -> Result<Vec<WorkerInfo>> {
+        worker_registry::list(
+            &self.get_prefixed_key(""),
+            &mut self.commands.clone(),
+            HEARTBEAT_TTL,
+        )
+    }
+
+    /// Gathers the job counts, paused state, next delayed job and connected
+    /// workers of the queue, e.g. for a health check or an admin page. They
+    /// are read one after the other, not as a single atomic snapshot.
+    pub fn status(&self) -> Result<QueueStatus> {
+        Ok(QueueStatus {
+            name: self.queue_name.clone(),
+            counts: self.get_job_counts()?,
+            paused: self.is_paused()?,
+            next_delayed_at: self.next_delayed_at()?,
+            workers: self.get_workers()?,
+        })
+    }
+
+    /// Returns the active jobs, each with the token locking it, the worker it
+    /// belongs to and the lock's TTL, to find out which worker holds a stuck
+    /// job and since when. Reads `batch_size` jobs per round trip.
+    pub fn get_active_jobs(&self) -> Result<Vec<ActiveJob>> {
+        let mut client = self.commands.clone();
+        let ids = self.get_job_ids(&mut client, &JobState::Active)?;
+        let workers: HashMap<String, WorkerInfo> = self
+            .get_workers()?
+            .into_iter()
+            .map(|info| (info.id.clone(), info))
+            .collect();
+        let mut jobs = Vec::with_capacity(ids.len());
+
+        for chunk in ids.chunks(self.batch_size as usize) {
+            let mut pipe = redis::pipe();
+
+            for id in chunk {
+                let lock_key = self.get_prefixed_key(&format!("{}:lock", id));
+
+                pipe.get(&lock_key)
+                    .pttl(&lock_key)
+                    .hget(self.get_prefixed_key(id), "processedOn");
+            }
+
+            let locks: Vec<(Option<String>, i64, Option<u128>)> = pipe.query(&mut client)?;
+
+            for (id, (token, ttl, processed_on)) in chunk.iter().zip(locks) {
+                jobs.push(ActiveJob {
+                    job_id: id.clone(),
+                    worker: token
+                        .as_deref()
+                        .and_then(|token| workers.get(token_worker_id(token)))
+                        .cloned(),
+                    token,
+                    // -2 if the lock doesn't exist, -1 if it has no TTL
+                    lock_ttl: u64::try_from(ttl).ok().map(Duration::from_millis),
+                    processed_on,
+                });
+            }
+        }
+
+        Ok(jobs)
+    }
+
+    /// Returns a handle to the job, or `None` if it doesn't exist.
+    pub fn get_job(&self, job_id: &str) -> Result<Option<JobHandle>> {
+        let mut client = self.commands.clone();
+
+        if !client.exists(self.get_prefixed_key(job_id))? {
+            return Ok(None);
+        }
+
+        Ok(Some(self.job_handle(job_id.to_string())))
+    }
+
+    fn job_handle<Data>(&self, id: String) -> JobHandle<Data> {
+        JobHandle::new(id, self.get_prefixed_key(""), self.client.clone())
+            .result_store(self.result_store.clone())
+    }
+
+    /// Returns the job's return value as stored, without decoding it as JSON,
+    /// e.g. for a `RawReturnValue`. `None` if the job doesn't exist or has not
+    /// completed. Offloaded return values are read from the result store.
+    pub fn get_return_value_bytes(&self, job_id: &str) -> Result<Option<Vec<u8>>> {
+        let mut client = self.commands.clone();
+        let value: Option<Vec<u8>> = client.hget(self.get_prefixed_key(job_id), "returnvalue")?;
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        let reference = recorded_reference(&mut client, &self.get_prefixed_key(""), job_id)?;
+
+        rehydrate(self.result_store.as_deref(), value, reference).map(Some)
+    }
+
+    /// Returns why the job failed, or `None` if the job doesn't exist or has
+    /// not failed. Only reads that field instead of the whole job.
+    pub fn get_failed_reason(&self, job_id: &str) -> Result<Option<String>> {
+        let reason: Option<String> = self
+            .client
+            .clone()
+            .hget(self.get_prefixed_key(job_id), "failedReason")?;
+
+        Ok(reason)
+    }
+
+    /// Counts the jobs in each state, in a single round trip once the jobs
+    /// whose `ttl` elapsed are removed from the states they were waiting in.
+    pub fn get_job_counts(&self) -> Result<JobCounts> {
+        let mut client = self.commands.clone();
+        let mut pipe = redis::pipe();
+
+        REMOVE_EXPIRED_JOBS.run(&self.get_prefixed_key(""), &mut client, now_ms())?;
+
+        for state in [JobState::Wait, JobState::Paused, JobState::Active] {
+            pipe.llen(self.get_prefixed_key(state.as_str()));
+        }
+
+        for state in [
+            JobState::Delayed,
+            JobState::Prioritized,
+            JobState::Completed,
+            JobState::Failed,
+            JobState::WaitingChildren,
+        ] {
+            pipe.zcard(self.get_prefixed_key(state.as_str()));
+        }
+
+        let (waiting, paused, active, delayed, prioritized, completed, failed, waiting_children) =
+            pipe.query(&mut client)?;
+
+        Ok(JobCounts {
+            waiting,
+            paused,
+            active,
+            delayed,
+            prioritized,
+            completed,
+            failed,
+            waiting_children,
+        })
+    }
+
+    /// Counts the jobs named `name` in each state. Jobs are not indexed by
+    /// name, so this reads the id of every job of the queue and then the
+    /// `name` field of each, `batch_size` jobs per round trip: the cost grows
+    /// with the size of the queue, including the completed and failed jobs
+    /// kept. Meant for dashboards polling every few seconds, not hot paths.
+    pub fn get_job_counts_by_name(&self, name: &str) -> Result<JobCounts> {
+        let mut client = self.commands.clone();
+        let mut counts = JobCounts::default();
+
+        for state in [
+            JobState::Wait,
+            JobState::Paused,
+            JobState::Active,
+            JobState::Delayed,
+            JobState::Prioritized,
+            JobState::Completed,
+            JobState::Failed,
+            JobState::WaitingChildren,
+        ] {
+            let ids = self.get_job_ids(&mut client, &state)?;
+
+            for chunk in ids.chunks(self.batch_size as usize) {
+                let mut pipe = redis::pipe();
+
+                for id in chunk {
+                    pipe.hget(self.get_prefixed_key(id), "name");
+                }
+
+                let names: Vec<Option<String>> = pipe.query(&mut client)?;
+
+                *counts.get_mut(&state) += names
+                    .iter()
+                    .filter(|job_name| job_name.as_deref() == Some(name))
+                    .count() as u64;
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Returns the children of a flow's parent job, split between those that
+    /// were processed and those still pending. Both are empty if the job has
+    /// no children or doesn't exist.
+    pub fn get_dependencies(&self, parent_job_id: &str) -> Result<Dependencies> {
+        let job_key = self.get_prefixed_key(parent_job_id);
+
+        let (mut processed, mut unprocessed): (Vec<String>, Vec<String>) = redis::pipe()
+            .hkeys(format!("{}:processed", job_key))
+            .smembers(format!("{}:dependencies", job_key))
+            .query(&mut self.commands.clone())?;
+
+        // Sets and hashes are unordered
+        processed.sort();
+        unprocessed.sort();
+
+        Ok(Dependencies {
+            processed,
+            unprocessed,
+        })
+    }
+
+    /// Returns the job's log lines between `start` and `end` (inclusive,
+    /// negative indexes count from the end) along with the total number of
+    /// lines stored for the job.
+    pub fn get_job_logs(
+        &self,
+        job_id: &str,
+        start: isize,
+        end: isize,
+    ) -> Result<(Vec<String>, usize)> {
+        let logs_key = self.get_prefixed_key(&format!("{}:logs", job_id));
+        let mut client = self.commands.clone();
+
+        let logs: Vec<String> = client.lrange(&logs_key, start, end)?;
+        let count: usize = client.llen(&logs_key)?;
+
+        Ok((logs, count))
+    }
+
+    /// Returns every job in `state` as a JSON object holding the job's id and
+    /// the raw fields of its hash, e.g. for backups or to move jobs to another
+    /// Redis instance with `import`.
+    pub fn export(&self, state: JobState) -> Result<Vec<Value>> {
+        let mut client = self.commands.clone();
+        let ids = self.get_job_ids(&mut client, &state)?;
+
+        let mut pipe = redis::pipe();
+
+        for id in ids.iter() {
+            pipe.hgetall(self.get_prefixed_key(id));
+        }
+
+        let hashes: Vec<HashMap<String, String>> = pipe.query(&mut client)?;
+        let mut jobs = vec![];
+
+        for (id, hash) in ids.into_iter().zip(hashes) {
+            // The job was removed in between
+            if hash.is_empty() {
+                continue;
+            }
+
+            let mut job: Map<String, Value> = hash
+                .into_iter()
+                .map(|(field, value)| (field, Value::String(value)))
+                .collect();
+
+            job.insert("id".to_string(), Value::String(id));
+            jobs.push(Value::Object(job));
+        }
+
+        Ok(jobs)
+    }
+
+    /// Re-creates jobs previously returned by `export`, keeping their ids, and
+    /// returns the ids imported. Waiting jobs go through the regular add path;
+    /// completed and failed jobs are restored as they were. A job whose id is
+    /// already used is skipped, unless `overwrite` is set, in which case the
+    /// existing job is removed first (it can't be active).
+    pub fn import(&self, state: JobState, jobs: &[Value], overwrite: bool) -> Result<Vec<String>> {
+        if !matches!(
+            state,
+            JobState::Wait | JobState::Completed | JobState::Failed
+        ) {
+            bail!(
+                "Only waiting, completed or failed jobs can be imported, got {}",
+                state.as_str()
+            );
+        }
+
+        let mut client = self.commands.clone();
+        let mut imported = vec![];
+
+        for job in jobs {
+            let fields = job
+                .as_object()
+                .ok_or_else(|| anyhow!("Exported job must be an object"))?;
+            let field = |name: &str| fields.get(name).and_then(|v| v.as_str());
+            let id = field("id").ok_or_else(|| anyhow!("Exported job is missing its id"))?;
+            let job_key = self.get_prefixed_key(id);
+
+            if client.exists(&job_key)? {
+                if !overwrite {
+                    continue;
+                }
+
+                self.remove_job_from_any_state(&mut client, id)?;
+            }
+
+            match state {
+                JobState::Wait => {
+                    let mut opts: JobOptions = serde_json::from_str(field("opts").unwrap_or("{}"))?;
+                    let data: Value = serde_json::from_str(field("data").unwrap_or("{}"))?;
+
+                    opts.job_id = Some(id.to_string());
+                    // Exported ids are integers, which `validate` rejects
+                    self.insert_job::<Value>(
+                        field("name").unwrap_or_default(),
+                        &serde_json::to_vec(&data)?,
+                        opts,
+                    )?;
+                }
+                _ => {
+                    let finished_on: u64 = field("finishedOn")
+                        .and_then(|v| v.parse().ok())
+                        .ok_or_else(|| anyhow!("Finished job {} is missing finishedOn", id))?;
+                    let hash: Vec<(&String, &str)> = fields
+                        .iter()
+                        .filter(|(name, _)| name.as_str() != "id")
+                        .filter_map(|(name, value)| value.as_str().map(|v| (name, v)))
+                        .collect();
+
+                    redis::pipe()
+                        .atomic()
+                        .hset_multiple(&job_key, &hash)
+                        .ignore()
+                        .zadd(self.get_prefixed_key(state.as_str()), id, finished_on)
+                        .ignore()
+                        .query::<()>(&mut client)?;
+                }
+            }
+
+            imported.push(id.to_string());
+        }
+
+        Ok(imported)
+    }
+
+    fn get_job_ids(&self, client: &mut TimeoutClient, state: &JobState) -> Result<Vec<String>> {
+        let key = self.get_prefixed_key(state.as_str());
+
+        let ids = match state {
+            JobState::Wait | JobState::Active | JobState::Paused => client.lrange(key, 0, -1)?,
+            _ => client.zrange(key, 0, -1)?,
+        };
+
+        Ok(ids)
+    }
+
+    fn remove_job_from_any_state(&self, client: &mut TimeoutClient, job_id: &str) -> Result<()> {
+        let active: Vec<String> = client.lrange(self.get_prefixed_key("active"), 0, -1)?;
+
+        if active.iter().any(|id| id == job_id) {
+            bail!("Job {} is active and can't be overwritten", job_id);
+        }
+
+        let mut pipe = redis::pipe();
+
+        pipe.atomic();
+
+        for list in ["wait", "paused"] {
+            pipe.lrem(self.get_prefixed_key(list), 0, job_id).ignore();
+        }
+
+        for set in [
+            "completed",
+            "failed",
+            "delayed",
+            "prioritized",
+            "waiting-children",
+        ] {
+            pipe.zrem(self.get_prefixed_key(set), job_id).ignore();
+        }
+
+        pipe.del(&[
+            self.get_prefixed_key(job_id),
+            self.get_prefixed_key(&format!("{}:logs", job_id)),
+        ])
+        .ignore();
+
+        pipe.query::<()>(client)?;
+
+        Ok(())
+    }
+
+    fn get_prefixed_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix.queue_key_prefix(&self.queue_name), key)
+    }
+}
+
+/// Invocation adding the job to the delayed set if it's delayed, to wait
+/// otherwise. The same timestamp picks the script and is sent to it, so a
+/// job due now can't end up in the delayed set with no delay left.
+fn prepare_add(
+    prefix: &str,
+    name: &str,
+    data: &[u8],
+    opts: &JobOptions,
+) -> Result<Invocation<'static>> {
+    let timestamp = now_ms();
+
+    if opts.is_delayed_at(timestamp) {
+        ADD_DELAYED_JOB.prepare(prefix, name, data, opts, timestamp)
+    } else {
+        ADD_STANDARD_JOB.prepare(prefix, name, data, opts, timestamp)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn delayed_timestamp(score: f64) -> u128 {
+    score as u128 / DELAYED_SCORE_FACTOR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        job::{
+            BackoffOptions, BackoffStrategy, DeduplicationOptions, InvalidJobOptions, Job,
+            KeepJobs, RawReturnValue,
+        },
+        result_store::{record_reference, tests::MemoryStore},
+        scripts::{
+            move_to_active::{MoveToActive, MoveToActiveArgs, MoveToActiveReturn},
+            move_to_delayed::{MoveToDelayed, MoveToDelayedReturn},
+            move_to_finished::{MoveToFinished, MoveToFinishedArgs, MoveToFinishedTarget},
+            record_idempotency_key::RecordIdempotencyKey,
+        },
+        worker::{FetchStrategy, Worker, WorkerOptions},
+    };
+
+    fn test_queue(name: &str) -> Queue {
+        Queue::new(
+            format!("{}-{}", name, uuid::Uuid::new_v4()),
+            "redis://localhost:6379".to_string(),
+            Prefix::default(),
+        )
+    }
+
+    /// Moves the next job to active, without locking it, as a worker would.
+    fn try_fetch(queue: &Queue) -> MoveToActiveReturn<String> {
+        MoveToActive::new()
+            .run::<String>(
+                &queue.get_prefixed_key(""),
+                &mut queue.client.clone(),
+                MoveToActiveArgs {
+                    token: "0".to_string(),
+                    lock_duration: 10_000,
+                    name: None,
+                    limiter: None,
+                    namespace: None,
+                },
+            )
+            .unwrap()
+    }
+
+    /// Same as `try_fetch`, expecting a job.
+    fn fetch(queue: &Queue) -> Job<String> {
+        let MoveToActiveReturn::Job(job) = try_fetch(queue) else {
+            panic!("job was not fetched");
+        };
+
+        *job
+    }
+
+    /// Moves `job`, fetched with `fetch`, to `target` as a worker would, with
+    /// a null return value and the retention of the job's options.
+    fn finish(queue: &Queue, job: &Job<String>, target: MoveToFinishedTarget) {
+        let keep_jobs = match target {
+            MoveToFinishedTarget::Completed => job.opts.keep_completed(),
+            MoveToFinishedTarget::Failed => job.opts.keep_failed(),
+        };
+
+        MoveToFinished::new()
+            .run::<()>(
+                &queue.get_prefixed_key(""),
+                &mut queue.client.clone(),
+                &job.id,
+                b"null",
+                target,
+                MoveToFinishedArgs {
+                    token: "0".to_string(),
+                    keep_jobs,
+                    lock_duration: 10_000,
+                    max_attempts: 1,
+                    max_metrics_size: 100,
+                    fail_parent_on_fail: false,
+                    remove_dependency_on_fail: false,
+                    fetch_next: false,
+                    name: None,
+                    limiter: None,
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_jobs_above_the_waiting_limit() {
+        let queue = test_queue("limit");
+
+        for data in 0..2 {
+            queue
+                .add_with_limit("job", &data, JobOptions::default(), 2)
+                .unwrap();
+        }
+
+        let Err(err) = queue.add_with_limit("job", &2, JobOptions::default(), 2) else {
+            panic!("job added to a full queue");
+        };
+        assert_eq!(
+            err.downcast_ref::<QueueFull>(),
+            Some(&QueueFull {
+                waiting: 2,
+                max_wait_len: 2,
+            })
+        );
+        assert_eq!(queue.get_waiting_count().unwrap(), 2);
+
+        // Room is made once a job is picked up
+        fetch(&queue);
+        queue
+            .add_with_limit("job", &2, JobOptions::default(), 2)
+            .unwrap();
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn splits_the_children_of_a_parent_by_progress() {
+        let queue = test_queue("flow");
+        let mut client = queue.client.clone();
+        let parent = queue
+            .add("parent", &"parent".to_string(), JobOptions::default())
+            .unwrap();
+        let parent_key = queue.get_prefixed_key(parent.id());
+        let mut children = vec![];
+
+        // Linked as a BullMQ flow producer would
+        for data in ["first", "second"] {
+            let child = queue
+                .add("child", &data.to_string(), JobOptions::default())
+                .unwrap();
+            let child_key = queue.get_prefixed_key(child.id());
+
+            let _: () = client.hset(&child_key, "parentKey", &parent_key).unwrap();
+            let _: () = client
+                .sadd(format!("{}:dependencies", parent_key), &child_key)
+                .unwrap();
+            children.push(child_key);
+        }
+
+        assert_eq!(
+            queue.get_dependencies(parent.id()).unwrap(),
+            Dependencies {
+                processed: vec![],
+                unprocessed: children.clone(),
+            }
+        );
+
+        assert_eq!(fetch(&queue).id, parent.id());
+        finish(&queue, &fetch(&queue), MoveToFinishedTarget::Completed);
+
+        assert_eq!(
+            queue.get_dependencies(parent.id()).unwrap(),
+            Dependencies {
+                processed: vec![children[0].clone()],
+                unprocessed: vec![children[1].clone()],
+            }
+        );
+        assert_eq!(
+            queue.get_dependencies("missing").unwrap(),
+            Dependencies::default()
+        );
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn stores_the_job_name() {
+        let queue = test_queue("named");
+
+        let handle = queue
+            .add("send-email", &"hello".to_string(), JobOptions::default())
+            .unwrap();
+
+        assert_eq!(handle.get().unwrap().unwrap().name, "send-email");
+
+        let job = fetch(&queue);
+
+        assert_eq!(job.name, "send-email");
+        assert_eq!(job.data, "hello");
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn skips_jobs_expired_before_being_picked_up() {
+        let queue = test_queue("expired");
+
+        queue
+            .add(
+                "job",
+                &"expiring".to_string(),
+                JobOptions {
+                    ttl_ms: Some(50),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        queue
+            .add("job", &"kept".to_string(), JobOptions::default())
+            .unwrap();
+
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert!(matches!(try_fetch(&queue), MoveToActiveReturn::None));
+        assert_eq!(fetch(&queue).data, "kept");
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn counts_no_expired_jobs() {
+        let queue = test_queue("expired-counts");
+        let expiring = |delay| JobOptions {
+            ttl_ms: Some(50),
+            delay,
+            ..Default::default()
+        };
+
+        queue
+            .add("job", &"waiting".to_string(), expiring(None))
+            .unwrap();
+        queue
+            .add("job", &"delayed".to_string(), expiring(Some(60_000)))
+            .unwrap();
+        queue
+            .add("job", &"kept".to_string(), JobOptions::default())
+            .unwrap();
+
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(
+            queue.get_job_counts().unwrap(),
+            JobCounts {
+                waiting: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(fetch(&queue).data, "kept");
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn adds_jobs_due_at_a_timestamp_to_the_delayed_set() {
+        let queue = test_queue("scheduled");
+        let due_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+            + 60_000;
+
+        let handle = queue
+            .add(
+                "remind",
+                &"stand up".to_string(),
+                JobOptions::default().delay_until(due_at),
+            )
+            .unwrap();
+
+        assert!(handle.is_delayed().unwrap());
+        assert_eq!(queue.next_delayed_at().unwrap(), Some(due_at as u128));
+
+        let handle = queue
+            .add(
+                "remind",
+                &"too late".to_string(),
+                JobOptions::default().delay_until(1_000),
+            )
+            .unwrap();
+
+        assert_eq!(handle.state().unwrap(), Some(JobState::Wait));
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn reports_its_status() {
+        let queue = test_queue("status");
+
+        queue
+            .add("resize", &"a.png".to_string(), JobOptions::default())
+            .unwrap();
+        queue
+            .add(
+                "resize",
+                &"b.png".to_string(),
+                JobOptions {
+                    delay: Some(60_000),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        queue.pause().unwrap();
+
+        let status = queue.status().unwrap();
+
+        assert_eq!(
+            status.counts,
+            JobCounts {
+                paused: 1,
+                delayed: 1,
+                ..Default::default()
+            }
+        );
+        assert!(status.paused);
+        assert!(status.next_delayed_at.is_some());
+        assert!(status.workers.is_empty());
+
+        let json = serde_json::to_value(&status).unwrap();
+
+        assert_eq!(json["counts"]["waitingChildren"], 0);
+        assert_eq!(
+            json["nextDelayedAt"],
+            status.next_delayed_at.unwrap() as u64
+        );
+
+        queue.resume().unwrap();
+
+        assert!(!queue.is_paused().unwrap());
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn stores_raw_payloads_as_is() {
+        let queue = test_queue("raw");
+
+        let handle = queue
+            .add_raw("forward", br#""already serialized""#, JobOptions::default())
+            .unwrap();
+
+        assert_eq!(
+            handle.get().unwrap().unwrap().data,
+            Value::String("already serialized".to_string())
+        );
+        assert_eq!(fetch(&queue).data, "already serialized");
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn adds_jobs_in_bulk() {
+        let queue = test_queue("bulk");
+
+        for transactional in [false, true] {
+            let handles = queue
+                .add_bulk(
+                    &[
+                        BulkJob::new("reserve", "seat".to_string()),
+                        BulkJob::new("charge", "card".to_string()),
+                    ],
+                    transactional,
+                )
+                .unwrap();
+
+            assert_eq!(handles.len(), 2);
+            assert_eq!(
+                handles[1].as_ref().unwrap().get().unwrap().unwrap().name,
+                "charge"
+            );
+            assert_eq!(fetch(&queue).data, "seat");
+            assert_eq!(fetch(&queue).data, "card");
+        }
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn adds_bulks_once_after_a_script_flush() {
+        // Retrying the pipeline on `NOSCRIPT` would add the first job twice
+        let queue = test_queue("flushed-bulk");
+        let mut con = queue.client.get_connection().unwrap();
+
+        redis::cmd("SCRIPT")
+            .arg("FLUSH")
+            .query::<()>(&mut con)
+            .unwrap();
+
+        let handles = queue
+            .add_bulk(
+                &[BulkJob::new("reserve", 0), BulkJob::new("charge", 1)],
+                false,
+            )
+            .unwrap();
+
+        assert!(handles.iter().all(|handle| handle.is_ok()));
+        assert_eq!(queue.get_waiting_count().unwrap(), 2);
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn adds_transactional_bulks_entirely_or_not_at_all() {
+        let queue = test_queue("atomic-bulk");
+        let with_id = |id: &str| {
+            BulkJob::new("charge", 1).opts(JobOptions {
+                job_id: Some(id.to_string()),
+                ..Default::default()
+            })
+        };
+
+        let err = queue
+            .add_bulk(
+                &[BulkJob::new("reserve", 0), with_id("a"), with_id("a")],
+                true,
+            )
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            err.downcast_ref::<BulkRejected>().unwrap(),
+            &BulkRejected {
+                index: 2,
+                reason: "has a custom id already taken"
+            }
+        );
+        assert_eq!(queue.get_waiting_count().unwrap(), 0);
+        assert_eq!(queue.peek_next_id().unwrap(), 1);
+
+        let handles = queue
+            .add_bulk(&[BulkJob::new("reserve", 0), with_id("a")], true)
+            .unwrap();
+
+        assert_eq!(handles[0].as_ref().unwrap().id(), "1");
+        assert_eq!(handles[1].as_ref().unwrap().id(), "a");
+        assert_eq!(queue.get_waiting_count().unwrap(), 2);
+
+        // Taken by the job added above
+        assert!(queue
+            .add_bulk(&[BulkJob::new("reserve", 0), with_id("a")], true)
+            .is_err());
+        assert_eq!(queue.get_waiting_count().unwrap(), 2);
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn rejects_bulks_with_an_invalid_job() {
+        // Rejected before reaching Redis
+        let queue = test_queue("invalid-bulk");
+        let invalid = BulkJob::new("charge", 1).opts(JobOptions {
+            job_id: Some("42".to_string()),
+            ..Default::default()
+        });
+
+        let err = queue
+            .add_bulk(&[BulkJob::new("reserve", 0), invalid], true)
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            err.downcast_ref::<InvalidJobOptions>().unwrap().option,
+            "jobId"
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_jobs() {
+        // Rejected before reaching Redis
+        let queue = test_queue("sized").size_limit(8);
+        let too_large = |res: Result<JobHandle<String>>| {
+            res.err()
+                .and_then(|err| err.downcast_ref::<JobTooLarge>().cloned())
+        };
+
+        assert_eq!(
+            too_large(queue.add("big", &"too large".to_string(), JobOptions::default())),
+            Some(JobTooLarge {
+                name: "big".to_string(),
+                size: 11,
+                limit: 8,
+            })
+        );
+
+        let opts = JobOptions {
+            size_limit: Some(4),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            too_large(queue.add("small", &"tiny".to_string(), opts))
+                .unwrap()
+                .limit,
+            4
+        );
+    }
+
+    #[test]
+    fn reads_timestamps_of_delayed_jobs() {
+        let timestamp = 1_700_000_000_123u128;
+
+        assert_eq!(delayed_timestamp((timestamp * 0x1000) as f64), timestamp);
+        assert_eq!(
+            delayed_timestamp((timestamp * 0x1000 + 42) as f64),
+            timestamp
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stops_processing_while_paused() {
+        let queue = test_queue("paused");
+        let mut events =
+            QueueEvents::from_client(&queue.get_prefixed_key(""), queue.client.clone()).unwrap();
+        let mut worker = Worker::new(
+            queue.queue_name.clone(),
+            "redis://localhost:6379".to_string(),
+            Prefix::default(),
+            1,
+            |job: Job<u32>| Ok(job.data),
+        );
+        let worker_handle = worker.handle();
+
+        tokio::spawn(async move { worker.run().await });
+
+        queue.pause().unwrap();
+
+        queue.add("job", &1, JobOptions::default()).unwrap();
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        assert_eq!(
+            queue.get_job_counts().unwrap(),
+            JobCounts {
+                paused: 1,
+                ..Default::default()
+            }
+        );
+
+        queue.resume().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut emitted = vec![];
+
+        while !emitted.contains(&"completed".to_string()) && Instant::now() < deadline {
+            emitted.extend(
+                events
+                    .read(Duration::from_millis(500))
+                    .unwrap()
+                    .into_iter()
+                    .map(|event| event.event)
+                    .filter(|event| ["paused", "resumed", "completed"].contains(&event.as_str())),
+            );
+        }
+
+        assert_eq!(emitted, ["paused", "resumed", "completed"]);
+
+        worker_handle.close();
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn processes_jobs_once_a_paused_worker_is_resumed() {
+        let queue = test_queue("paused-worker");
+        let mut worker = Worker::new(
+            queue.queue_name.clone(),
+            "redis://localhost:6379".to_string(),
+            Prefix::default(),
+            1,
+            |job: Job<u32>| Ok(job.data),
+        );
+        let worker_handle = worker.handle();
+
+        worker_handle.pause();
+        tokio::spawn(async move { worker.run().await });
+
+        let job = queue.add("job", &1, JobOptions::default()).unwrap();
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        assert_eq!(
+            queue.get_job_counts().unwrap(),
+            JobCounts {
+                waiting: 1,
+                ..Default::default()
+            }
+        );
+
+        worker_handle.resume();
+
+        let res = tokio::task::spawn_blocking(move || job.wait(Duration::from_secs(2))).await;
+
+        assert_eq!(res.unwrap().unwrap(), 1);
+
+        worker_handle.close();
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn wakes_workers_up_with_keyspace_notifications() {
+        let queue = test_queue("keyspace");
+        let mut client = queue.client.clone();
+        let (_, flags): (String, String) = redis::cmd("CONFIG")
+            .arg("GET")
+            .arg("notify-keyspace-events")
+            .query(&mut client)
+            .unwrap();
+        let set_flags = |client: &mut Client, flags: &str| {
+            redis::cmd("CONFIG")
+                .arg("SET")
+                .arg("notify-keyspace-events")
+                .arg(flags)
+                .query::<()>(client)
+                .unwrap()
+        };
+        let mut worker = Worker::with_options(
+            queue.queue_name.clone(),
+            "redis://localhost:6379".to_string(),
+            WorkerOptions::new().fetch_strategy(FetchStrategy::KeyspaceNotifications),
+            |job: Job<u32>| Ok(job.data),
+        );
+        let worker_handle = worker.handle();
+
+        set_flags(&mut client, "Klz");
+        tokio::spawn(async move { worker.run().await });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // Well before the worker polls, every 5 seconds
+        let job = queue.add("job", &1, JobOptions::default()).unwrap();
+        tokio::task::spawn_blocking(move || job.wait(Duration::from_secs(2)))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Without notifications, the job is found by polling
+        set_flags(&mut client, "");
+        let job = queue.add("job", &2, JobOptions::default()).unwrap();
+        let res = tokio::task::spawn_blocking(move || job.wait(Duration::from_secs(12))).await;
+
+        set_flags(&mut client, &flags);
+        assert_eq!(res.unwrap().unwrap(), 2);
+
+        worker_handle.close();
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn emits_completed_events_of_jobs_removed_immediately() {
+        let queue = test_queue("removed-immediately");
+        let mut events =
+            QueueEvents::from_client(&queue.get_prefixed_key(""), queue.client.clone()).unwrap();
+        let mut worker = Worker::with_options(
+            queue.queue_name.clone(),
+            "redis://localhost:6379".to_string(),
+            WorkerOptions::new().remove_on_complete_immediate(true),
+            |job: Job<u32>| Ok(job.data * 2),
+        );
+        let worker_handle = worker.handle();
+
+        tokio::spawn(async move { worker.run().await });
+
+        let job = queue.add("job", &21, JobOptions::default()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut completed = None;
+
+        while completed.is_none() && Instant::now() < deadline {
+            completed = events
+                .read(Duration::from_millis(500))
+                .unwrap()
+                .into_iter()
+                .find(|event| event.event == "completed");
+        }
+
+        let completed = completed.unwrap();
+
+        assert_eq!(completed.job_id.as_deref(), Some(job.id()));
+        assert_eq!(completed.get("returnvalue"), Some("42"));
+        assert!(job.get().unwrap().is_none());
+        assert_eq!(queue.get_job_counts().unwrap(), JobCounts::default());
+
+        worker_handle.close();
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn waits_for_binary_return_values() {
+        let queue = test_queue("raw-wait");
+        let mut worker = Worker::new(
+            queue.queue_name.clone(),
+            "redis://localhost:6379".to_string(),
+            Prefix::default(),
+            1,
+            |job: Job<u32>| Ok(RawReturnValue(vec![0, 159, job.data as u8])),
+        );
+        let worker_handle = worker.handle();
+
+        tokio::spawn(async move { worker.run().await });
+
+        tokio::task::block_in_place(|| {
+            // Removed on completion, read from its completed event
+            let removed: RawReturnValue = queue
+                .add_and_wait(
+                    "job",
+                    &1,
+                    JobOptions {
+                        remove_on_complete: Some(KeepJobs::count(0)),
+                        ..Default::default()
+                    },
+                    Duration::from_secs(5),
+                )
+                .unwrap();
+            assert_eq!(removed, RawReturnValue(vec![0, 159, 1]));
+
+            // Read from the job hash
+            let kept = queue
+                .add("job", &2, JobOptions::default())
+                .unwrap()
+                .returning::<RawReturnValue>();
+            assert_eq!(
+                kept.wait(Duration::from_secs(5)).unwrap(),
+                RawReturnValue(vec![0, 159, 2])
+            );
+        });
+
+        worker_handle.close();
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn waits_for_jobs_to_start() {
+        let queue = test_queue("started");
+        let job = queue
+            .add("job", &"data".to_string(), JobOptions::default())
+            .unwrap();
+
+        let err = job.wait_active(Duration::from_millis(100)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Timed out waiting for job {} to start", job.id())
+        );
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(200));
+                fetch(&queue);
+            });
+
+            job.wait_active(Duration::from_secs(5)).unwrap();
+        });
+
+        // Already active
+        job.wait_active(Duration::from_millis(100)).unwrap();
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn reserves_ids_from_the_counter() {
+        let queue = test_queue("ids");
+
+        assert_eq!(queue.peek_next_id().unwrap(), 1);
+
+        let reserved = queue.reserve_id().unwrap();
+        assert_eq!(reserved.as_str(), "1");
+
+        let generated = queue
+            .add("job", &"data".to_string(), JobOptions::default())
+            .unwrap();
+        assert_eq!(generated.id(), "2");
+
+        let job = queue
+            .add(
+                "job",
+                &"data".to_string(),
+                JobOptions::default().reserved_id(reserved),
+            )
+            .unwrap();
+        assert_eq!(job.id(), "1");
+        assert_eq!(queue.peek_next_id().unwrap(), 4);
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn cleans_completed_and_failed_jobs() {
+        let queue = test_queue("clean");
+
+        for target in [
+            MoveToFinishedTarget::Completed,
+            MoveToFinishedTarget::Failed,
+        ] {
+            queue
+                .add("job", &"data".to_string(), JobOptions::default())
+                .unwrap();
+            let job = fetch(&queue);
+
+            finish(&queue, &job, target);
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(
+            queue.clean_all(0, 0).unwrap(),
+            CleanedJobs {
+                completed: vec!["1".to_string()],
+                failed: vec!["2".to_string()],
+            }
+        );
+        assert!(queue.clean_all(0, 0).unwrap().is_empty());
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn releases_the_stored_values_of_jobs_removed_on_completion() {
+        let store = Arc::new(MemoryStore::default());
+        let queue = test_queue("released-refs").result_store(store.clone());
+        let prefix = queue.get_prefixed_key("");
+        let mut client = queue.client.clone();
+
+        queue
+            .add(
+                "job",
+                &"data".to_string(),
+                JobOptions {
+                    remove_on_complete: Some(KeepJobs::count(0)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let job = fetch(&queue);
+        let reference = store.put(&job.id, b"\"large\"").unwrap();
+        record_reference(&mut client, &prefix, &job.id, &reference).unwrap();
+
+        finish(&queue, &job, MoveToFinishedTarget::Completed);
+
+        assert_eq!(
+            recorded_reference(&mut client, &prefix, &job.id).unwrap(),
+            None
+        );
+        assert!(store.get(&reference).is_ok());
+
+        // As a worker with the store does on every tick
+        delete_released(Some(store.as_ref()), &mut client, &prefix);
+        assert!(store.get(&reference).is_err());
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn holds_jobs_backing_off_until_their_delay() {
+        let queue = test_queue("backoff");
+        let prefix = queue.get_prefixed_key("");
+
+        let handle = queue
+            .add(
+                "job",
+                &"data".to_string(),
+                JobOptions {
+                    attempts: 3,
+                    backoff: Some(BackoffOptions {
+                        strategy: BackoffStrategy::Exponential,
+                        delay: 1000,
+                        jitter: 0.0,
+                    }),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let job = fetch(&queue);
+        let delay = job.opts.backoff_delay(1);
+
+        assert_eq!(delay, 1000);
+        assert!(matches!(
+            MoveToDelayed::new()
+                .run(&prefix, &mut queue.client.clone(), &job.id, "0", delay)
+                .unwrap(),
+            MoveToDelayedReturn::Ok
+        ));
+        assert!(handle.is_delayed().unwrap());
+
+        let next = || {
+            MoveToActive::new()
+                .run::<String>(
+                    &prefix,
+                    &mut queue.client.clone(),
+                    MoveToActiveArgs {
+                        token: "1".to_string(),
+                        lock_duration: 10_000,
+                        name: None,
+                        limiter: None,
+                        namespace: None,
+                    },
+                )
+                .unwrap()
+        };
+
+        assert!(!matches!(next(), MoveToActiveReturn::Job(_)));
+
+        // Fetching promotes the delayed jobs that are due
+        std::thread::sleep(Duration::from_millis(1_100));
+        assert!(matches!(next(), MoveToActiveReturn::Job(_)));
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn deduplicates_jobs() {
+        let queue = test_queue("dedup");
+        let deduplicated = |ttl| JobOptions {
+            deduplication: Some(DeduplicationOptions {
+                id: "stats".to_string(),
+                ttl,
+            }),
+            ..Default::default()
+        };
+
+        let first = queue.add("job", &0, deduplicated(Some(60_000))).unwrap();
+        let second = queue.add("job", &1, deduplicated(Some(60_000))).unwrap();
+
+        assert!(!first.was_deduplicated());
+        assert!(second.was_deduplicated());
+        assert_eq!(second.id(), first.id());
+
+        let _ = queue.obliterate(true);
+        let queue = test_queue("dedup");
+        // Without a ttl, jobs are deduplicated until the first one finishes
+        let first = queue
+            .add("job", &"a".to_string(), deduplicated(None))
+            .unwrap();
+        assert!(queue
+            .add("job", &"b".to_string(), deduplicated(None))
+            .unwrap()
+            .was_deduplicated());
+
+        finish(&queue, &fetch(&queue), MoveToFinishedTarget::Completed);
+
+        let next = queue
+            .add("job", &"c".to_string(), deduplicated(None))
+            .unwrap();
+        assert!(!next.was_deduplicated());
+        assert_ne!(next.id(), first.id());
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn re_adds_removed_deduplicated_jobs() {
+        let queue = test_queue("dedup-removed");
+
+        for ttl in [None, Some(60_000)] {
+            let deduplicated = JobOptions {
+                deduplication: Some(DeduplicationOptions {
+                    id: "stats".to_string(),
+                    ttl,
+                }),
+                ..Default::default()
+            };
+            let first = queue.add("job", &0, deduplicated.clone()).unwrap();
+
+            first.remove().unwrap();
+
+            let next = queue.add("job", &1, deduplicated).unwrap();
+            assert!(!next.was_deduplicated());
+            assert_ne!(next.id(), first.id());
+
+            next.remove().unwrap();
+        }
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn keeps_the_last_log_lines() {
+        let queue = test_queue("logs");
+        let mut client = queue.client.clone();
+
+        for (keep_logs, kept) in [
+            (None, vec!["a", "b", "c"]),
+            (Some(2), vec!["b", "c"]),
+            (Some(0), vec![]),
+        ] {
+            queue
+                .add(
+                    "job",
+                    &"data".to_string(),
+                    JobOptions {
+                        keep_logs,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let job = fetch(&queue);
+            let mut count = 0;
+
+            for line in ["a", "b", "c"] {
+                count = job.log(&mut client, line).unwrap();
+            }
+
+            assert_eq!(count, kept.len());
+            assert_eq!(
+                queue.get_job_logs(&job.id, 0, -1).unwrap(),
+                (
+                    kept.iter().map(|line| line.to_string()).collect(),
+                    kept.len()
+                )
+            );
+        }
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn clears_idempotency_keys_once_jobs_finish() {
+        let queue = test_queue("idempotent");
+        let prefix = queue.get_prefixed_key("");
+        let job_key = queue.get_prefixed_key("1");
+
+        queue
+            .add("charge", &"card".to_string(), JobOptions::default())
+            .unwrap();
+        let job = fetch(&queue);
+        let recorded = |queue: &Queue| -> bool {
+            queue
+                .client
+                .clone()
+                .hexists(&job_key, "idempotencyKey")
+                .unwrap()
+        };
+
+        RecordIdempotencyKey::new()
+            .run(
+                &prefix,
+                &mut queue.client.clone(),
+                &job.id,
+                "charge:1",
+                b"42",
+            )
+            .unwrap();
+        assert!(recorded(&queue));
+
+        finish(&queue, &job, MoveToFinishedTarget::Completed);
+        assert!(!recorded(&queue));
+
+        // Left behind by an older version completing the job
+        RecordIdempotencyKey::new()
+            .run(
+                &prefix,
+                &mut queue.client.clone(),
+                &job.id,
+                "charge:1",
+                b"42",
+            )
+            .unwrap();
+        queue.retry_job(&job.id, JobState::Completed).unwrap();
+        assert!(!recorded(&queue));
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn keeps_the_events_maxlen_set_by_others() {
+        let queue = test_queue("maxlen");
+        let meta_key = queue.get_prefixed_key("meta");
+
+        set_events_maxlen(&mut queue.client.clone(), &queue.get_prefixed_key(""), 50).unwrap();
+        queue.add("job", &0, JobOptions::default()).unwrap();
+
+        let maxlen: u64 = queue
+            .client
+            .clone()
+            .hget(&meta_key, "opts.maxLenEvents")
+            .unwrap();
+        assert_eq!(maxlen, 50);
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn keeps_the_ids_cleaned_before_an_error() {
+        // Nothing listens there
+        let queue = Queue::new(
+            "unreachable".to_string(),
+            "redis://127.0.0.1:1".to_string(),
+            Prefix::default(),
+        );
+
+        let err = queue.clean_all(0, 0).unwrap_err();
+        let partial = err.downcast_ref::<PartiallyCleaned>().unwrap();
+
+        assert!(partial.cleaned.is_empty());
+        assert!(partial.source.downcast_ref::<redis::RedisError>().is_some());
+    }
+
+    #[test]
+    fn trims_events() {
+        let queue = test_queue("trim");
+
+        for i in 0..5 {
+            queue.add("job", &i, JobOptions::default()).unwrap();
+        }
+
+        let events_key = queue.get_prefixed_key("events");
+        let before: usize = queue.client.clone().xlen(&events_key).unwrap();
+
+        assert_eq!(queue.trim_events(2).unwrap(), before - 2);
+        assert_eq!(
+            queue.client.clone().xlen::<_, usize>(&events_key).unwrap(),
+            2
+        );
+        assert_eq!(queue.trim_events(2).unwrap(), 0);
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn reports_who_holds_active_jobs() {
+        let queue = test_queue("active");
+        let prefix = queue.get_prefixed_key("");
+        let mut info = WorkerInfo::new("worker-id".to_string(), Some("mailer".to_string()));
+
+        worker_registry::heartbeat(&prefix, &mut queue.client.clone(), &mut info, 60_000).unwrap();
+
+        for i in 0..2 {
+            queue.add("job", &i, JobOptions::default()).unwrap();
+        }
+
+        let MoveToActiveReturn::Job(job) = MoveToActive::new()
+            .run::<u32>(
+                &prefix,
+                &mut queue.client.clone(),
+                MoveToActiveArgs {
+                    token: "worker-id:1".to_string(),
+                    lock_duration: 10_000,
+                    name: None,
+                    limiter: None,
+                    namespace: None,
+                },
+            )
+            .unwrap()
+        else {
+            panic!("job was not fetched");
+        };
+        let unlocked = fetch(&queue);
+
+        let jobs = queue.get_active_jobs().unwrap();
+        let locked = jobs.iter().find(|active| active.job_id == job.id).unwrap();
+
+        assert_eq!(locked.token.as_deref(), Some("worker-id:1"));
+        assert_eq!(
+            locked.worker.as_ref().unwrap().name.as_deref(),
+            Some("mailer")
+        );
+        assert!(locked.lock_ttl.unwrap() <= Duration::from_secs(10));
+        assert!(locked.processed_on.is_some());
+
+        let unlocked = jobs
+            .iter()
+            .find(|active| active.job_id == unlocked.id)
+            .unwrap();
+
+        assert_eq!(unlocked.token, None);
+        assert_eq!(unlocked.worker, None);
+        assert_eq!(unlocked.lock_ttl, None);
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn reports_job_positions() {
+        let queue = test_queue("position");
+        let ids: Vec<String> = (0..4)
+            .map(|i| {
+                queue
+                    .add("job", &i, JobOptions::default())
+                    .unwrap()
+                    .id()
+                    .to_string()
+            })
+            .collect();
+
+        queue.change_priority(&ids[1], 1).unwrap();
+
+        let positions: Vec<Option<usize>> = ids
+            .iter()
+            .map(|id| queue.get_job_position(id).unwrap())
+            .collect();
+
+        assert_eq!(positions, vec![Some(0), Some(3), Some(1), Some(2)]);
+
+        fetch(&queue);
+
+        assert_eq!(queue.get_job_position(&ids[0]).unwrap(), None);
+        assert_eq!(queue.get_job_position(&ids[2]).unwrap(), Some(0));
+        assert_eq!(queue.get_job_position(&ids[1]).unwrap(), Some(2));
+        assert_eq!(queue.get_job_position("missing").unwrap(), None);
+
+        let _ = queue.obliterate(true);
+    }
+
+    #[test]
+    fn applies_retention_set_by_the_producer() {
+        let queue = test_queue("retention");
+        let opts = JobOptions {
+            remove_on_complete: Some(KeepJobs::count(5)),
+            ..Default::default()
+        };
+
+        for i in 0..6 {
+            queue.add("job", &i.to_string(), opts.clone()).unwrap();
+        }
+
+        for _ in 0..6 {
+            let job = fetch(&queue);
+
+            finish(&queue, &job, MoveToFinishedTarget::Completed);
+        }
+
+        assert_eq!(queue.get_job_counts_by_name("job").unwrap().completed, 5);
+
+        let _ = queue.obliterate(true);
+    }
+}
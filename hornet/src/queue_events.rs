@@ -0,0 +1,150 @@
+use crate::queue_keys::QueueKeys;
+use anyhow::Result;
+use redis::{
+    streams::{StreamRangeReply, StreamReadOptions, StreamReadReply},
+    Client, Commands, ConnectionLike, FromRedisValue, Value,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Approximate number of entries kept in the `events` stream, unless
+/// configured otherwise. Same default as BullMQ.
+pub const DEFAULT_EVENTS_MAXLEN: u64 = 10000;
+
+/// Stores the `events` stream cap in the queue's meta key, where the Lua
+/// commands read it from when adding events (`XADD ... MAXLEN ~ N`).
+pub(crate) fn set_events_maxlen(
+    client: &mut impl ConnectionLike,
+    prefix: &str,
+    maxlen: u64,
+) -> Result<()> {
+    let _: () = client.hset(
+        QueueKeys::Meta.with_prefix(prefix),
+        "opts.maxLenEvents",
+        maxlen,
+    )?;
+
+    Ok(())
+}
+
+/// An entry of the queue's `events` stream, e.g. `completed` or `failed`.
+#[derive(Debug, Clone)]
+pub struct QueueEvent {
+    pub id: String,
+    pub event: String,
+    pub job_id: Option<String>,
+    pub fields: HashMap<String, String>,
+    /// The `returnvalue` field of `completed` events as stored, e.g. a binary
+    /// `RawReturnValue` that `fields` only holds lossily
+    pub return_value: Option<Vec<u8>>,
+}
+
+impl QueueEvent {
+    pub fn get(&self, field: &str) -> Option<&str> {
+        self.fields.get(field).map(|s| s.as_str())
+    }
+}
+
+/// Reads the queue's `events` stream. Events are read starting from the last
+/// entry present when the reader was created, so nothing emitted afterwards
+/// is missed even if it happens before the first call to `read`.
+pub struct QueueEvents {
+    events_key: String,
+    client: Client,
+    last_id: String,
+}
+
+impl QueueEvents {
+    pub fn new(queue_name: String, redis_url: String) -> Result<Self> {
+        let client = Client::open(redis_url)?;
+
+        Self::from_client(&format!("bull:{}:", queue_name), client)
+    }
+
+    pub(crate) fn from_client(prefix: &str, mut client: Client) -> Result<Self> {
+        let events_key = QueueKeys::Events.with_prefix(prefix);
+        let last: StreamRangeReply = client.xrevrange_count(&events_key, "+", "-", 1)?;
+        let last_id = match last.ids.first() {
+            Some(entry) => entry.id.clone(),
+            None => "0-0".to_string(),
+        };
+
+        Ok(QueueEvents {
+            events_key,
+            client,
+            last_id,
+        })
+    }
+
+    /// Returns the events emitted since the previous read, blocking for at
+    /// most `timeout` if there are none yet.
+    pub fn read(&mut self, timeout: Duration) -> Result<Vec<QueueEvent>> {
+        let opts = StreamReadOptions::default().block(timeout.as_millis().max(1) as usize);
+        let reply: Option<StreamReadReply> =
+            self.client
+                .xread_options(&[&self.events_key], &[&self.last_id], &opts)?;
+
+        let mut events = vec![];
+
+        for key in reply.map(|r| r.keys).unwrap_or_default() {
+            for entry in key.ids {
+                let mut fields = HashMap::new();
+                let mut return_value = None;
+
+                for (field, value) in entry.map.iter() {
+                    if let (Value::BulkString(bytes), "returnvalue") = (value, field.as_str()) {
+                        return_value = Some(bytes.clone());
+                    }
+
+                    // Return values can be binary, see `RawReturnValue`
+                    let value = match value {
+                        Value::BulkString(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+                        value => String::from_redis_value(value)?,
+                    };
+
+                    fields.insert(field.clone(), value);
+                }
+
+                self.last_id = entry.id.clone();
+
+                events.push(QueueEvent {
+                    id: entry.id,
+                    event: fields.remove("event").unwrap_or_default(),
+                    job_id: fields.get("jobId").cloned(),
+                    fields,
+                    return_value,
+                });
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Waits until one of `events` is emitted for `job_id`. Returns `None` if
+    /// `timeout` elapses first.
+    pub fn wait_for(
+        &mut self,
+        job_id: &str,
+        events: &[&str],
+        timeout: Duration,
+    ) -> Result<Option<QueueEvent>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+
+            for event in self.read(remaining)? {
+                if event.job_id.as_deref() == Some(job_id) && events.contains(&event.event.as_str())
+                {
+                    return Ok(Some(event));
+                }
+            }
+        }
+    }
+}
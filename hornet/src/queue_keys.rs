@@ -1,3 +1,5 @@
+use crate::scripts::move_to_finished::MoveToFinishedTarget;
+
 pub enum QueueKeys {
     Wait,
     Active,
@@ -11,6 +13,20 @@ pub enum QueueKeys {
     Pc,
     Marker,
     Metrics,
+    Id,
+    Completed,
+    Failed,
+    /// Hash of the references of the offloaded return values, by job id, see
+    /// `ResultStore`
+    ResultRefs,
+    /// List of the references of the offloaded return values of removed jobs,
+    /// left to delete from the `ResultStore`
+    ReleasedResultRefs,
+    /// Sorted set of the ids of the jobs with a `ttl`, by the timestamp they
+    /// expire at unless picked up
+    Expiring,
+    /// Hash of the job with the given id
+    Job(String),
     Custom(String),
 }
 
@@ -29,11 +45,30 @@ impl QueueKeys {
             QueueKeys::Pc => "pc",
             QueueKeys::Marker => "marker",
             QueueKeys::Metrics => "metrics",
+            QueueKeys::Id => "id",
+            QueueKeys::Completed => "completed",
+            QueueKeys::Failed => "failed",
+            QueueKeys::ResultRefs => "result-refs",
+            QueueKeys::ReleasedResultRefs => "released-result-refs",
+            QueueKeys::Expiring => "expiring",
+            QueueKeys::Job(id) => id,
             QueueKeys::Custom(s) => s,
         }
         .into()
     }
 
+    /// Set `MoveToFinished` moves jobs to.
+    pub fn target(target: &MoveToFinishedTarget) -> Self {
+        match target {
+            MoveToFinishedTarget::Completed => QueueKeys::Completed,
+            MoveToFinishedTarget::Failed => QueueKeys::Failed,
+        }
+    }
+
+    pub fn job(job_id: &str) -> Self {
+        QueueKeys::Job(job_id.to_string())
+    }
+
     pub fn with_prefix(&self, prefix: &str) -> String {
         format!("{}{}", prefix, self.as_str())
     }
@@ -50,3 +85,60 @@ impl std::fmt::Display for QueueKeys {
         write!(f, "{}", self.as_str())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_finished_targets_and_jobs_to_keys() {
+        let prefix = "bull:queue:";
+
+        assert_eq!(
+            QueueKeys::target(&MoveToFinishedTarget::Completed).with_prefix(prefix),
+            "bull:queue:completed"
+        );
+        assert_eq!(
+            QueueKeys::target(&MoveToFinishedTarget::Failed).with_prefix(prefix),
+            "bull:queue:failed"
+        );
+
+        for target in [
+            MoveToFinishedTarget::Completed,
+            MoveToFinishedTarget::Failed,
+        ] {
+            // The Lua script emits the target as the event name and state
+            assert_eq!(QueueKeys::target(&target).as_str(), target.as_str());
+        }
+
+        assert_eq!(QueueKeys::job("42").with_prefix(prefix), "bull:queue:42");
+    }
+
+    #[test]
+    fn matches_bullmq_key_names() {
+        let prefix = "bull:myqueue:";
+        // Names BullMQ's `QueueKeys.getKeys` uses for the same keys
+        let expected = [
+            (QueueKeys::Wait, "bull:myqueue:wait"),
+            (QueueKeys::Active, "bull:myqueue:active"),
+            (QueueKeys::Prioritized, "bull:myqueue:prioritized"),
+            (QueueKeys::Events, "bull:myqueue:events"),
+            (QueueKeys::Stalled, "bull:myqueue:stalled"),
+            (QueueKeys::Limiter, "bull:myqueue:limiter"),
+            (QueueKeys::Delayed, "bull:myqueue:delayed"),
+            (QueueKeys::Paused, "bull:myqueue:paused"),
+            (QueueKeys::Meta, "bull:myqueue:meta"),
+            (QueueKeys::Pc, "bull:myqueue:pc"),
+            (QueueKeys::Marker, "bull:myqueue:marker"),
+            (QueueKeys::Metrics, "bull:myqueue:metrics"),
+            (QueueKeys::Id, "bull:myqueue:id"),
+            (QueueKeys::Completed, "bull:myqueue:completed"),
+            (QueueKeys::Failed, "bull:myqueue:failed"),
+            (QueueKeys::job("1"), "bull:myqueue:1"),
+        ];
+
+        for (key, name) in expected {
+            assert_eq!(key.with_prefix(prefix), name);
+        }
+    }
+}
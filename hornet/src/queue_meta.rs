@@ -0,0 +1,137 @@
+use std::{collections::HashMap, str::FromStr};
+
+const PAUSED: &str = "paused";
+const VERSION: &str = "version";
+const MAX_LEN_EVENTS: &str = "opts.maxLenEvents";
+const CONCURRENCY: &str = "concurrency";
+const MAX: &str = "max";
+const DURATION: &str = "duration";
+
+/// Contents of the queue's `meta` hash.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueueMeta {
+    /// Set by `Queue::pause`, read-only here
+    pub paused: bool,
+    /// Library that created the queue, e.g. `bullmq:5.1.0`
+    pub version: Option<String>,
+    /// Approximate maximum length of the `events` stream
+    pub max_len_events: Option<u64>,
+    /// Queue-level concurrency. BullMQ applies it across all workers, hornet
+    /// workers lower their own concurrency to it
+    pub concurrency: Option<u32>,
+    /// Global rate limit: at most `max` jobs every `duration` milliseconds
+    pub max: Option<u32>,
+    pub duration: Option<u64>,
+    /// Fields unknown to this crate, and known fields whose value can't be
+    /// parsed, written back as they were read unless the field is set above
+    pub other: HashMap<String, String>,
+}
+
+impl QueueMeta {
+    pub(crate) fn from_hash(mut hash: HashMap<String, String>) -> Self {
+        QueueMeta {
+            paused: hash.remove(PAUSED).is_some(),
+            version: hash.remove(VERSION),
+            max_len_events: parse_field(&mut hash, MAX_LEN_EVENTS),
+            concurrency: parse_field(&mut hash, CONCURRENCY),
+            max: parse_field(&mut hash, MAX),
+            duration: parse_field(&mut hash, DURATION),
+            other: hash,
+        }
+    }
+
+    /// Writable fields with their value, `None` meaning the field is removed.
+    /// `paused` is left out as it's only changed along with the wait list.
+    pub(crate) fn writable_fields(&self) -> Vec<(String, Option<String>)> {
+        let mut fields = vec![
+            (VERSION.to_string(), self.version.clone()),
+            (
+                MAX_LEN_EVENTS.to_string(),
+                self.max_len_events.map(|v| v.to_string()),
+            ),
+            (
+                CONCURRENCY.to_string(),
+                self.concurrency.map(|v| v.to_string()),
+            ),
+            (MAX.to_string(), self.max.map(|v| v.to_string())),
+            (DURATION.to_string(), self.duration.map(|v| v.to_string())),
+        ];
+
+        // Unparsable values are kept, unless replaced
+        fields.retain(|(field, value)| value.is_some() || !self.other.contains_key(field));
+
+        for (field, value) in self.other.iter() {
+            if !fields.iter().any(|(known, _)| known == field) {
+                fields.push((field.clone(), Some(value.clone())));
+            }
+        }
+
+        fields
+    }
+}
+
+/// Removes the field from the hash if its value parses, leaving it otherwise
+/// so it ends up in `QueueMeta::other`.
+fn parse_field<T: FromStr>(hash: &mut HashMap<String, String>, field: &str) -> Option<T> {
+    let value = hash.get(field)?.parse().ok()?;
+
+    hash.remove(field);
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_meta_hash() {
+        let hash: HashMap<String, String> = [
+            ("paused", "1"),
+            ("opts.maxLenEvents", "10000"),
+            ("concurrency", "4"),
+            ("library", "custom"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let meta = QueueMeta::from_hash(hash);
+
+        assert!(meta.paused);
+        assert_eq!(meta.max_len_events, Some(10000));
+        assert_eq!(meta.concurrency, Some(4));
+        assert_eq!(meta.version, None);
+
+        let fields = meta.writable_fields();
+
+        assert!(!fields.iter().any(|(field, _)| field == "paused"));
+        assert!(fields.contains(&("library".to_string(), Some("custom".to_string()))));
+        assert!(fields.contains(&("version".to_string(), None)));
+    }
+
+    #[test]
+    fn keeps_unparsable_fields() {
+        let hash: HashMap<String, String> = [("concurrency", "-1"), ("max", "ten")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        let mut meta = QueueMeta::from_hash(hash);
+
+        assert_eq!(meta.concurrency, None);
+        assert_eq!(meta.max, None);
+
+        let fields = meta.writable_fields();
+
+        assert!(fields.contains(&("concurrency".to_string(), Some("-1".to_string()))));
+        assert!(fields.contains(&("max".to_string(), Some("ten".to_string()))));
+        assert_eq!(fields.iter().filter(|(field, _)| field == "max").count(), 1);
+
+        meta.max = Some(10);
+
+        let fields = meta.writable_fields();
+
+        assert!(fields.contains(&("max".to_string(), Some("10".to_string()))));
+        assert_eq!(fields.iter().filter(|(field, _)| field == "max").count(), 1);
+    }
+}
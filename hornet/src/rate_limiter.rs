@@ -0,0 +1,91 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token bucket allowing `max_per_sec` acquisitions per second on average,
+/// with bursts of up to `max_per_sec`. Clones share the same bucket, so a
+/// single limiter spans every processor task of a worker.
+#[derive(Clone)]
+pub(crate) struct LocalRateLimiter {
+    max_per_sec: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl LocalRateLimiter {
+    pub fn new(max_per_sec: u32) -> Self {
+        let max_per_sec = max_per_sec.max(1) as f64;
+
+        LocalRateLimiter {
+            max_per_sec,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: max_per_sec,
+                refilled_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Waits until a token is available and takes it.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().await;
+                self.refill(&mut bucket);
+
+                if bucket.tokens >= 1. {
+                    bucket.tokens -= 1.;
+                    return;
+                }
+
+                Duration::from_secs_f64((1. - bucket.tokens) / self.max_per_sec)
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Gives back a token that ended up not being used.
+    pub async fn release(&self) {
+        let mut bucket = self.bucket.lock().await;
+
+        bucket.tokens = (bucket.tokens + 1.).min(self.max_per_sec);
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+
+        bucket.tokens = (bucket.tokens + elapsed * self.max_per_sec).min(self.max_per_sec);
+        bucket.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn limits_rate_after_burst() {
+        let limiter = LocalRateLimiter::new(20);
+        let started_at = Instant::now();
+
+        for _ in 0..20 {
+            limiter.acquire().await;
+        }
+
+        assert!(started_at.elapsed() < Duration::from_millis(40));
+
+        for _ in 0..4 {
+            limiter.clone().acquire().await;
+        }
+
+        // 4 more tokens at 20 per second
+        assert!(started_at.elapsed() >= Duration::from_millis(190));
+    }
+}
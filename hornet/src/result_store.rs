@@ -0,0 +1,234 @@
+use anyhow::{anyhow, bail, Result};
+use redis::{Commands, ConnectionLike};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::{log, queue_keys::QueueKeys};
+
+/// Storage for return values too large to be kept in the job hash, e.g. S3 or
+/// a disk, so they don't bloat Redis. Workers offload them with
+/// `WorkerOptions::result_store`, storing only a reference in Redis, and
+/// queues read them back with `Queue::result_store`. Workers call it from a
+/// blocking thread, never from their async tasks.
+pub trait ResultStore: Send + Sync {
+    /// Stores the return value of the job, returning the reference to read it
+    /// back with, e.g. an object key.
+    fn put(&self, job_id: &str, value: &[u8]) -> Result<String>;
+
+    /// Reads a return value stored by `put`.
+    fn get(&self, reference: &str) -> Result<Vec<u8>>;
+
+    /// Deletes a return value stored by `put` once its job is removed, however
+    /// it's removed. Called shortly after by a worker with the store, or right
+    /// away by `JobHandle::remove`, `Queue::clean` and `Queue::obliterate` on a
+    /// queue with the store. Does nothing by default, leaving it to the
+    /// store's own expiry.
+    fn delete(&self, reference: &str) -> Result<()> {
+        let _ = reference;
+        Ok(())
+    }
+}
+
+/// Stored in the job hash in place of an offloaded return value, for readers
+/// unaware of the result store. Whether a value is offloaded is only decided
+/// by the queue's `result-refs` hash, so a return value that looks like this
+/// is never mistaken for a reference.
+#[derive(Serialize)]
+struct StoredReference<'a> {
+    #[serde(rename = "hornetResultRef")]
+    reference: &'a str,
+}
+
+fn stored_reference(reference: &str) -> Vec<u8> {
+    serde_json::to_vec(&StoredReference { reference }).unwrap()
+}
+
+/// Result store used by a worker, along with the size in bytes above which
+/// return values are offloaded to it.
+#[derive(Clone)]
+pub(crate) struct ResultStoreOptions {
+    pub store: Arc<dyn ResultStore>,
+    pub threshold: usize,
+}
+
+impl std::fmt::Debug for ResultStoreOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResultStoreOptions")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResultStoreOptions {
+    /// Returns what to store in Redis for the job's encoded return value: the
+    /// value itself, or a placeholder once offloaded if it's too large, along
+    /// with the reference to record with `record_reference`.
+    pub fn offload(&self, job_id: &str, value: Vec<u8>) -> Result<(Vec<u8>, Option<String>)> {
+        if value.len() <= self.threshold {
+            return Ok((value, None));
+        }
+
+        let reference = self
+            .store
+            .put(job_id, &value)
+            .map_err(|err| anyhow!("Failed to offload the return value: {}", err))?;
+
+        Ok((stored_reference(&reference), Some(reference)))
+    }
+}
+
+/// Records that the job's return value was offloaded as `reference`, before
+/// the job is completed.
+pub(crate) fn record_reference(
+    client: &mut impl ConnectionLike,
+    prefix: &str,
+    job_id: &str,
+    reference: &str,
+) -> Result<()> {
+    let _: () = client.hset(QueueKeys::ResultRefs.with_prefix(prefix), job_id, reference)?;
+
+    Ok(())
+}
+
+/// Reference the job's return value was offloaded as, if it was.
+pub(crate) fn recorded_reference(
+    client: &mut impl ConnectionLike,
+    prefix: &str,
+    job_id: &str,
+) -> Result<Option<String>> {
+    Ok(client.hget(QueueKeys::ResultRefs.with_prefix(prefix), job_id)?)
+}
+
+/// Reads back a return value offloaded to `store` as `reference`, returning
+/// any other value as is. A reference recorded by an earlier attempt of a
+/// retried job doesn't match the value anymore, which is then returned as is.
+pub(crate) fn rehydrate(
+    store: Option<&dyn ResultStore>,
+    value: Vec<u8>,
+    reference: Option<String>,
+) -> Result<Vec<u8>> {
+    let Some(reference) = reference.filter(|reference| value == stored_reference(reference)) else {
+        return Ok(value);
+    };
+
+    match store {
+        Some(store) => store.get(&reference),
+        None => bail!(
+            "Return value offloaded as {}, but no result store is set",
+            reference
+        ),
+    }
+}
+
+/// Deletes from `store` the return values of the jobs removed since the last
+/// call, whatever removed them (`JobHandle::remove`, `removeOnComplete`,
+/// retention...): the scripts removing jobs queue their references under
+/// `prefix`. Without a store, the references are left for a worker or queue
+/// that has one. Failures are logged, as the jobs are gone already.
+pub(crate) fn delete_released(
+    store: Option<&dyn ResultStore>,
+    client: &mut impl ConnectionLike,
+    prefix: &str,
+) {
+    let Some(store) = store else {
+        return;
+    };
+
+    let key = QueueKeys::ReleasedResultRefs.with_prefix(prefix);
+    let references = redis::pipe()
+        .atomic()
+        .lrange(&key, 0, -1)
+        .del(&key)
+        .ignore()
+        .query::<(Vec<String>,)>(client);
+
+    match references {
+        Ok((references,)) => delete_all(store, references.into_iter()),
+        Err(err) => log::warning!("Error reading released return values: {:?}", err),
+    }
+}
+
+fn delete_all(store: &dyn ResultStore, references: impl Iterator<Item = String>) {
+    for reference in references {
+        if let Err(err) = store.delete(&reference) {
+            log::warning!("Error deleting return value {}: {:?}", reference, err);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Mutex};
+
+    #[derive(Default)]
+    pub(crate) struct MemoryStore(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl ResultStore for MemoryStore {
+        fn put(&self, job_id: &str, value: &[u8]) -> Result<String> {
+            let reference = format!("results/{}", job_id);
+
+            self.0
+                .lock()
+                .unwrap()
+                .insert(reference.clone(), value.to_vec());
+
+            Ok(reference)
+        }
+
+        fn get(&self, reference: &str) -> Result<Vec<u8>> {
+            self.0
+                .lock()
+                .unwrap()
+                .get(reference)
+                .cloned()
+                .ok_or_else(|| anyhow!("No result {}", reference))
+        }
+
+        fn delete(&self, reference: &str) -> Result<()> {
+            self.0.lock().unwrap().remove(reference);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn offloads_values_above_the_threshold() {
+        let store = Arc::new(MemoryStore::default());
+        let opts = ResultStoreOptions {
+            store: store.clone(),
+            threshold: 8,
+        };
+
+        let small = opts.offload("1", b"\"small\"".to_vec()).unwrap();
+        let (large, reference) = opts.offload("2", b"\"much larger\"".to_vec()).unwrap();
+
+        assert_eq!(small, (b"\"small\"".to_vec(), None));
+        assert_eq!(large, br#"{"hornetResultRef":"results/2"}"#);
+        assert_eq!(reference.as_deref(), Some("results/2"));
+        assert_eq!(
+            rehydrate(Some(store.as_ref()), large.clone(), reference.clone()).unwrap(),
+            b"\"much larger\""
+        );
+        assert!(rehydrate(None, large, reference).is_err());
+
+        delete_all(store.as_ref(), ["results/2".to_string()].into_iter());
+        assert!(store.get("results/2").is_err());
+    }
+
+    #[test]
+    fn leaves_other_values_as_is() {
+        // Not recorded as offloaded, user data looking like a reference
+        let value = br#"{"hornetResultRef":"results/1"}"#.to_vec();
+
+        assert_eq!(rehydrate(None, value.clone(), None).unwrap(), value);
+        assert_eq!(rehydrate(None, vec![0, 159], None).unwrap(), vec![0, 159]);
+
+        // Recorded by an earlier attempt of a retried job
+        let reference = Some("results/1".to_string());
+
+        assert_eq!(
+            rehydrate(None, b"\"small\"".to_vec(), reference).unwrap(),
+            b"\"small\""
+        );
+    }
+}
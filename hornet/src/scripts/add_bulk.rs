@@ -0,0 +1,91 @@
+use anyhow::Result;
+use redis::{FromRedisValue, Value};
+
+use super::{
+    add_standard_job::{encode_job, AddJobReturn},
+    Invocation,
+};
+use crate::{generate_script_struct, job::JobOptions, queue_keys::QueueKeys};
+
+generate_script_struct!(AddBulk, "./src/scripts/commands/addBulk-8.lua");
+
+#[derive(Debug)]
+pub enum AddBulkReturn {
+    /// Every job was added, or deduplicated, in order
+    Added(Vec<AddJobReturn>),
+    /// Nothing was added: the parent of the job at this index is gone
+    MissingParentKey(usize),
+    /// Nothing was added: the custom id of the job at this index is taken
+    DuplicatedJobId(usize),
+}
+
+impl FromRedisValue for AddBulkReturn {
+    fn from_redis_value(v: &Value) -> redis::RedisResult<Self> {
+        match v {
+            Value::Array(items) => match items.as_slice() {
+                [Value::Int(-5), Value::Int(i)] => {
+                    Ok(AddBulkReturn::MissingParentKey(*i as usize - 1))
+                }
+                [Value::Int(-6), Value::Int(i)] => {
+                    Ok(AddBulkReturn::DuplicatedJobId(*i as usize - 1))
+                }
+                _ => Ok(AddBulkReturn::Added(
+                    items
+                        .iter()
+                        .map(AddJobReturn::from_redis_value)
+                        .collect::<redis::RedisResult<_>>()?,
+                )),
+            },
+            _ => Err(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "Unknown return value",
+            ))),
+        }
+    }
+}
+
+/// Job of a bulk, with its data already serialized as JSON.
+pub struct BulkEntry<'a> {
+    pub name: &'a str,
+    pub data: &'a [u8],
+    pub opts: &'a JobOptions,
+}
+
+impl AddBulk {
+    /// Adds the jobs in a single script, checking all of them before adding
+    /// any. `timestamp` decides which jobs are delayed.
+    pub fn prepare(
+        &self,
+        prefix: &str,
+        jobs: &[BulkEntry],
+        timestamp: u64,
+    ) -> Result<Invocation<'_>> {
+        let mut script = self.prepare_invoke();
+
+        for key in [
+            QueueKeys::Wait,
+            QueueKeys::Paused,
+            QueueKeys::Meta,
+            QueueKeys::Id,
+            QueueKeys::Completed,
+            QueueKeys::Events,
+            QueueKeys::Marker,
+            QueueKeys::Delayed,
+        ] {
+            script.key(key.with_prefix(prefix));
+        }
+
+        for job in jobs {
+            let (args, opts) = encode_job(prefix, job.name, job.opts, timestamp)?;
+            let delayed = if job.opts.is_delayed_at(timestamp) {
+                "1"
+            } else {
+                "0"
+            };
+
+            script.arg(args).arg(job.data).arg(opts).arg(delayed);
+        }
+
+        Ok(script)
+    }
+}
@@ -0,0 +1,38 @@
+use anyhow::Result;
+
+use super::{add_standard_job::encode_job, Invocation};
+use crate::{generate_script_struct, job::JobOptions, queue_keys::QueueKeys};
+
+generate_script_struct!(AddDelayedJob, "./src/scripts/commands/addDelayedJob-6.lua");
+
+impl AddDelayedJob {
+    /// Adds a job to the delayed set, from which workers promote it to wait
+    /// once its delay is over.
+    pub fn prepare(
+        &self,
+        prefix: &str,
+        name: &str,
+        data: &[u8],
+        opts: &JobOptions,
+        timestamp: u64,
+    ) -> Result<Invocation<'_>> {
+        let mut script = self.prepare_invoke();
+
+        for key in [
+            QueueKeys::Marker,
+            QueueKeys::Meta,
+            QueueKeys::Id,
+            QueueKeys::Delayed,
+            QueueKeys::Completed,
+            QueueKeys::Events,
+        ] {
+            script.key(key.with_prefix(prefix));
+        }
+
+        let (args, opts) = encode_job(prefix, name, opts, timestamp)?;
+
+        script.arg(args).arg(data).arg(opts);
+
+        Ok(script)
+    }
+}
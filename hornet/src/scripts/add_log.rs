@@ -0,0 +1,50 @@
+use anyhow::Result;
+use redis::{ConnectionLike, FromRedisValue};
+
+use crate::{generate_script_struct, queue_keys::QueueKeys};
+
+generate_script_struct!(AddLog, "./src/scripts/commands/addLog-2.lua");
+
+#[derive(Debug)]
+pub enum AddLogReturn {
+    /// Number of log lines kept for the job
+    Count(usize),
+    MissingKey,
+}
+
+impl FromRedisValue for AddLogReturn {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        match v {
+            redis::Value::Int(-1) => Ok(AddLogReturn::MissingKey),
+            redis::Value::Int(count) if *count >= 0 => Ok(AddLogReturn::Count(*count as usize)),
+            _ => Err(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "Unknown return value",
+            ))),
+        }
+    }
+}
+
+impl AddLog {
+    /// Appends `log` to the job's logs, keeping only the last `keep_logs`
+    /// lines when set, none for 0.
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        job_id: &str,
+        log: &str,
+        keep_logs: Option<u32>,
+    ) -> Result<AddLogReturn> {
+        let res = self
+            .prepare_invoke()
+            .key(QueueKeys::job(job_id).with_prefix(prefix))
+            .key(QueueKeys::Custom(format!("{}:logs", job_id)).with_prefix(prefix))
+            .arg(job_id)
+            .arg(log)
+            .arg(keep_logs.map(|n| n.to_string()).unwrap_or_default())
+            .invoke::<AddLogReturn>(&mut client)?;
+
+        Ok(res)
+    }
+}
@@ -1,6 +1,105 @@
-use crate::generate_script_struct;
+use anyhow::Result;
+use redis::FromRedisValue;
+
+use super::Invocation;
+use crate::{generate_script_struct, job::JobOptions, queue_keys::QueueKeys};
 
 generate_script_struct!(
     AddStandardJob,
-    "./src/scripts/commands/addStandardJob-11.lua"
+    "./src/scripts/commands/addStandardJob-7.lua"
 );
+
+#[derive(Debug)]
+pub enum AddJobReturn {
+    Id(String),
+    /// A job with the same deduplication id was added within its ttl, holds
+    /// that job's id
+    Deduplicated(String),
+    MissingParentKey,
+}
+
+impl FromRedisValue for AddJobReturn {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        match v {
+            redis::Value::Int(-5) => Ok(AddJobReturn::MissingParentKey),
+            redis::Value::BulkString(id) => Ok(AddJobReturn::Id(String::from_utf8(id.to_vec())?)),
+            redis::Value::Array(items) => match items.as_slice() {
+                [redis::Value::BulkString(id)] => {
+                    Ok(AddJobReturn::Deduplicated(String::from_utf8(id.to_vec())?))
+                }
+                _ => Err(redis::RedisError::from((
+                    redis::ErrorKind::TypeError,
+                    "Unknown return value",
+                ))),
+            },
+            _ => Err(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "Unknown return value",
+            ))),
+        }
+    }
+}
+
+impl AddStandardJob {
+    /// Adds a job with its data already serialized as JSON, stored as is.
+    pub fn prepare(
+        &self,
+        prefix: &str,
+        name: &str,
+        data: &[u8],
+        opts: &JobOptions,
+        timestamp: u64,
+    ) -> Result<Invocation<'_>> {
+        let mut script = self.prepare_invoke();
+
+        let keys: Vec<String> = [
+            QueueKeys::Wait,
+            QueueKeys::Paused,
+            QueueKeys::Meta,
+            QueueKeys::Id,
+            QueueKeys::Completed,
+            QueueKeys::Events,
+            QueueKeys::Marker,
+        ]
+        .iter()
+        .map(|s| s.with_prefix(prefix))
+        .collect();
+
+        for key in keys {
+            script.key(key);
+        }
+
+        let (args, opts) = encode_job(prefix, name, opts, timestamp)?;
+
+        script.arg(args).arg(data).arg(opts);
+
+        Ok(script)
+    }
+}
+
+/// Msgpacked arguments and options of the scripts adding jobs. The arguments
+/// are [prefix, custom id, name, timestamp, parentKey, waitChildrenKey,
+/// parentDependenciesKey, parent, repeatJobKey], the options being resolved
+/// against the same timestamp, see `JobOptions::resolved`.
+pub(crate) fn encode_job(
+    prefix: &str,
+    name: &str,
+    opts: &JobOptions,
+    timestamp: u64,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let opts = opts.resolved(timestamp);
+
+    let args = (
+        prefix,
+        opts.job_id.as_deref().unwrap_or(""),
+        name,
+        timestamp,
+        None::<String>,
+        None::<String>,
+        None::<String>,
+        None::<String>,
+        None::<String>,
+    );
+
+    Ok((rmp_serde::to_vec(&args)?, rmp_serde::to_vec_named(&opts)?))
+}
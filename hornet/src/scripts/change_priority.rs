@@ -0,0 +1,69 @@
+use anyhow::Result;
+use redis::{ConnectionLike, FromRedisValue};
+
+use crate::{generate_script_struct, queue_keys::QueueKeys};
+
+generate_script_struct!(
+    ChangePriority,
+    "./src/scripts/commands/changePriority-6.lua"
+);
+
+#[derive(Debug)]
+pub enum ChangePriorityReturn {
+    Ok,
+    MissingKey,
+}
+
+impl FromRedisValue for ChangePriorityReturn {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        match v {
+            redis::Value::Int(0) => Ok(ChangePriorityReturn::Ok),
+            redis::Value::Int(-1) => Ok(ChangePriorityReturn::MissingKey),
+            _ => Err(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "Unknown return value",
+            ))),
+        }
+    }
+}
+
+impl ChangePriority {
+    /// Updates the job's priority. A waiting job is moved between the `wait`
+    /// list and the `prioritized` set as needed; jobs in any other state only
+    /// get the new priority stored for when they are requeued.
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        job_id: &str,
+        priority: u32,
+        lifo: bool,
+    ) -> Result<ChangePriorityReturn> {
+        let mut script = &mut self.prepare_invoke();
+
+        let keys: Vec<String> = [
+            QueueKeys::Wait,
+            QueueKeys::Paused,
+            QueueKeys::Meta,
+            QueueKeys::Prioritized,
+            QueueKeys::Pc,
+            QueueKeys::Marker,
+        ]
+        .iter()
+        .map(|s| s.with_prefix(prefix))
+        .collect();
+
+        for key in keys {
+            script = script.key(key)
+        }
+
+        let res = script
+            .arg(priority)
+            .arg(QueueKeys::job(job_id).with_prefix(prefix))
+            .arg(job_id)
+            .arg(if lifo { "1" } else { "0" })
+            .invoke::<ChangePriorityReturn>(&mut client)?;
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Result;
+use redis::ConnectionLike;
+use std::time::SystemTime;
+
+use crate::{generate_script_struct, job::JobState, queue_keys::QueueKeys};
+
+generate_script_struct!(
+    CleanJobsInSet,
+    "./src/scripts/commands/cleanJobsInSet-2.lua"
+);
+
+impl CleanJobsInSet {
+    /// Removes up to `limit` jobs (0 means unlimited) from `state` that are
+    /// older than `grace` milliseconds, returning the removed ids.
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        state: &JobState,
+        grace: u64,
+        limit: u32,
+    ) -> Result<Vec<String>> {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            .saturating_sub(grace as u128)
+            .to_string();
+
+        let res = self
+            .prepare_invoke()
+            .key(QueueKeys::Custom(state.as_str().to_string()).with_prefix(prefix))
+            .key(QueueKeys::Events.with_prefix(prefix))
+            .arg(prefix)
+            .arg(timestamp)
+            .arg(limit)
+            .arg(state.as_str())
+            .invoke::<Vec<String>>(&mut client)?;
+
+        Ok(res)
+    }
+}
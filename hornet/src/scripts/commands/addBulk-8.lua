@@ -0,0 +1,114 @@
+--[[
+  Adds several jobs to the queue, all or none of them: every job is checked
+  before any is written, so a job that can't be added leaves the queue as it
+  was. Each job is then added as by addStandardJob or addDelayedJob.
+
+    Input:
+      KEYS[1] 'wait',
+      KEYS[2] 'paused'
+      KEYS[3] 'meta'
+      KEYS[4] 'id'
+      KEYS[5] 'completed'
+      KEYS[6] events stream key
+      KEYS[7] marker key
+      KEYS[8] 'delayed'
+
+      ARGV[4 * i - 3] msgpacked arguments array of the i-th job, as in
+                      addStandardJob
+      ARGV[4 * i - 2] Json stringified job data
+      ARGV[4 * i - 1] msgpacked options
+      ARGV[4 * i]     "1" if the job is delayed, "0" otherwise
+
+      Output:
+        {jobId | {jobId}, ...} - OK, the jobs' ids, within a table for the
+                                 ones deduplicated
+        {-5, i}                - Missing parent key of the i-th job
+        {-6, i}                - The i-th job's custom id is taken, by a job
+                                 already added or an earlier one of the bulk
+]]
+local eventsKey = KEYS[6]
+local metaKey = KEYS[3]
+
+local rcall = redis.call
+
+-- Includes
+--- @include "includes/addDelayedJob"
+--- @include "includes/addJobInTargetList"
+--- @include "includes/deduplicateJob"
+--- @include "includes/getOrSetMaxEvents"
+--- @include "includes/getTargetQueueList"
+--- @include "includes/storeJob"
+
+local jobs = {}
+local customIds = {}
+
+for i = 1, #ARGV / 4 do
+    local args = cmsgpack.unpack(ARGV[4 * i - 3])
+
+    if args[5] ~= nil and rcall("EXISTS", args[5]) ~= 1 then
+        return {-5, i}
+    end
+
+    if args[2] ~= "" then
+        if customIds[args[2]] or rcall("EXISTS", args[1] .. args[2]) == 1 then
+            return {-6, i}
+        end
+        customIds[args[2]] = true
+    end
+
+    jobs[i] = {
+        args = args,
+        data = ARGV[4 * i - 2],
+        opts = cmsgpack.unpack(ARGV[4 * i - 1]),
+        delayed = ARGV[4 * i] == "1"
+    }
+end
+
+local maxEvents = getOrSetMaxEvents(metaKey)
+local results = {}
+
+for i, job in ipairs(jobs) do
+    local args = job.args
+    local jobCounter = rcall("INCR", KEYS[4])
+    local jobId = args[2] == "" and jobCounter or args[2]
+    local jobIdKey = args[1] .. jobId
+    local timestamp = args[4]
+    local parentKey = args[5]
+    local parentData
+
+    if parentKey ~= nil then
+        parentData = cjson.encode(args[8])
+    end
+
+    local deduplicatedJobId = deduplicateJob(args[1], job.opts['de'], jobId,
+                                             eventsKey, maxEvents)
+
+    if deduplicatedJobId then
+        results[i] = {deduplicatedJobId}
+    else
+        local delay = storeJob(eventsKey, jobIdKey, jobId, args[3], job.data,
+                               job.opts, timestamp, parentKey, parentData,
+                               args[9])
+
+        if job.delayed then
+            addDelayedJob(jobId, KEYS[8], eventsKey, timestamp, maxEvents,
+                          KEYS[7], delay)
+        else
+            local target, paused = getTargetQueueList(metaKey, KEYS[1], KEYS[2])
+            local pushCmd = job.opts['lifo'] and 'RPUSH' or 'LPUSH'
+
+            addJobInTargetList(target, KEYS[7], pushCmd, paused, jobId)
+
+            rcall("XADD", eventsKey, "MAXLEN", "~", maxEvents, "*", "event",
+                  "waiting", "jobId", jobId)
+        end
+
+        if args[7] ~= nil then
+            rcall("SADD", args[7], jobIdKey)
+        end
+
+        results[i] = jobId .. ""
+    end
+end
+
+return results
@@ -38,8 +38,9 @@
       ARGV[3] msgpacked options
 
       Output:
-        jobId  - OK
-        -5     - Missing parent key
+        jobId   - OK
+        {jobId} - Deduplicated, id of the job added within the deduplication ttl
+        -5      - Missing parent key
 ]]
 local eventsKey = KEYS[6]
 
@@ -59,6 +60,7 @@ local parentData
 
 -- Includes
 --- @include "includes/addJobInTargetList"
+--- @include "includes/deduplicateJob"
 --- @include "includes/getOrSetMaxEvents"
 --- @include "includes/getTargetQueueList"
 --- @include "includes/storeJob"
@@ -95,6 +97,11 @@ else
     end
 end
 
+local deduplicatedJobId = deduplicateJob(args[1], opts['de'], jobId, eventsKey, maxEvents)
+if deduplicatedJobId then
+    return {deduplicatedJobId}
+end
+
 -- Store the job.
 storeJob(eventsKey, jobIdKey, jobId, args[3], ARGV[2], opts, timestamp,
          parentKey, parentData, repeatJobKey)
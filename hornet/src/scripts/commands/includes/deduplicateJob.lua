@@ -0,0 +1,24 @@
+--[[
+  Function to debounce a job: if a job with the same deduplication id was added
+  less than ttl ms ago, the id of that job is returned and nothing is added.
+]]
+
+local function deduplicateJob(prefixKey, deduplicationOpts, jobId, eventsKey, maxEvents)
+  local deduplicationId = deduplicationOpts and deduplicationOpts['id']
+  if deduplicationId then
+    local deduplicationKey = prefixKey .. "dedup:" .. deduplicationId
+    local ttl = deduplicationOpts['ttl']
+    local deduplicationKeyExists
+    if ttl then
+      deduplicationKeyExists = not rcall('SET', deduplicationKey, jobId, 'PX', ttl, 'NX')
+    else
+      deduplicationKeyExists = not rcall('SET', deduplicationKey, jobId, 'NX')
+    end
+    if deduplicationKeyExists then
+      local currentJobId = rcall('GET', deduplicationKey)
+      rcall("XADD", eventsKey, "MAXLEN", "~", maxEvents, "*", "event",
+        "deduplicated", "jobId", currentJobId, "deduplicationId", deduplicationId)
+      return currentJobId
+    end
+  end
+end
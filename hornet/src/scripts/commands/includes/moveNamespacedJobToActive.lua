@@ -0,0 +1,28 @@
+--[[
+  Function to move the next job of a namespace, i.e. whose id starts with
+  "<namespace>/", to active: the oldest one in wait, else the one with the
+  highest priority. Only the first `maxScanned` jobs of each are looked at.
+]]
+
+local function moveNamespacedJobToActive(waitKey, activeKey, priorityKey, namespace, maxScanned)
+  local idPrefix = namespace .. "/"
+
+  -- Jobs are pushed to the head of the wait list, the oldest is its tail
+  local jobIds = rcall("LRANGE", waitKey, -maxScanned, -1)
+  for i = #jobIds, 1, -1 do
+    if string.sub(jobIds[i], 1, #idPrefix) == idPrefix then
+      rcall("LREM", waitKey, -1, jobIds[i])
+      rcall("LPUSH", activeKey, jobIds[i])
+      return jobIds[i]
+    end
+  end
+
+  jobIds = rcall("ZRANGE", priorityKey, 0, maxScanned - 1)
+  for _, jobId in ipairs(jobIds) do
+    if string.sub(jobId, 1, #idPrefix) == idPrefix then
+      rcall("ZREM", priorityKey, jobId)
+      rcall("LPUSH", activeKey, jobId)
+      return jobId
+    end
+  end
+end
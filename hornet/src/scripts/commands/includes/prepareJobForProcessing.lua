@@ -5,12 +5,29 @@
     opts - token - lock token
     opts - lockDuration
     opts - limiter
+    opts - name - worker name
+
+  Jobs whose hash expired (ttl option) while waiting are dropped, returning
+  no job and adding a marker so the worker fetches the next one.
 ]]
 
 local function prepareJobForProcessing(keyPrefix, rateLimiterKey, eventStreamKey,
     jobId, processedOn, maxJobs, opts)
   local jobKey = keyPrefix .. jobId
 
+  -- Promoting a delayed job that expired may have recreated a partial hash
+  if rcall("HEXISTS", jobKey, "name") == 0 then
+    rcall("DEL", jobKey)
+    rcall("LREM", keyPrefix .. "active", 1, jobId)
+    rcall("ZREM", keyPrefix .. "expiring", jobId)
+    rcall("ZADD", keyPrefix .. "marker", 0, "0")
+    return {0, 0, 0, 0}
+  end
+
+  -- Active jobs don't expire, their lock decides whether they stalled
+  rcall("PERSIST", jobKey)
+  rcall("ZREM", keyPrefix .. "expiring", jobId)
+
   -- Check if we need to perform rate limiting.
   if maxJobs then
     local jobCounter = tonumber(rcall("INCR", rateLimiterKey))
@@ -33,5 +50,10 @@ local function prepareJobForProcessing(keyPrefix, rateLimiterKey, eventStreamKey
   rcall("HSET", jobKey, "processedOn", processedOn)
   rcall("HINCRBY", jobKey, "ats", 1)
 
+  if opts['name'] then
+    -- Set "processedBy" field to the worker name
+    rcall("HSET", jobKey, "pb", opts['name'])
+  end
+
   return {rcall("HGETALL", jobKey), jobId, 0, 0} -- get job data
 end
@@ -0,0 +1,14 @@
+--[[
+  Function to release the reference of the offloaded return value of a job
+  being removed, queued for a worker or queue with a result store to delete
+  the value.
+]]
+
+local function releaseResultRef(prefix, jobId)
+  local refsKey = prefix .. "result-refs"
+  local reference = rcall("HGET", refsKey, jobId)
+  if reference then
+    rcall("HDEL", refsKey, jobId)
+    rcall("RPUSH", prefix .. "released-result-refs", reference)
+  end
+end
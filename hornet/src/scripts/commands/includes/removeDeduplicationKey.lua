@@ -0,0 +1,17 @@
+--[[
+  Function to release the deduplication key of a finished or removed job, so
+  the next job with the same deduplication id is added. Keys with a ttl are
+  left to expire, debouncing until then, unless `withTtl` is set, as for jobs
+  removed explicitly.
+]]
+
+local function removeDeduplicationKey(prefixKey, jobKey, jobId, withTtl)
+  local deduplicationId = rcall("HGET", jobKey, "deid")
+  if deduplicationId then
+    local deduplicationKey = prefixKey .. "dedup:" .. deduplicationId
+    if (withTtl or rcall("PTTL", deduplicationKey) == -1) and
+        rcall("GET", deduplicationKey) == jobId then
+      rcall("DEL", deduplicationKey)
+    end
+  end
+end
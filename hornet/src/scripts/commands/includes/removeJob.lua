@@ -3,11 +3,16 @@
 ]]
 
 -- Includes
+--- @include "releaseResultRef"
+--- @include "removeDeduplicationKey"
 --- @include "removeParentDependencyKey"
 
 local function removeJob(jobId, hard, baseKey)
   local jobKey = baseKey .. jobId
   removeParentDependencyKey(jobKey, hard, nil, baseKey)
+  releaseResultRef(baseKey, jobId)
+  removeDeduplicationKey(baseKey, jobKey, jobId, false)
+  rcall("ZREM", baseKey .. "expiring", jobId)
   rcall("DEL", jobKey, jobKey .. ':logs',
     jobKey .. ':dependencies', jobKey .. ':processed')
 end
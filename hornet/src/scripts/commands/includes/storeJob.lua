@@ -20,10 +20,25 @@ local function storeJob(eventsKey, jobIdKey, jobId, name, data, opts, timestamp,
         table.insert(optionalValues, repeatJobKey)
     end
 
+    -- Read back to release the deduplication key once the job finishes
+    if opts['de'] and opts['de']['id'] then
+        table.insert(optionalValues, "deid")
+        table.insert(optionalValues, opts['de']['id'])
+    end
+
     rcall("HMSET", jobIdKey, "name", name, "data", data, "opts", jsonOpts,
           "timestamp", timestamp, "delay", delay, "priority", priority,
           unpack(optionalValues))
 
+    -- Jobs with a ttl expire unless processed before, see prepareJobForProcessing
+    -- and removeExpiredJobs
+    if opts['ttl'] then
+        local keyPrefix = string.sub(jobIdKey, 1, #jobIdKey - #tostring(jobId))
+
+        rcall("PEXPIRE", jobIdKey, opts['ttl'])
+        rcall("ZADD", keyPrefix .. "expiring", timestamp + opts['ttl'], jobId)
+    end
+
     rcall("XADD", eventsKey, "*", "event", "added", "jobId", jobId, "name", name)
 
     return delay, priority
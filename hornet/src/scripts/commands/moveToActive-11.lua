@@ -33,6 +33,7 @@
     opts - token - lock token
     opts - lockDuration
     opts - limiter
+    opts - namespace, only jobs of that namespace are moved to active
 ]]
 local rcall = redis.call
 local waitKey = KEYS[1]
@@ -41,13 +42,15 @@ local eventStreamKey = KEYS[4]
 local rateLimiterKey = KEYS[6]
 local delayedKey = KEYS[7]
 local opts = cmsgpack.unpack(ARGV[3])
-
+-- Jobs looked at per call by namespaced workers
+local maxScanned = 1000
 
 -- Includes
 --- @include "includes/getNextDelayedTimestamp"
 --- @include "includes/getRateLimitTTL"
 --- @include "includes/getTargetQueueList"
 --- @include "includes/moveJobFromPriorityToActive"
+--- @include "includes/moveNamespacedJobToActive"
 --- @include "includes/prepareJobForProcessing"
 --- @include "includes/promoteDelayedJobs"
 
@@ -67,24 +70,28 @@ if expireTime > 0 then return {0, 0, expireTime, 0} end
 -- paused queue
 if paused then return {0, 0, 0, 0} end
 
--- no job ID, try non-blocking move from wait to active
-local jobId = rcall("RPOPLPUSH", waitKey, activeKey)
+local jobId
 
--- Markers in waitlist DEPRECATED in v5: Will be completely removed in v6.
-if jobId and string.sub(jobId, 1, 2) == "0:" then
-    rcall("LREM", activeKey, 1, jobId)
+if opts['namespace'] then
+    jobId = moveNamespacedJobToActive(waitKey, activeKey, KEYS[3], opts['namespace'], maxScanned)
+else
+    -- no job ID, try non-blocking move from wait to active
     jobId = rcall("RPOPLPUSH", waitKey, activeKey)
+
+    -- Markers in waitlist DEPRECATED in v5: Will be completely removed in v6.
+    if jobId and string.sub(jobId, 1, 2) == "0:" then
+        rcall("LREM", activeKey, 1, jobId)
+        jobId = rcall("RPOPLPUSH", waitKey, activeKey)
+    end
+
+    if not jobId then
+        jobId = moveJobFromPriorityToActive(KEYS[3], activeKey, KEYS[10])
+    end
 end
 
 if jobId then
     return prepareJobForProcessing(ARGV[1], rateLimiterKey, eventStreamKey, jobId, ARGV[2],
                                    maxJobs, opts)
-else
-    jobId = moveJobFromPriorityToActive(KEYS[3], activeKey, KEYS[10])
-    if jobId then
-        return prepareJobForProcessing(ARGV[1], rateLimiterKey, eventStreamKey, jobId, ARGV[2],
-                                       maxJobs, opts)
-    end
 end
 
 -- Return the timestamp for the next delayed job if any.
@@ -63,6 +63,8 @@ local rcall = redis.call
 --- @include "includes/moveParentFromWaitingChildrenToFailed"
 --- @include "includes/moveParentToWaitIfNeeded"
 --- @include "includes/promoteDelayedJobs"
+--- @include "includes/releaseResultRef"
+--- @include "includes/removeDeduplicationKey"
 --- @include "includes/removeJobsByMaxAge"
 --- @include "includes/removeJobsByMaxCount"
 --- @include "includes/removeParentDependencyKey"
@@ -159,6 +161,8 @@ if rcall("EXISTS", jobIdKey) == 1 then -- // Make sure job exists
 
     local attemptsMade = rcall("HINCRBY", jobIdKey, "atm", 1)
 
+    removeDeduplicationKey(ARGV[7], jobIdKey, jobId, false)
+
     -- Remove job?
     if maxCount ~= 0 then
         local targetSet = KEYS[11]
@@ -166,6 +170,9 @@ if rcall("EXISTS", jobIdKey) == 1 then -- // Make sure job exists
         rcall("ZADD", targetSet, timestamp, jobId)
         rcall("HMSET", jobIdKey, ARGV[3], ARGV[4], "finishedOn", timestamp)
         -- "returnvalue" / "failedReason" and "finishedOn"
+        -- The recorded idempotency key only short-circuits a re-fetch before
+        -- the job finishes, a retried job must run again
+        rcall("HDEL", jobIdKey, "idempotencyKey", "idempotentReturnValue")
 
         -- Remove old jobs?
         local prefix = ARGV[7]
@@ -178,6 +185,7 @@ if rcall("EXISTS", jobIdKey) == 1 then -- // Make sure job exists
             removeJobsByMaxCount(maxCount, targetSet, prefix)
         end
     else
+        releaseResultRef(ARGV[7], jobId)
         rcall("DEL", jobIdKey, jobIdKey .. ':logs', jobIdKey .. ':processed')
         if parentKey ~= "" then
             removeParentDependencyKey(jobIdKey, false, parentKey)
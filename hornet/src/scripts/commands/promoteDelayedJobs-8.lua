@@ -0,0 +1,30 @@
+--[[
+  Moves the delayed jobs that are due to the wait (or paused) list, or to the
+  prioritized set. Safe to call from several workers at the same time.
+
+  Input:
+    KEYS[1] delayed key
+    KEYS[2] wait key
+    KEYS[3] paused key
+    KEYS[4] meta key
+    KEYS[5] prioritized key
+    KEYS[6] event stream key
+    KEYS[7] priority counter key
+    KEYS[8] marker key
+
+    ARGV[1] key prefix
+    ARGV[2] timestamp
+
+  Events:
+    'waiting'
+]]
+local rcall = redis.call
+
+-- Includes
+--- @include "includes/getTargetQueueList"
+--- @include "includes/promoteDelayedJobs"
+
+local target, paused = getTargetQueueList(KEYS[4], KEYS[2], KEYS[3])
+
+promoteDelayedJobs(KEYS[1], KEYS[8], target, KEYS[5], KEYS[6], ARGV[1],
+                   tonumber(ARGV[2]), KEYS[7], paused)
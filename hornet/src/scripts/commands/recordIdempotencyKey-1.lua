@@ -0,0 +1,20 @@
+--[[
+  Records the idempotency key of a job whose process function succeeded,
+  along with its return value, so that the job is completed without being
+  processed again if it's fetched again before being completed.
+
+  Input:
+    KEYS[1] job key
+
+    ARGV[1] idempotency key
+    ARGV[2] return value
+
+  Output:
+    1 if recorded, 0 if the job does not exist.
+]]
+local rcall = redis.call
+if rcall("EXISTS", KEYS[1]) == 1 then
+  rcall("HSET", KEYS[1], "idempotencyKey", ARGV[1], "idempotentReturnValue", ARGV[2])
+  return 1
+end
+return 0
@@ -0,0 +1,36 @@
+--[[
+  Removes the ids of the jobs whose ttl elapsed before a worker picked them up
+  from the wait and paused lists and the prioritized and delayed sets, their
+  hash being gone already.
+
+  Input:
+    KEYS[1] expiring key
+    KEYS[2] wait key
+    KEYS[3] paused key
+    KEYS[4] prioritized key
+    KEYS[5] delayed key
+
+    ARGV[1] key prefix
+    ARGV[2] timestamp
+
+  Output:
+    number of jobs removed
+]]
+local rcall = redis.call
+local removed = 0
+
+local expired = rcall("ZRANGEBYSCORE", KEYS[1], 0, ARGV[2])
+
+for _, jobId in ipairs(expired) do
+  -- Left for the next call if the server's clock is behind
+  if rcall("EXISTS", ARGV[1] .. jobId) == 0 then
+    rcall("LREM", KEYS[2], 0, jobId)
+    rcall("LREM", KEYS[3], 0, jobId)
+    rcall("ZREM", KEYS[4], jobId)
+    rcall("ZREM", KEYS[5], jobId)
+    rcall("ZREM", KEYS[1], jobId)
+    removed = removed + 1
+  end
+end
+
+return removed
@@ -0,0 +1,50 @@
+--[[
+  Attempts to reprocess a job
+
+  Input:
+    KEYS[1] job key
+    KEYS[2] events stream
+    KEYS[3] job state
+    KEYS[4] wait key
+    KEYS[5] meta
+    KEYS[6] paused key
+    KEYS[7] marker key
+
+    ARGV[1] job.id
+    ARGV[2] (job.opts.lifo ? 'R' : 'L') + 'PUSH'
+    ARGV[3] propVal - failedReason/returnvalue
+    ARGV[4] prev state - failed/completed
+
+  Output:
+     1 means the operation was a success
+    -1 means the job does not exist
+    -3 means the job was not found in the expected set.
+]]
+local rcall = redis.call;
+
+-- Includes
+--- @include "includes/addJobInTargetList"
+--- @include "includes/getOrSetMaxEvents"
+--- @include "includes/getTargetQueueList"
+
+local jobKey = KEYS[1]
+if rcall("EXISTS", jobKey) == 1 then
+  local jobId = ARGV[1]
+  if (rcall("ZREM", KEYS[3], jobId) == 1) then
+    rcall("HDEL", jobKey, "finishedOn", "processedOn", ARGV[3], "idempotencyKey",
+      "idempotentReturnValue")
+
+    local target, isPaused = getTargetQueueList(KEYS[5], KEYS[4], KEYS[6])
+    addJobInTargetList(target, KEYS[7], ARGV[2], isPaused, jobId)
+
+    local maxEvents = getOrSetMaxEvents(KEYS[5])
+    -- Emit waiting event
+    rcall("XADD", KEYS[2], "MAXLEN", "~", maxEvents, "*", "event", "waiting",
+      "jobId", jobId, "prev", ARGV[4]);
+    return 1
+  else
+    return -3
+  end
+else
+  return -1
+end
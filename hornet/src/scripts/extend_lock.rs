@@ -0,0 +1,30 @@
+use anyhow::Result;
+use redis::ConnectionLike;
+
+use crate::{generate_script_struct, queue_keys::QueueKeys};
+
+generate_script_struct!(ExtendLock, "./src/scripts/commands/extendLock-2.lua");
+
+impl ExtendLock {
+    /// Extends the job's lock by `duration` milliseconds and takes it out of
+    /// the stalled set. Returns false if the lock is not owned by `token`.
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        job_id: &str,
+        token: &str,
+        duration: u64,
+    ) -> Result<bool> {
+        let res = self
+            .prepare_invoke()
+            .key(QueueKeys::Custom(format!("{}:lock", job_id)).with_prefix(prefix))
+            .key(QueueKeys::Stalled.with_prefix(prefix))
+            .arg(token)
+            .arg(duration)
+            .arg(job_id)
+            .invoke::<i64>(&mut client)?;
+
+        Ok(res == 1)
+    }
+}
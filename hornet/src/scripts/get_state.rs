@@ -0,0 +1,51 @@
+use anyhow::{bail, Result};
+use redis::ConnectionLike;
+
+use crate::{generate_script_struct, job::JobState, queue_keys::QueueKeys};
+
+generate_script_struct!(GetState, "./src/scripts/commands/getState-8.lua");
+
+impl GetState {
+    /// Returns the state the job is in, `None` if it's in none of them. Jobs
+    /// of a paused queue are reported as `Wait`, like BullMQ does.
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        job_id: &str,
+    ) -> Result<Option<JobState>> {
+        let mut script = &mut self.prepare_invoke();
+
+        let keys: Vec<String> = [
+            QueueKeys::Completed,
+            QueueKeys::Failed,
+            QueueKeys::Delayed,
+            QueueKeys::Active,
+            QueueKeys::Wait,
+            QueueKeys::Paused,
+            QueueKeys::Custom("waiting-children".to_string()),
+            QueueKeys::Prioritized,
+        ]
+        .iter()
+        .map(|s| s.with_prefix(prefix))
+        .collect();
+
+        for key in keys {
+            script = script.key(key)
+        }
+
+        let state = script.arg(job_id).invoke::<String>(&mut client)?;
+
+        Ok(match state.as_str() {
+            "completed" => Some(JobState::Completed),
+            "failed" => Some(JobState::Failed),
+            "delayed" => Some(JobState::Delayed),
+            "active" => Some(JobState::Active),
+            "waiting" => Some(JobState::Wait),
+            "prioritized" => Some(JobState::Prioritized),
+            "waiting-children" => Some(JobState::WaitingChildren),
+            "unknown" => None,
+            _ => bail!("Unknown job state {}", state),
+        })
+    }
+}
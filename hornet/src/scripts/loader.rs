@@ -6,6 +6,10 @@ use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
 use std::{collections::hash_map::DefaultHasher, fs, path::Path};
 
+/// Environment variable pointing to a directory containing the Lua commands
+/// to use instead of the bundled ones.
+pub const SCRIPTS_DIR_ENV: &str = "HORNET_SCRIPTS_DIR";
+
 lazy_static! {
     static ref INCLUDE_REGEX: Regex =
         Regex::new(r#"(?m)^[-]{2,3}[ \t]*@include[ \t]["']+([^; \t\n]*)["'];?[ \t]?"#).unwrap();
@@ -36,16 +40,40 @@ pub enum ScriptLoaderError {
 
 #[derive(Debug, Clone)]
 struct ScriptMetadata {
-    parent_token: Option<String>,
+    /// Tokens of every script on the include path leading to this one
+    ancestors: Vec<String>,
     path: PathBuf,
     token: String,
     content: String,
     includes: HashSet<String>,
 }
 
-impl PartialEq for ScriptMetadata {
-    fn eq(&self, other: &Self) -> bool {
-        self.token == other.token
+/// Where to load the Lua commands from. A custom directory must mirror the
+/// layout of `src/scripts/commands`, including the `includes` folder, so that
+/// BullMQ version specific commands can be swapped in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptSource {
+    Default,
+    Dir(PathBuf),
+}
+
+impl ScriptSource {
+    pub fn from_env() -> Self {
+        match std::env::var_os(SCRIPTS_DIR_ENV) {
+            Some(dir) if !dir.is_empty() => ScriptSource::Dir(PathBuf::from(dir)),
+            _ => ScriptSource::Default,
+        }
+    }
+
+    pub fn resolve(&self, default_path: &str) -> String {
+        match self {
+            ScriptSource::Default => default_path.to_string(),
+            ScriptSource::Dir(dir) => {
+                let file_name = Path::new(default_path).file_name().unwrap();
+
+                dir.join(file_name).to_str().unwrap().to_string()
+            }
+        }
     }
 }
 
@@ -57,35 +85,37 @@ pub fn load_redis_script(path: &str) -> Result<redis::Script, ScriptLoaderError>
 
 fn load_script_content(path: &str) -> Result<String, ScriptLoaderError> {
     let path = Path::new(path);
-    let mut includes: Vec<ScriptMetadata> = Vec::new();
+    // Tokens of every script already inlined, so each is inserted only once
+    let mut included: HashSet<String> = HashSet::new();
 
     let content = match fs::read_to_string(path) {
         core::result::Result::Ok(content) => content,
         core::result::Result::Err(err) => return Err(ScriptLoaderError::IoError(err.to_string())),
     };
 
-    let mut meta = ScriptMetadata {
-        parent_token: None,
+    let meta = ScriptMetadata {
+        ancestors: vec![],
         path: path.to_path_buf(),
-        token: get_path_hash(path),
+        token: get_path_hash(path)?,
         content,
         includes: HashSet::new(),
     };
 
-    resolve_dependencies(&mut meta, &mut includes)?;
-
-    for include in includes.iter().rev() {
-        meta.content = meta
-            .content
-            .replacen(&include.token, include.content.as_str(), 1);
-        meta.content = meta.content.replace(&include.token, "");
-    }
-
-    Ok(meta.content)
+    resolve_dependencies(meta, &mut included)
 }
 
-fn get_path_hash(path: &Path) -> String {
-    format!("@@{}", calculate_hash(path.to_str().unwrap().to_string()))
+/// Hash of the canonical path, so the same file reached through different
+/// relative paths (e.g. `includes/x` and `../includes/x`) is considered equal.
+fn get_path_hash(path: &Path) -> Result<String, ScriptLoaderError> {
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(err) => return Err(ScriptLoaderError::IoError(err.to_string())),
+    };
+
+    Ok(format!(
+        "@@{}",
+        calculate_hash(path.to_str().unwrap().to_string())
+    ))
 }
 
 fn calculate_hash(t: String) -> String {
@@ -94,14 +124,23 @@ fn calculate_hash(t: String) -> String {
     s.finish().to_string()
 }
 
+/// Returns the script content with every include line replaced, in a single
+/// pass, by the resolved content of the included script. A script that was
+/// already inlined earlier is replaced by nothing instead.
 fn resolve_dependencies(
-    script_meta: &mut ScriptMetadata,
-    includes: &mut Vec<ScriptMetadata>,
-) -> Result<(), ScriptLoaderError> {
-    let script_dir = script_meta.path.parent().unwrap();
+    mut script_meta: ScriptMetadata,
+    included: &mut HashSet<String>,
+) -> Result<String, ScriptLoaderError> {
+    let script_dir = script_meta.path.parent().unwrap().to_path_buf();
+    let mut content = String::with_capacity(script_meta.content.len());
+    let mut last_end = 0;
+
+    for cap in INCLUDE_REGEX.captures_iter(&script_meta.content) {
+        let line = cap.get(0).unwrap();
+        let include = cap.get(1).unwrap().as_str();
 
-    for cap in INCLUDE_REGEX.captures_iter(&script_meta.content.clone()) {
-        let (line, [include]) = cap.extract();
+        content.push_str(&script_meta.content[last_end..line.start()]);
+        last_end = line.end();
 
         if script_meta.includes.contains(include) {
             return Err(ScriptLoaderError::DuplicateIncludes(include.to_string()));
@@ -115,17 +154,22 @@ fn resolve_dependencies(
             script_dir.join(format!("{}.lua", include))
         };
 
-        let token = get_path_hash(&include_path);
+        let token = get_path_hash(&include_path)?;
 
-        if let Some(parent_token) = &script_meta.parent_token {
-            if *parent_token == token {
-                return Err(ScriptLoaderError::CircularDependency);
-            }
+        if token == script_meta.token || script_meta.ancestors.contains(&token) {
+            return Err(ScriptLoaderError::CircularDependency);
+        }
+
+        if included.contains(&token) {
+            continue;
         }
 
-        let mut include_meta: ScriptMetadata = ScriptMetadata {
-            parent_token: Some(script_meta.token.clone()),
-            token,
+        let mut ancestors = script_meta.ancestors.clone();
+        ancestors.push(script_meta.token.clone());
+
+        let include_meta: ScriptMetadata = ScriptMetadata {
+            ancestors,
+            token: token.clone(),
             content: match fs::read_to_string(&include_path) {
                 Ok(content) => content,
                 Err(err) => return Err(ScriptLoaderError::IoError(err.to_string())),
@@ -134,22 +178,30 @@ fn resolve_dependencies(
             includes: HashSet::new(),
         };
 
-        resolve_dependencies(&mut include_meta, includes)?;
-
-        script_meta.content = script_meta.content.replace(line, &include_meta.token);
-
-        if !includes.contains(&include_meta) {
-            includes.push(include_meta.clone());
-        }
+        content.push_str(&resolve_dependencies(include_meta, included)?);
+        included.insert(token);
     }
 
-    Ok(())
+    content.push_str(&script_meta.content[last_end..]);
+
+    Ok(content)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn resolves_script_source() {
+        let path = "./src/scripts/commands/moveToActive-11.lua";
+
+        assert_eq!(ScriptSource::Default.resolve(path), path);
+        assert_eq!(
+            ScriptSource::Dir(PathBuf::from("/opt/bullmq")).resolve(path),
+            "/opt/bullmq/moveToActive-11.lua"
+        );
+    }
+
     #[test]
     fn handles_basic_include() {
         let fixture = "./tests/fixtures/scripts/fixture_simple_include.lua";
@@ -206,6 +258,27 @@ mod tests {
         assert_eq!(includes, expected);
     }
 
+    #[test]
+    fn interpolates_helper_shared_by_different_parents_once() {
+        let fixture = "./tests/fixtures/scripts/fixture_shared_include.lua";
+        let script = load_script_content(fixture).unwrap();
+
+        assert!(!script.contains("@@"));
+        assert!(!script.contains("@include"));
+
+        let includes = parse_included_files(script);
+
+        assert_eq!(
+            includes,
+            vec![
+                "strings.lua",
+                "fixture_shared_include_child.lua",
+                "fixture_shared_include_child_2.lua",
+                "fixture_shared_include.lua",
+            ]
+        );
+    }
+
     #[test]
     fn detect_circular_dependencies() {
         let fixture = "./tests/fixtures/scripts/fixture_circular_dependency.lua";
@@ -226,4 +299,127 @@ mod tests {
             ScriptLoaderError::DuplicateIncludes("includes/utils".to_string())
         );
     }
+
+    mod properties {
+        use super::*;
+        use proptest::prelude::*;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static FIXTURE_ID: AtomicUsize = AtomicUsize::new(0);
+
+        /// Directory of the scripts written by `write_graph`, removed on drop.
+        struct Fixture {
+            dir: PathBuf,
+        }
+
+        impl Fixture {
+            fn root(&self) -> String {
+                self.dir.join("f0.lua").to_str().unwrap().to_string()
+            }
+        }
+
+        impl Drop for Fixture {
+            fn drop(&mut self) {
+                let _ = fs::remove_dir_all(&self.dir);
+            }
+        }
+
+        /// Writes `f{i}.lua` for every node, each one including its children
+        /// and ending with a `--- file:` marker. `f0.lua` is the root script.
+        fn write_graph(edges: &[Vec<usize>]) -> Fixture {
+            let dir = std::env::temp_dir().join(format!(
+                "hornet-loader-{}-{}",
+                std::process::id(),
+                FIXTURE_ID.fetch_add(1, Ordering::SeqCst)
+            ));
+
+            fs::create_dir_all(&dir).unwrap();
+            let fixture = Fixture { dir };
+
+            for (i, children) in edges.iter().enumerate() {
+                let mut content = String::new();
+
+                for child in children {
+                    content.push_str(&format!("--- @include \"f{}\"\n", child));
+                }
+
+                content.push_str(&format!("--- file: f{}.lua\n", i));
+                fs::write(fixture.dir.join(format!("f{}.lua", i)), content).unwrap();
+            }
+
+            fixture
+        }
+
+        fn reachable(edges: &[Vec<usize>]) -> HashSet<usize> {
+            let mut seen = HashSet::new();
+            let mut stack = vec![0];
+
+            while let Some(node) = stack.pop() {
+                if seen.insert(node) {
+                    stack.extend(edges[node].iter().copied());
+                }
+            }
+
+            seen
+        }
+
+        /// Random DAGs: a node may only include nodes with a greater index.
+        fn dag() -> impl Strategy<Value = Vec<Vec<usize>>> {
+            (2..8usize).prop_flat_map(|n| {
+                proptest::collection::vec(proptest::collection::vec(any::<bool>(), n), n).prop_map(
+                    move |matrix| {
+                        (0..n)
+                            .map(|i| ((i + 1)..n).filter(|j| matrix[i][*j]).collect())
+                            .collect()
+                    },
+                )
+            })
+        }
+
+        proptest! {
+            #[test]
+            fn resolves_random_dags(edges in dag()) {
+                let fixture = write_graph(&edges);
+                let script = load_script_content(&fixture.root()).unwrap();
+                let included = parse_included_files(script.clone());
+                let reachable = reachable(&edges);
+
+                prop_assert!(!script.contains("@include"));
+
+                for i in 0..edges.len() {
+                    let count = included.iter().filter(|f| **f == format!("f{}.lua", i)).count();
+                    prop_assert_eq!(count, if reachable.contains(&i) { 1 } else { 0 });
+                }
+
+                let position = |i: usize| included.iter().position(|f| *f == format!("f{}.lua", i));
+
+                for parent in reachable.iter() {
+                    for child in edges[*parent].iter() {
+                        prop_assert!(position(*child) < position(*parent));
+                    }
+                }
+            }
+
+            #[test]
+            fn detects_cycles(mut edges in dag(), len in 2..8usize) {
+                // Close a chain 0 -> 1 -> ... -> last -> 0
+                let last = len.min(edges.len()) - 1;
+
+                for (i, children) in edges.iter_mut().enumerate().take(last) {
+                    if !children.contains(&(i + 1)) {
+                        children.push(i + 1);
+                    }
+                }
+
+                edges[last].push(0);
+
+                let fixture = write_graph(&edges);
+
+                prop_assert_eq!(
+                    load_script_content(&fixture.root()).err(),
+                    Some(ScriptLoaderError::CircularDependency)
+                );
+            }
+        }
+    }
 }
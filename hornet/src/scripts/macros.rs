@@ -1,13 +1,29 @@
 #[macro_export]
 macro_rules! generate_script_struct {
     ($struct_name:ident, $string_path:expr) => {
-        use super::loader::load_redis_script;
+        use super::loader::{load_redis_script, ScriptSource};
 
         pub struct $struct_name(pub redis::Script);
 
         impl $struct_name {
             pub fn new() -> Self {
-                let script = load_redis_script($string_path);
+                Self::from_source(&ScriptSource::from_env())
+            }
+
+            /// Same as `new`, returning an error instead of panicking if the
+            /// script can't be loaded.
+            #[allow(dead_code)]
+            pub fn try_new() -> Result<Self, super::loader::ScriptLoaderError> {
+                load_redis_script(&ScriptSource::from_env().resolve($string_path)).map($struct_name)
+            }
+
+            /// Starts an invocation of the script, see `Invocation`.
+            pub fn prepare_invoke(&self) -> super::Invocation<'_> {
+                super::Invocation::new(stringify!($struct_name), &self.0)
+            }
+
+            pub fn from_source(source: &ScriptSource) -> Self {
+                let script = load_redis_script(&source.resolve($string_path));
 
                 match script {
                     Ok(script) => $struct_name(script),
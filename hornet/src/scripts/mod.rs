@@ -1,9 +1,211 @@
+use std::collections::HashSet;
+
 use anyhow::Result;
-use redis::{Client, FromRedisValue, ScriptInvocation, ToRedisArgs};
+use redis::{ConnectionLike, FromRedisValue, RedisError, Script, ScriptInvocation, ToRedisArgs};
+
+use crate::connection::ScriptNotLoaded;
 
+pub(crate) mod add_bulk;
+pub(crate) mod add_delayed_job;
+pub(crate) mod add_log;
 pub(crate) mod add_standard_job;
+pub(crate) mod change_priority;
+pub(crate) mod clean_jobs_in_set;
+pub(crate) mod extend_lock;
+pub(crate) mod get_state;
 pub(crate) mod loader;
 pub(crate) mod macros;
+pub(crate) mod move_stalled_jobs_to_wait;
 pub(crate) mod move_to_active;
+pub(crate) mod move_to_delayed;
 pub(crate) mod move_to_finished;
+pub(crate) mod obliterate;
+pub(crate) mod pause;
+pub(crate) mod promote_delayed_jobs;
+pub(crate) mod record_idempotency_key;
+pub(crate) mod remove_expired_jobs;
+pub(crate) mod remove_job;
+pub(crate) mod reprocess_job;
 pub(crate) mod retry_job;
+pub(crate) mod save_stacktrace;
+
+/// Invocation of one of the scripts above. With the `tracing` feature, it logs
+/// the script's name, KEYS and the size of each ARGV (their content may be
+/// sensitive job data) at debug level, and again at error level if the
+/// invocation fails. With the `metrics` feature, it records how long the
+/// script took.
+pub(crate) struct Invocation<'a> {
+    inner: ScriptInvocation<'a>,
+    name: &'static str,
+    #[cfg(feature = "tracing")]
+    keys: Vec<String>,
+    #[cfg(feature = "tracing")]
+    arg_sizes: Vec<usize>,
+}
+
+impl<'a> Invocation<'a> {
+    pub fn new(name: &'static str, script: &'a Script) -> Self {
+        Invocation {
+            inner: script.prepare_invoke(),
+            name,
+            #[cfg(feature = "tracing")]
+            keys: vec![],
+            #[cfg(feature = "tracing")]
+            arg_sizes: vec![],
+        }
+    }
+
+    pub fn key<T: ToRedisArgs>(&mut self, key: T) -> &mut Self {
+        #[cfg(feature = "tracing")]
+        self.keys.extend(
+            key.to_redis_args()
+                .iter()
+                .map(|key| String::from_utf8_lossy(key).into_owned()),
+        );
+
+        self.inner.key(key);
+        self
+    }
+
+    pub fn arg<T: ToRedisArgs>(&mut self, arg: T) -> &mut Self {
+        #[cfg(feature = "tracing")]
+        self.arg_sizes
+            .extend(arg.to_redis_args().iter().map(|arg| arg.len()));
+
+        self.inner.arg(arg);
+        self
+    }
+
+    /// Runs the script with `EVALSHA`. If Redis lost it, e.g. after a restart
+    /// or a `SCRIPT FLUSH`, the script is loaded and run once more, and a
+    /// `ScriptNotLoaded` error is returned if it's still missing.
+    pub fn invoke<T: FromRedisValue>(&self, con: &mut dyn ConnectionLike) -> Result<T> {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            script = self.name,
+            keys = ?self.keys,
+            argv_bytes = ?self.arg_sizes,
+            "Invoking script"
+        );
+
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+
+        let res = self.inner.invoke(con);
+
+        #[cfg(feature = "metrics")]
+        crate::metrics::script_invoked(self.name, started.elapsed());
+
+        #[cfg(feature = "tracing")]
+        if let Err(err) = &res {
+            tracing::error!(
+                script = self.name,
+                keys = ?self.keys,
+                argv_bytes = ?self.arg_sizes,
+                error = %err,
+                "Script invocation failed"
+            );
+        }
+
+        res.map_err(|err| self.not_loaded_error(err))
+    }
+
+    /// Same as `invoke`, for invocations sent in a pipeline, which unlike
+    /// `invoke` doesn't load the script when it's missing. `query` sends the
+    /// pipeline.
+    pub fn retry_unloaded<T>(
+        &self,
+        con: &mut dyn ConnectionLike,
+        query: impl FnMut(&mut dyn ConnectionLike) -> redis::RedisResult<T>,
+    ) -> Result<T> {
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+
+        let res = self.query_loaded(con, query);
+
+        #[cfg(feature = "metrics")]
+        crate::metrics::script_invoked(self.name, started.elapsed());
+
+        res
+    }
+
+    fn query_loaded<T>(
+        &self,
+        con: &mut dyn ConnectionLike,
+        mut query: impl FnMut(&mut dyn ConnectionLike) -> redis::RedisResult<T>,
+    ) -> Result<T> {
+        match query(con) {
+            Err(err) if err.kind() == redis::ErrorKind::NoScriptError => {
+                self.inner.load(con)?;
+
+                query(con).map_err(|err| self.not_loaded_error(err))
+            }
+            res => Ok(res?),
+        }
+    }
+
+    fn not_loaded_error(&self, err: RedisError) -> anyhow::Error {
+        if err.kind() == redis::ErrorKind::NoScriptError {
+            ScriptNotLoaded { script: self.name }.into()
+        } else {
+            err.into()
+        }
+    }
+
+    /// Loads the script, e.g. before sending it in a pipeline that must not
+    /// be retried after a `NOSCRIPT` error, as its other commands would have
+    /// run already.
+    pub fn load(&self, con: &mut dyn ConnectionLike) -> Result<()> {
+        self.inner.load(con)?;
+        Ok(())
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The underlying invocation, e.g. to add it to a pipeline.
+    pub fn inner(&self) -> &ScriptInvocation<'a> {
+        &self.inner
+    }
+}
+
+/// Loads each distinct script of `invocations` once, before sending them in a
+/// pipeline: retrying it after a `NOSCRIPT` error would run the commands that
+/// preceded the failing one twice.
+pub fn load_scripts(invocations: &[Invocation], con: &mut dyn ConnectionLike) -> Result<()> {
+    let mut loaded = HashSet::new();
+
+    for invocation in invocations {
+        if loaded.insert(invocation.name()) {
+            invocation.load(con)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn types_missing_script_errors() {
+        let script = Script::new("return 1");
+        let invocation = Invocation::new("Test", &script);
+
+        let err = invocation.not_loaded_error(RedisError::from((
+            redis::ErrorKind::NoScriptError,
+            "NOSCRIPT",
+        )));
+
+        assert_eq!(
+            err.downcast_ref::<ScriptNotLoaded>(),
+            Some(&ScriptNotLoaded { script: "Test" })
+        );
+
+        let err = invocation.not_loaded_error(RedisError::from((redis::ErrorKind::IoError, "io")));
+
+        assert!(err.downcast_ref::<ScriptNotLoaded>().is_none());
+    }
+}
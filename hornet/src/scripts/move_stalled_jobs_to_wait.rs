@@ -0,0 +1,67 @@
+use anyhow::Result;
+use redis::ConnectionLike;
+use std::time::SystemTime;
+
+use crate::{generate_script_struct, queue_keys::QueueKeys};
+
+generate_script_struct!(
+    MoveStalledJobsToWait,
+    "./src/scripts/commands/moveStalledJobsToWait-9.lua"
+);
+
+#[derive(Debug, Default)]
+pub struct MoveStalledJobsToWaitReturn {
+    /// Jobs stalled more than the allowed number of times, moved to failed
+    pub failed: Vec<String>,
+    /// Jobs moved back to wait
+    pub stalled: Vec<String>,
+}
+
+impl MoveStalledJobsToWait {
+    /// Moves active jobs whose lock expired since the previous check back to
+    /// wait, or to failed once they stalled more than `max_stalled_count`
+    /// times, then marks the current active jobs as potentially stalled. Only
+    /// one check runs every `max_check_time` milliseconds across all workers.
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        max_stalled_count: u32,
+        max_check_time: u64,
+    ) -> Result<MoveStalledJobsToWaitReturn> {
+        let mut script = &mut self.prepare_invoke();
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let keys: Vec<String> = [
+            QueueKeys::Stalled,
+            QueueKeys::Wait,
+            QueueKeys::Active,
+            QueueKeys::Failed,
+            QueueKeys::Custom("stalled-check".to_string()),
+            QueueKeys::Meta,
+            QueueKeys::Paused,
+            QueueKeys::Marker,
+            QueueKeys::Events,
+        ]
+        .iter()
+        .map(|s| s.with_prefix(prefix))
+        .collect();
+
+        for key in keys {
+            script = script.key(key)
+        }
+
+        let (failed, stalled) = script
+            .arg(max_stalled_count)
+            .arg(prefix)
+            .arg(timestamp)
+            .arg(max_check_time)
+            .invoke::<(Vec<String>, Vec<String>)>(&mut client)?;
+
+        Ok(MoveStalledJobsToWaitReturn { failed, stalled })
+    }
+}
@@ -2,12 +2,13 @@ use std::time::SystemTime;
 
 use crate::{
     generate_script_struct,
-    job::{self, Job, JobBuilder},
+    job::{Job, JobBuilder, JobOptions},
     queue_keys::QueueKeys,
+    worker::RateLimiterOptions,
 };
 
-use anyhow::Result;
-use redis::{FromRedisValue, ToRedisArgs};
+use anyhow::{anyhow, Result};
+use redis::{ConnectionLike, FromRedisValue, ToRedisArgs};
 
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
@@ -17,10 +18,10 @@ impl MoveToActive {
     pub fn run<JobData: DeserializeOwned>(
         &self,
         prefix: &str,
-        mut client: &mut redis::Client,
+        mut client: &mut impl ConnectionLike,
         opts: MoveToActiveArgs,
     ) -> Result<MoveToActiveReturn<JobData>> {
-        let mut script = &mut self.0.prepare_invoke();
+        let mut script = &mut self.prepare_invoke();
 
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -49,21 +50,35 @@ impl MoveToActive {
             script = script.key(key)
         }
 
-        let res = script
+        let mut res = script
             .arg(prefix)
             .arg(timestamp)
             .arg(opts)
             .invoke::<MoveToActiveReturn<JobData>>(&mut client)?;
 
+        if let MoveToActiveReturn::Job(job) = &mut res {
+            job.prefix = prefix.to_string();
+        }
+
         Ok(res)
     }
 }
 
+/// Options read by the Lua script from `ARGV[3]`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MoveToActiveArgs {
+    /// Lock token, "0" to not lock the job
     pub token: String,
     #[serde(rename = "lockDuration")]
     pub lock_duration: u32,
+    /// Worker name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limiter: Option<RateLimiterOptions>,
+    /// Only moves the jobs of this namespace, see `WorkerOptions::namespace`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
 }
 
 impl ToRedisArgs for MoveToActiveArgs {
@@ -79,8 +94,17 @@ impl ToRedisArgs for MoveToActiveArgs {
 
 #[derive(Debug)]
 pub enum MoveToActiveReturn<JobData> {
-    Job(Job<JobData>),
+    Job(Box<Job<JobData>>),
+    /// The queue's rate limit was reached, holds the ms until it resets
+    RateLimited(u64),
     None,
+    /// A job was moved to active, but its data could not be deserialized as
+    /// `JobData`, or another of its fields could not be decoded
+    Undecodable {
+        job_id: String,
+        opts: Box<JobOptions>,
+        reason: String,
+    },
 }
 
 impl<JobData: DeserializeOwned> FromRedisValue for MoveToActiveReturn<JobData> {
@@ -88,74 +112,30 @@ impl<JobData: DeserializeOwned> FromRedisValue for MoveToActiveReturn<JobData> {
         use redis::Value;
 
         match *v {
-            Value::Bulk(ref items) => match items.as_slice() {
+            Value::Array(ref items) => match items.as_slice() {
                 [Value::Int(0), Value::Int(0), Value::Int(0), Value::Int(0)] => {
                     return Ok(MoveToActiveReturn::None)
                 }
-                [Value::Bulk(raw_job), Value::Data(job_id), Value::Int(_), Value::Int(_)] => {
-                    let mut job_builder: JobBuilder<JobData> = JobBuilder::new();
-                    let slices = raw_job.chunks(2).collect::<Vec<_>>();
-
-                    job_builder = job_builder.id(String::from_utf8(job_id.to_vec()).unwrap());
-
-                    for slice in slices {
-                        match slice {
-                            [Value::Data(key), Value::Data(value)] => {
-                                let key = String::from_utf8(key.to_vec()).unwrap();
-
-                                job_builder =
-                                    match key.as_str() {
-                                        "name" => job_builder
-                                            .name(String::from_utf8(value.to_vec()).unwrap()),
-                                        "data" => {
-                                            job_builder.data(serde_json::from_slice(value).unwrap())
-                                        }
-                                        "opts" => job_builder
-                                            .opts(String::from_utf8(value.to_vec()).unwrap()),
-                                        "timestamp" => job_builder.timestamp(
-                                            String::from_utf8(value.to_vec())
-                                                .unwrap()
-                                                .parse::<u128>()
-                                                .unwrap(),
-                                        ),
-                                        "delay" => job_builder.delay(
-                                            String::from_utf8(value.to_vec())
-                                                .unwrap()
-                                                .parse::<u128>()
-                                                .unwrap(),
-                                        ),
-                                        "priority" => job_builder.priority(
-                                            String::from_utf8(value.to_vec())
-                                                .unwrap()
-                                                .parse::<u32>()
-                                                .unwrap(),
-                                        ),
-                                        "processedOn" => job_builder.processed_on(
-                                            String::from_utf8(value.to_vec())
-                                                .unwrap()
-                                                .parse::<u128>()
-                                                .unwrap(),
-                                        ),
-                                        "ats" => job_builder.attempts_started(
-                                            String::from_utf8(value.to_vec())
-                                                .unwrap()
-                                                .parse::<u32>()
-                                                .unwrap(),
-                                        ),
-                                        "atm" => job_builder.attempts_made(
-                                            String::from_utf8(value.to_vec())
-                                                .unwrap()
-                                                .parse::<u32>()
-                                                .unwrap(),
-                                        ),
-                                        _ => job_builder,
-                                    };
-                            }
-                            _ => {}
-                        }
-                    }
+                [Value::Int(0), Value::Int(0), Value::Int(ttl), Value::Int(0)] if *ttl > 0 => {
+                    Ok(MoveToActiveReturn::RateLimited(*ttl as u64))
+                }
+                // No job, but a delayed one is due at the given timestamp
+                [Value::Int(0), Value::Int(0), Value::Int(0), Value::Int(_)] => {
+                    Ok(MoveToActiveReturn::None)
+                }
+                [Value::Array(raw_job), Value::BulkString(job_id), Value::Int(_), Value::Int(_)] => {
+                    let job_id = String::from_utf8(job_id.to_vec())?;
 
-                    Ok(MoveToActiveReturn::Job(job_builder.build()))
+                    // The job is active by now, so it's failed rather than
+                    // left there if any of its fields is invalid
+                    match decode_job(&job_id, raw_job) {
+                        Ok(job) => Ok(MoveToActiveReturn::Job(Box::new(job))),
+                        Err(err) => Ok(MoveToActiveReturn::Undecodable {
+                            opts: Box::new(decode_opts(raw_job)),
+                            job_id,
+                            reason: err.to_string(),
+                        }),
+                    }
                 }
                 _ => {
                     return Err(redis::RedisError::from((
@@ -172,14 +152,61 @@ impl<JobData: DeserializeOwned> FromRedisValue for MoveToActiveReturn<JobData> {
     }
 }
 
+/// Builds the job from the fields of its hash, as returned by the script.
+fn decode_job<JobData: DeserializeOwned>(
+    job_id: &str,
+    raw_job: &[redis::Value],
+) -> Result<Job<JobData>> {
+    let mut job_builder = JobBuilder::new().id(job_id.to_string());
+
+    for slice in raw_job.chunks(2) {
+        if let [redis::Value::BulkString(key), redis::Value::BulkString(value)] = slice {
+            let key = String::from_utf8_lossy(key);
+
+            job_builder = job_builder
+                .field(&key, value)
+                .map_err(|err| match key.as_ref() {
+                    // Already names the field
+                    "data" => err,
+                    _ => anyhow!("Invalid `{}` field: {}", key, err),
+                })?;
+        }
+    }
+
+    job_builder.try_build()
+}
+
+/// Options of a job that could not be decoded, the defaults if they can't
+/// either.
+fn decode_opts(raw_job: &[redis::Value]) -> JobOptions {
+    raw_job
+        .chunks(2)
+        .find_map(|slice| match slice {
+            [redis::Value::BulkString(key), redis::Value::BulkString(value)]
+                if key.as_slice() == b"opts" =>
+            {
+                serde_json::from_slice(value).ok()
+            }
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::SystemTime;
 
-    use crate::queue_keys::QueueKeys;
+    use crate::{job, queue_keys::QueueKeys};
 
     use super::*;
 
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
     #[test]
     fn loads() {
         let script = MoveToActive::new();
@@ -220,6 +247,9 @@ mod tests {
             .arg(MoveToActiveArgs {
                 token: "test".to_string(),
                 lock_duration: 10_000,
+                name: None,
+                limiter: None,
+                namespace: None,
             })
             .invoke(&mut redis);
 
@@ -231,4 +261,440 @@ mod tests {
 
         dbg!(res);
     }
+
+    /// Adds a job per `(name, priority)` to a fresh queue, prioritizing it
+    /// through `ChangePriority` as the producer only adds standard jobs, then
+    /// returns the names of the jobs in the order workers fetch them.
+    fn processing_order(jobs: &[(&str, u32)], reprioritize: &[(&str, u32)]) -> Vec<String> {
+        use super::super::{add_standard_job::*, change_priority::ChangePriority};
+
+        let mut client = redis::Client::open("redis://localhost:6379").unwrap();
+        let prefix = format!("bull:ordering-{}:", uuid::Uuid::new_v4());
+        let mut ids = std::collections::HashMap::new();
+
+        for (name, priority) in jobs {
+            let AddJobReturn::Id(id) = AddStandardJob::new()
+                .prepare(&prefix, name, b"null", &job::JobOptions::default(), now())
+                .unwrap()
+                .invoke(&mut client)
+                .unwrap()
+            else {
+                panic!("job {} was not added", name);
+            };
+
+            if *priority > 0 {
+                ChangePriority::new()
+                    .run(&prefix, &mut client, &id, *priority, false)
+                    .unwrap();
+            }
+
+            ids.insert(*name, id);
+        }
+
+        for (name, priority) in reprioritize {
+            ChangePriority::new()
+                .run(&prefix, &mut client, &ids[name], *priority, false)
+                .unwrap();
+        }
+
+        let mut order = vec![];
+
+        while let MoveToActiveReturn::Job(job) = MoveToActive::new()
+            .run::<()>(
+                &prefix,
+                &mut client,
+                MoveToActiveArgs {
+                    token: "0".to_string(),
+                    lock_duration: 10_000,
+                    name: None,
+                    limiter: None,
+                    namespace: None,
+                },
+            )
+            .unwrap()
+        {
+            order.push(job.name);
+        }
+
+        let _ = crate::scripts::obliterate::Obliterate::new().run(&prefix, &mut client, 1000, true);
+
+        order
+    }
+
+    #[test]
+    fn fetches_only_the_jobs_of_its_namespace() {
+        use super::super::{add_standard_job::*, change_priority::ChangePriority};
+
+        let mut client = redis::Client::open("redis://localhost:6379").unwrap();
+        let prefix = format!("bull:namespaces-{}:", uuid::Uuid::new_v4());
+
+        for (name, namespace, priority) in [
+            ("a", Some("acme"), 0),
+            ("b", Some("globex"), 0),
+            ("c", None, 0),
+            ("d", Some("acme"), 1),
+            ("e", Some("acme"), 0),
+        ] {
+            let opts = job::JobOptions {
+                namespace: namespace.map(str::to_string),
+                ..Default::default()
+            };
+
+            let AddJobReturn::Id(id) = AddStandardJob::new()
+                .prepare(&prefix, name, b"null", &opts, now())
+                .unwrap()
+                .invoke(&mut client)
+                .unwrap()
+            else {
+                panic!("job {} was not added", name);
+            };
+
+            if priority > 0 {
+                ChangePriority::new()
+                    .run(&prefix, &mut client, &id, priority, false)
+                    .unwrap();
+            }
+        }
+
+        let mut fetch = |namespace: Option<&str>| {
+            let mut names = vec![];
+
+            while let MoveToActiveReturn::Job(job) = MoveToActive::new()
+                .run::<()>(
+                    &prefix,
+                    &mut client,
+                    MoveToActiveArgs {
+                        token: "0".to_string(),
+                        lock_duration: 10_000,
+                        name: None,
+                        limiter: None,
+                        namespace: namespace.map(str::to_string),
+                    },
+                )
+                .unwrap()
+            {
+                assert!(namespace.is_none_or(|namespace| job.id.starts_with(namespace)));
+                names.push(job.name);
+            }
+
+            names
+        };
+
+        assert_eq!(fetch(Some("acme")), ["a", "e", "d"]);
+        assert_eq!(fetch(Some("initech")), Vec::<String>::new());
+        assert_eq!(fetch(None), ["b", "c"]);
+
+        let _ = crate::scripts::obliterate::Obliterate::new().run(&prefix, &mut client, 1000, true);
+    }
+
+    #[test]
+    fn processes_standard_jobs_first_then_by_priority_in_fifo_order() {
+        let order = processing_order(
+            &[
+                ("a", 0),
+                ("b", 2),
+                ("c", 0),
+                ("d", 1),
+                ("e", 2),
+                ("f", 1),
+                ("g", 0),
+            ],
+            &[],
+        );
+
+        assert_eq!(order, ["a", "c", "g", "d", "f", "b", "e"]);
+    }
+
+    #[test]
+    fn reprioritized_jobs_go_after_jobs_of_the_same_priority() {
+        // Re-adding a prioritized job takes a new `pc` counter value, and
+        // moving a job back to priority 0 pushes it behind the wait list
+        let order = processing_order(
+            &[("a", 1), ("b", 1), ("c", 1), ("d", 0), ("e", 0)],
+            &[("a", 1), ("d", 0)],
+        );
+
+        assert_eq!(order, ["e", "d", "b", "c", "a"]);
+    }
+
+    fn bulk(s: &str) -> redis::Value {
+        redis::Value::BulkString(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn decodes_job_of_bullmq_flow() {
+        // As written by BullMQ's FlowProducer, which uses the same script as
+        // `addBulk`
+        let hash = [
+            ("name", r#"child"#),
+            ("data", r#"{"idx":1}"#),
+            ("opts", r#"{"attempts":1,"kl":10,"fpof":true}"#),
+            ("parentKey", "bull:parent-queue:7"),
+            ("parent", r#"{"id":"7","queueKey":"bull:parent-queue"}"#),
+            ("timestamp", "1700000000000"),
+            ("delay", "0"),
+            ("priority", "0"),
+            ("processedOn", "1700000000100"),
+            ("ats", "1"),
+        ];
+
+        let raw = redis::Value::Array(vec![
+            redis::Value::Array(
+                hash.iter()
+                    .flat_map(|(field, value)| [bulk(field), bulk(value)])
+                    .collect(),
+            ),
+            bulk("3"),
+            redis::Value::Int(0),
+            redis::Value::Int(0),
+        ]);
+
+        let MoveToActiveReturn::Job(job) =
+            MoveToActiveReturn::<serde_json::Value>::from_redis_value(&raw).unwrap()
+        else {
+            panic!("expected a job");
+        };
+
+        assert_eq!(job.id, "3");
+        assert_eq!(job.data, serde_json::json!({ "idx": 1 }));
+        assert_eq!(job.opts.keep_logs, Some(10));
+        assert_eq!(
+            job.parent,
+            Some(job::ParentRef {
+                id: "7".to_string(),
+                queue_key: "bull:parent-queue".to_string(),
+            })
+        );
+        assert_eq!(job.parent.unwrap().key(), "bull:parent-queue:7");
+    }
+
+    #[test]
+    fn reports_undecodable_job_data() {
+        let raw = redis::Value::Array(vec![
+            redis::Value::Array(vec![
+                bulk("name"),
+                bulk("mixed"),
+                bulk("data"),
+                bulk(r#"{"idx":"one"}"#),
+                bulk("opts"),
+                bulk(r#"{"attempts":3,"removeOnFail":true}"#),
+            ]),
+            bulk("4"),
+            redis::Value::Int(0),
+            redis::Value::Int(0),
+        ]);
+
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Data {
+            idx: u32,
+        }
+
+        let MoveToActiveReturn::Undecodable {
+            job_id,
+            opts,
+            reason,
+        } = MoveToActiveReturn::<Data>::from_redis_value(&raw).unwrap()
+        else {
+            panic!("expected an undecodable job");
+        };
+
+        assert_eq!(job_id, "4");
+        assert_eq!(opts.attempts, 3);
+        assert_eq!(opts.keep_failed(), job::KeepJobs::count(0));
+        assert_eq!(
+            reason,
+            r#"Invalid `data` field: invalid type: string "one", expected u32 at line 1 column 12 (raw: "{\"idx\":\"one\"}")"#
+        );
+    }
+
+    #[test]
+    fn reports_jobs_with_invalid_fields() {
+        let raw = job_reply(
+            b"5",
+            &[
+                ("name", "mixed"),
+                ("data", "1"),
+                ("priority", "high"),
+                ("opts", r#"{"attempts":2}"#),
+            ],
+        );
+
+        let MoveToActiveReturn::Undecodable {
+            job_id,
+            opts,
+            reason,
+        } = MoveToActiveReturn::<u32>::from_redis_value(&raw).unwrap()
+        else {
+            panic!("expected an undecodable job");
+        };
+
+        assert_eq!(job_id, "5");
+        assert_eq!(opts.attempts, 2);
+        assert_eq!(
+            reason,
+            "Invalid `priority` field: invalid digit found in string"
+        );
+
+        // A hash without the job's name
+        let raw = job_reply(b"6", &[("data", "1")]);
+
+        assert!(matches!(
+            MoveToActiveReturn::<u32>::from_redis_value(&raw).unwrap(),
+            MoveToActiveReturn::Undecodable { .. }
+        ));
+    }
+
+    /// Reply of the script for a job with the given hash.
+    fn job_reply(job_id: &[u8], hash: &[(&str, &str)]) -> redis::Value {
+        redis::Value::Array(vec![
+            redis::Value::Array(
+                hash.iter()
+                    .flat_map(|(field, value)| [bulk(field), bulk(value)])
+                    .collect(),
+            ),
+            redis::Value::BulkString(job_id.to_vec()),
+            redis::Value::Int(0),
+            redis::Value::Int(0),
+        ])
+    }
+
+    fn parse(raw: &redis::Value) -> redis::RedisResult<MoveToActiveReturn<serde_json::Value>> {
+        MoveToActiveReturn::from_redis_value(raw)
+    }
+
+    #[test]
+    fn decodes_replies_without_a_job() {
+        let reply = |ints: [i64; 4]| {
+            parse(&redis::Value::Array(
+                ints.into_iter().map(redis::Value::Int).collect(),
+            ))
+        };
+
+        assert!(matches!(reply([0, 0, 0, 0]), Ok(MoveToActiveReturn::None)));
+        assert!(matches!(
+            reply([0, 0, 500, 0]),
+            Ok(MoveToActiveReturn::RateLimited(500))
+        ));
+        // A delayed job is due later
+        assert!(matches!(
+            reply([0, 0, 0, 1700000000000]),
+            Ok(MoveToActiveReturn::None)
+        ));
+        assert!(reply([1, 0, 0, 0]).is_err());
+        assert!(parse(&redis::Value::Nil).is_err());
+        assert!(parse(&redis::Value::Array(vec![redis::Value::Int(0)])).is_err());
+    }
+
+    #[test]
+    fn defaults_missing_optional_fields() {
+        let raw = job_reply(b"5", &[("name", "minimal"), ("data", "null")]);
+
+        let MoveToActiveReturn::Job(job) = parse(&raw).unwrap() else {
+            panic!("expected a job");
+        };
+
+        assert_eq!(job.id, "5");
+        assert_eq!(job.name, "minimal");
+        assert_eq!(job.timestamp, 0);
+        assert_eq!(job.priority, 0);
+        assert_eq!(job.attempts_made, None);
+        assert_eq!(job.opts.attempts, 0);
+        assert_eq!(job.parent, None);
+    }
+
+    #[test]
+    fn ignores_unknown_fields() {
+        let mut raw = job_reply(
+            b"6",
+            &[
+                ("name", "extra"),
+                ("data", "1"),
+                ("rjk", "repeat:key"),
+                ("added-by", "another producer"),
+            ],
+        );
+
+        // Values that aren't strings are skipped too
+        if let redis::Value::Array(items) = &mut raw {
+            if let redis::Value::Array(hash) = &mut items[0] {
+                hash.extend([bulk("counter"), redis::Value::Int(3)]);
+            }
+        }
+
+        let MoveToActiveReturn::Job(job) = parse(&raw).unwrap() else {
+            panic!("expected a job");
+        };
+
+        assert_eq!(job.name, "extra");
+        assert_eq!(job.data, serde_json::json!(1));
+    }
+
+    #[test]
+    fn rejects_malformed_jobs_without_panicking() {
+        // Reported with their id, to be moved to failed
+        let malformed = [
+            job_reply(
+                b"7",
+                &[("name", "late"), ("data", "1"), ("timestamp", "soon")],
+            ),
+            job_reply(b"7", &[("name", "bad-opts"), ("data", "1"), ("opts", "{")]),
+            job_reply(b"7", &[("data", "1")]),
+            job_reply(b"7", &[("name", "no-data")]),
+        ];
+
+        for raw in malformed {
+            assert!(
+                matches!(
+                    parse(&raw),
+                    Ok(MoveToActiveReturn::Undecodable { ref job_id, .. }) if job_id == "7"
+                ),
+                "{:?}",
+                raw
+            );
+        }
+
+        let binary_id = job_reply(&[0xff, 0xfe], &[("name", "binary-id"), ("data", "1")]);
+
+        assert!(parse(&binary_id).is_err());
+    }
+
+    #[test]
+    fn falls_back_to_parent_key() {
+        assert_eq!(
+            job::ParentRef::from_key("bull:parent-queue:7"),
+            Some(job::ParentRef {
+                id: "7".to_string(),
+                queue_key: "bull:parent-queue".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn encodes_args_with_lua_names() {
+        let args = MoveToActiveArgs {
+            token: "token".to_string(),
+            lock_duration: 30_000,
+            name: Some("worker".to_string()),
+            limiter: Some(RateLimiterOptions {
+                max: 10,
+                duration: 1000,
+            }),
+            namespace: Some("acme".to_string()),
+        };
+
+        let encoded = rmp_serde::encode::to_vec_named(&args).unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&encoded).unwrap();
+
+        assert_eq!(
+            decoded,
+            serde_json::json!({
+                "token": "token",
+                "lockDuration": 30000,
+                "name": "worker",
+                "limiter": { "max": 10, "duration": 1000 },
+                "namespace": "acme",
+            })
+        );
+    }
 }
@@ -0,0 +1,99 @@
+use anyhow::Result;
+use redis::{ConnectionLike, FromRedisValue};
+use std::time::SystemTime;
+
+use crate::{generate_script_struct, queue_keys::QueueKeys};
+
+generate_script_struct!(MoveToDelayed, "./src/scripts/commands/moveToDelayed-8.lua");
+
+#[derive(Debug)]
+pub enum MoveToDelayedReturn {
+    Ok,
+    MissingKey,
+    MissingLock,
+    JobNotActiveInSet,
+}
+
+impl FromRedisValue for MoveToDelayedReturn {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        match v {
+            redis::Value::Int(0) => Ok(MoveToDelayedReturn::Ok),
+            redis::Value::Int(-1) => Ok(MoveToDelayedReturn::MissingKey),
+            redis::Value::Int(-2) => Ok(MoveToDelayedReturn::MissingLock),
+            redis::Value::Int(-3) => Ok(MoveToDelayedReturn::JobNotActiveInSet),
+            _ => Err(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "Unknown return value",
+            ))),
+        }
+    }
+}
+
+/// Score of a job in the delayed set. The lower 12 bits hold part of the job
+/// id so that jobs delayed until the same millisecond keep a stable order.
+pub fn delayed_score(timestamp: u128, delay: u64, job_id: &str) -> u128 {
+    let id_bits = job_id.parse::<u128>().unwrap_or(0) & 0xfff;
+
+    (timestamp + delay as u128) * 0x1000 + id_bits
+}
+
+impl MoveToDelayed {
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        job_id: &str,
+        token: &str,
+        delay: u64,
+    ) -> Result<MoveToDelayedReturn> {
+        let mut script = &mut self.prepare_invoke();
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        let keys: Vec<String> = [
+            QueueKeys::Marker,
+            QueueKeys::Active,
+            QueueKeys::Prioritized,
+            QueueKeys::Delayed,
+            QueueKeys::job(job_id),
+            QueueKeys::Events,
+            QueueKeys::Meta,
+            QueueKeys::Stalled,
+        ]
+        .iter()
+        .map(|s| s.with_prefix(prefix))
+        .collect();
+
+        for key in keys {
+            script = script.key(key)
+        }
+
+        let res = script
+            .arg(prefix)
+            .arg(timestamp.to_string())
+            .arg(delayed_score(timestamp, delay, job_id).to_string())
+            .arg(job_id)
+            .arg(token)
+            .arg(delay)
+            .invoke::<MoveToDelayedReturn>(&mut client)?;
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delayed_score_is_in_the_future() {
+        let now = 1_700_000_000_000;
+        let score = delayed_score(now, 1000, "42");
+
+        assert!(score / 0x1000 >= now + 1000);
+        assert_eq!(score % 0x1000, 42);
+    }
+}
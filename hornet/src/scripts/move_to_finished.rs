@@ -1,9 +1,12 @@
+use super::move_to_active::MoveToActiveReturn;
+use super::Invocation;
 use crate::generate_script_struct;
+use crate::job::KeepJobs;
 use crate::queue_keys::QueueKeys;
+use crate::worker::RateLimiterOptions;
 use anyhow::Result;
-use redis::FromRedisValue;
-use serde::Serialize;
-use std::convert::Into;
+use redis::{ConnectionLike, FromRedisValue};
+use serde::{de::DeserializeOwned, Serialize};
 use std::time::SystemTime;
 
 generate_script_struct!(
@@ -11,6 +14,7 @@ generate_script_struct!(
     "./src/scripts/commands/moveToFinished-14.lua"
 );
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum MoveToFinishedTarget {
     Completed,
     Failed,
@@ -38,11 +42,6 @@ impl ToString for MoveToFinishedTarget {
     }
 }
 
-#[derive(Debug, Serialize)]
-pub struct KeepJobs {
-    pub count: i32,
-}
-
 #[derive(Debug, Serialize)]
 pub struct MoveToFinishedArgs {
     pub token: String,
@@ -58,22 +57,68 @@ pub struct MoveToFinishedArgs {
     pub fail_parent_on_fail: bool,
     #[serde(rename = "rdof")]
     pub remove_dependency_on_fail: bool,
+    /// Whether to move the next job to active in the same call, sent as
+    /// `ARGV[6]`. The token, lock duration, name and limiter are used for it.
+    #[serde(skip)]
+    pub fetch_next: bool,
+    /// Worker name, used for the next job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Used for the next job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limiter: Option<RateLimiterOptions>,
 }
 
-#[derive(Debug)]
-pub enum MoveToFinishedReturn {
+pub enum MoveToFinishedReturn<JobData = ()> {
     Ok,
+    /// The job was moved and, as `fetch_next` was set, the script tried to move
+    /// the next job to active, as `MoveToActive` would.
+    Next(MoveToActiveReturn<JobData>),
     MissingKey,
     MissingLock,
     JobNotActiveInSet,
+    /// The job is a parent whose children have not all finished yet. It is an
+    /// expected outcome for flows rather than an error: the job is not moved
+    /// and stays waiting for its children.
     JobHasPendingDependencies,
     LockIsNotOwnedByThisClient,
 }
 
-impl FromRedisValue for MoveToFinishedReturn {
+// Not derived so it doesn't require `JobData: Debug`
+impl<JobData> std::fmt::Debug for MoveToFinishedReturn<JobData> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoveToFinishedReturn::Ok => write!(f, "Ok"),
+            MoveToFinishedReturn::Next(MoveToActiveReturn::Job(job)) => {
+                write!(f, "Next(Job({}))", job.id)
+            }
+            MoveToFinishedReturn::Next(MoveToActiveReturn::RateLimited(ttl)) => {
+                write!(f, "Next(RateLimited({}))", ttl)
+            }
+            MoveToFinishedReturn::Next(MoveToActiveReturn::None) => write!(f, "Next(None)"),
+            MoveToFinishedReturn::Next(MoveToActiveReturn::Undecodable { job_id, .. }) => {
+                write!(f, "Next(Undecodable({}))", job_id)
+            }
+            MoveToFinishedReturn::MissingKey => write!(f, "MissingKey"),
+            MoveToFinishedReturn::MissingLock => write!(f, "MissingLock"),
+            MoveToFinishedReturn::JobNotActiveInSet => write!(f, "JobNotActiveInSet"),
+            MoveToFinishedReturn::JobHasPendingDependencies => {
+                write!(f, "JobHasPendingDependencies")
+            }
+            MoveToFinishedReturn::LockIsNotOwnedByThisClient => {
+                write!(f, "LockIsNotOwnedByThisClient")
+            }
+        }
+    }
+}
+
+impl<JobData: DeserializeOwned> FromRedisValue for MoveToFinishedReturn<JobData> {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
         match v {
             redis::Value::Int(0) => Ok(MoveToFinishedReturn::Ok),
+            redis::Value::Array(_) => Ok(MoveToFinishedReturn::Next(
+                MoveToActiveReturn::from_redis_value(v)?,
+            )),
             redis::Value::Int(-1) => Ok(MoveToFinishedReturn::MissingKey),
             redis::Value::Int(-2) => Ok(MoveToFinishedReturn::MissingLock),
             redis::Value::Int(-3) => Ok(MoveToFinishedReturn::JobNotActiveInSet),
@@ -87,17 +132,73 @@ impl FromRedisValue for MoveToFinishedReturn {
     }
 }
 
+/// A job to move to completed or failed through `MoveToFinished::run_batch`.
+#[derive(Debug)]
+pub struct FinishedJob {
+    pub job_id: String,
+    pub return_value: Vec<u8>,
+    pub args: MoveToFinishedArgs,
+}
+
 impl MoveToFinished {
-    pub fn run(
+    /// Moves the job to `target`. The return value or failed reason is stored
+    /// as is, it's not necessarily valid UTF-8, e.g. a protobuf message.
+    pub fn run<JobData: DeserializeOwned>(
         &self,
         prefix: &str,
-        mut client: &mut redis::Client,
+        mut client: &mut impl ConnectionLike,
         job_id: &str,
-        return_msg: &str,
+        return_value: &[u8],
         target: MoveToFinishedTarget,
         args: MoveToFinishedArgs,
-    ) -> Result<MoveToFinishedReturn> {
-        let mut script = &mut self.0.prepare_invoke();
+    ) -> Result<MoveToFinishedReturn<JobData>> {
+        let mut res = self
+            .prepare(prefix, job_id, return_value, &target, &args)
+            .invoke::<MoveToFinishedReturn<JobData>>(&mut client)?;
+
+        if let MoveToFinishedReturn::Next(MoveToActiveReturn::Job(job)) = &mut res {
+            job.prefix = prefix.to_string();
+        }
+
+        Ok(res)
+    }
+
+    /// Moves every job to `target` in a single round trip, pipelining one
+    /// script call per job. Returns the result of each call, in order.
+    pub fn run_batch(
+        &self,
+        prefix: &str,
+        client: &mut impl ConnectionLike,
+        target: MoveToFinishedTarget,
+        jobs: &[FinishedJob],
+    ) -> Result<Vec<MoveToFinishedReturn>> {
+        if jobs.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let invocations: Vec<_> = jobs
+            .iter()
+            .map(|job| self.prepare(prefix, &job.job_id, &job.return_value, &target, &job.args))
+            .collect();
+
+        let mut pipe = redis::pipe();
+
+        for invocation in invocations.iter() {
+            pipe.invoke_script(invocation.inner());
+        }
+
+        invocations[0].retry_unloaded(client, |con| pipe.query(con))
+    }
+
+    fn prepare(
+        &self,
+        prefix: &str,
+        job_id: &str,
+        return_value: &[u8],
+        target: &MoveToFinishedTarget,
+        args: &MoveToFinishedArgs,
+    ) -> Invocation<'_> {
+        let mut script = self.prepare_invoke();
 
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -116,8 +217,8 @@ impl MoveToFinished {
             QueueKeys::Paused,
             QueueKeys::Meta,
             QueueKeys::Pc,
-            QueueKeys::Custom(target.to_string()),
-            QueueKeys::Custom(job_id.into()),
+            QueueKeys::target(target),
+            QueueKeys::job(job_id),
             QueueKeys::Metrics,
             QueueKeys::Marker,
         ]
@@ -126,27 +227,44 @@ impl MoveToFinished {
         .collect();
 
         for key in keys {
-            script = script.key(key)
+            script.key(key);
         }
 
-        let _args = vec![
-            job_id,
-            timestamp.as_str(),
-            target.msg_prorperty(),
-            return_msg,
-            target.as_str(),
-            "false",
-            prefix,
-        ];
-
-        for arg in _args {
-            script = script.arg(arg);
-        }
+        script
+            .arg(job_id)
+            .arg(timestamp)
+            .arg(target.msg_prorperty())
+            .arg(return_value)
+            .arg(target.as_str())
+            .arg(if args.fetch_next { "1" } else { "0" })
+            .arg(prefix);
 
-        script = script.arg(rmp_serde::to_vec_named(&args).unwrap());
+        script.arg(rmp_serde::to_vec_named(args).unwrap());
 
-        let res = script.invoke::<MoveToFinishedReturn>(&mut client)?;
+        script
+    }
+}
 
-        Ok(res)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Value;
+
+    #[test]
+    fn decodes_fetched_next_job() {
+        let res: MoveToFinishedReturn = FromRedisValue::from_redis_value(&Value::Int(0)).unwrap();
+        assert!(matches!(res, MoveToFinishedReturn::Ok));
+
+        let res: MoveToFinishedReturn = FromRedisValue::from_redis_value(&Value::Array(vec![
+            Value::Int(0),
+            Value::Int(0),
+            Value::Int(500),
+            Value::Int(0),
+        ]))
+        .unwrap();
+        assert!(matches!(
+            res,
+            MoveToFinishedReturn::Next(MoveToActiveReturn::RateLimited(500))
+        ));
     }
 }
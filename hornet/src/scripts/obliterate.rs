@@ -0,0 +1,51 @@
+use anyhow::Result;
+use redis::{ConnectionLike, FromRedisValue};
+
+use crate::{generate_script_struct, queue_keys::QueueKeys};
+
+generate_script_struct!(Obliterate, "./src/scripts/commands/obliterate-2.lua");
+
+#[derive(Debug)]
+pub enum ObliterateReturn {
+    Done,
+    HasMore,
+    NotPaused,
+    HasActiveJobs,
+}
+
+impl FromRedisValue for ObliterateReturn {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        match v {
+            redis::Value::Int(0) => Ok(ObliterateReturn::Done),
+            redis::Value::Int(1) => Ok(ObliterateReturn::HasMore),
+            redis::Value::Int(-1) => Ok(ObliterateReturn::NotPaused),
+            redis::Value::Int(-2) => Ok(ObliterateReturn::HasActiveJobs),
+            _ => Err(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "Unknown return value",
+            ))),
+        }
+    }
+}
+
+impl Obliterate {
+    /// Removes at most `count` jobs per invocation. Callers are expected to
+    /// keep calling while `ObliterateReturn::HasMore` is returned.
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        count: u32,
+        force: bool,
+    ) -> Result<ObliterateReturn> {
+        let res = self
+            .prepare_invoke()
+            .key(QueueKeys::Meta.with_prefix(prefix))
+            .key(prefix)
+            .arg(count)
+            .arg(if force { "1" } else { "" })
+            .invoke::<ObliterateReturn>(&mut client)?;
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::Result;
+use redis::ConnectionLike;
+
+use crate::{generate_script_struct, queue_keys::QueueKeys};
+
+generate_script_struct!(Pause, "./src/scripts/commands/pause-7.lua");
+
+impl Pause {
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        pause: bool,
+    ) -> Result<()> {
+        let mut script = &mut self.prepare_invoke();
+
+        let (src, dst) = if pause {
+            (QueueKeys::Wait, QueueKeys::Paused)
+        } else {
+            (QueueKeys::Paused, QueueKeys::Wait)
+        };
+
+        let keys: Vec<String> = [
+            src,
+            dst,
+            QueueKeys::Meta,
+            QueueKeys::Prioritized,
+            QueueKeys::Events,
+            QueueKeys::Delayed,
+            QueueKeys::Marker,
+        ]
+        .iter()
+        .map(|s| s.with_prefix(prefix))
+        .collect();
+
+        for key in keys {
+            script = script.key(key)
+        }
+
+        script
+            .arg(if pause { "paused" } else { "resumed" })
+            .invoke::<()>(&mut client)?;
+
+        Ok(())
+    }
+}
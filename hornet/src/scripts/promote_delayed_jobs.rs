@@ -0,0 +1,48 @@
+use anyhow::Result;
+use redis::ConnectionLike;
+use std::time::SystemTime;
+
+use crate::{generate_script_struct, queue_keys::QueueKeys};
+
+generate_script_struct!(
+    PromoteDelayedJobs,
+    "./src/scripts/commands/promoteDelayedJobs-8.lua"
+);
+
+impl PromoteDelayedJobs {
+    /// Moves the delayed jobs that are due to be processed out of the delayed
+    /// set, adding a marker so blocked workers wake up.
+    pub fn run(&self, prefix: &str, mut client: &mut impl ConnectionLike) -> Result<()> {
+        let mut script = &mut self.prepare_invoke();
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let keys: Vec<String> = [
+            QueueKeys::Delayed,
+            QueueKeys::Wait,
+            QueueKeys::Paused,
+            QueueKeys::Meta,
+            QueueKeys::Prioritized,
+            QueueKeys::Events,
+            QueueKeys::Pc,
+            QueueKeys::Marker,
+        ]
+        .iter()
+        .map(|s| s.with_prefix(prefix))
+        .collect();
+
+        for key in keys {
+            script = script.key(key)
+        }
+
+        script
+            .arg(prefix)
+            .arg(timestamp)
+            .invoke::<()>(&mut client)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use anyhow::Result;
+use redis::ConnectionLike;
+
+use crate::{generate_script_struct, queue_keys::QueueKeys};
+
+generate_script_struct!(
+    RecordIdempotencyKey,
+    "./src/scripts/commands/recordIdempotencyKey-1.lua"
+);
+
+impl RecordIdempotencyKey {
+    /// Stores the key and the encoded return value on the job's hash, see
+    /// `Job::set_idempotency_key`. Returns false if the job does not exist.
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        job_id: &str,
+        key: &str,
+        return_value: &[u8],
+    ) -> Result<bool> {
+        let res = self
+            .prepare_invoke()
+            .key(QueueKeys::job(job_id).with_prefix(prefix))
+            .arg(key)
+            .arg(return_value)
+            .invoke::<i64>(&mut client)?;
+
+        Ok(res == 1)
+    }
+}
@@ -0,0 +1,34 @@
+use anyhow::Result;
+use redis::ConnectionLike;
+
+use crate::{generate_script_struct, queue_keys::QueueKeys};
+
+generate_script_struct!(
+    RemoveExpiredJobs,
+    "./src/scripts/commands/removeExpiredJobs-5.lua"
+);
+
+impl RemoveExpiredJobs {
+    /// Removes the jobs whose `ttl` elapsed at `timestamp` from the states
+    /// they were waiting in, returning how many were.
+    pub fn run(
+        &self,
+        prefix: &str,
+        client: &mut impl ConnectionLike,
+        timestamp: u64,
+    ) -> Result<u64> {
+        let mut script = self.prepare_invoke();
+
+        for key in [
+            QueueKeys::Expiring,
+            QueueKeys::Wait,
+            QueueKeys::Paused,
+            QueueKeys::Prioritized,
+            QueueKeys::Delayed,
+        ] {
+            script.key(key.with_prefix(prefix));
+        }
+
+        script.arg(prefix).arg(timestamp).invoke(client)
+    }
+}
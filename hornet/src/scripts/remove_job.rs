@@ -0,0 +1,29 @@
+use anyhow::Result;
+use redis::ConnectionLike;
+
+use crate::generate_script_struct;
+
+generate_script_struct!(RemoveJob, "./src/scripts/commands/removeJob-1.lua");
+
+impl RemoveJob {
+    /// Removes the job, and its children if `remove_children` is set, from
+    /// whatever state it's in along with its logs. Returns false, without
+    /// removing anything, if the job or one of those children is locked by a
+    /// worker.
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        job_id: &str,
+        remove_children: bool,
+    ) -> Result<bool> {
+        let res = self
+            .prepare_invoke()
+            .key(prefix)
+            .arg(job_id)
+            .arg(if remove_children { "1" } else { "0" })
+            .invoke::<i64>(&mut client)?;
+
+        Ok(res == 1)
+    }
+}
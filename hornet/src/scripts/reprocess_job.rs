@@ -0,0 +1,72 @@
+use anyhow::Result;
+use redis::{ConnectionLike, FromRedisValue};
+
+use crate::{generate_script_struct, job::JobState, queue_keys::QueueKeys};
+
+generate_script_struct!(ReprocessJob, "./src/scripts/commands/reprocessJob-7.lua");
+
+#[derive(Debug)]
+pub enum ReprocessJobReturn {
+    Ok,
+    MissingKey,
+    JobNotInState,
+}
+
+impl FromRedisValue for ReprocessJobReturn {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        match v {
+            redis::Value::Int(1) => Ok(ReprocessJobReturn::Ok),
+            redis::Value::Int(-1) => Ok(ReprocessJobReturn::MissingKey),
+            redis::Value::Int(-3) => Ok(ReprocessJobReturn::JobNotInState),
+            _ => Err(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "Unknown return value",
+            ))),
+        }
+    }
+}
+
+impl ReprocessJob {
+    /// Moves a finished job back to wait. `state` must be either
+    /// `JobState::Completed` or `JobState::Failed`.
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        job_id: &str,
+        state: &JobState,
+    ) -> Result<ReprocessJobReturn> {
+        let mut script = &mut self.prepare_invoke();
+
+        let keys: Vec<String> = [
+            QueueKeys::job(job_id),
+            QueueKeys::Events,
+            QueueKeys::Custom(state.as_str().to_string()),
+            QueueKeys::Wait,
+            QueueKeys::Meta,
+            QueueKeys::Paused,
+            QueueKeys::Marker,
+        ]
+        .iter()
+        .map(|s| s.with_prefix(prefix))
+        .collect();
+
+        for key in keys {
+            script = script.key(key)
+        }
+
+        let prop = match state {
+            JobState::Completed => "returnvalue",
+            _ => "failedReason",
+        };
+
+        let res = script
+            .arg(job_id)
+            .arg("LPUSH")
+            .arg(prop)
+            .arg(state.as_str())
+            .invoke::<ReprocessJobReturn>(&mut client)?;
+
+        Ok(res)
+    }
+}
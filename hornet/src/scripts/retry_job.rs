@@ -1,5 +1,5 @@
 use anyhow::Result;
-use redis::{FromRedisValue, ToRedisArgs};
+use redis::{ConnectionLike, FromRedisValue, ToRedisArgs};
 use serde::Serialize;
 use std::time::SystemTime;
 
@@ -50,11 +50,11 @@ impl RetryJob {
     pub fn run(
         &self,
         prefix: &str,
-        mut client: &mut redis::Client,
+        mut client: &mut impl ConnectionLike,
         job_id: &str,
         token: &str,
     ) -> Result<RetryJobReturn> {
-        let mut script = &mut self.0.prepare_invoke();
+        let mut script = &mut self.prepare_invoke();
 
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -66,7 +66,7 @@ impl RetryJob {
             QueueKeys::Active,
             QueueKeys::Wait,
             QueueKeys::Paused,
-            QueueKeys::Custom(job_id.to_string()),
+            QueueKeys::job(job_id),
             QueueKeys::Meta,
             QueueKeys::Events,
             QueueKeys::Delayed,
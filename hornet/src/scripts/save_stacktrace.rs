@@ -0,0 +1,32 @@
+use anyhow::Result;
+use redis::ConnectionLike;
+
+use crate::{generate_script_struct, queue_keys::QueueKeys};
+
+generate_script_struct!(
+    SaveStacktrace,
+    "./src/scripts/commands/saveStacktrace-1.lua"
+);
+
+impl SaveStacktrace {
+    /// Stores the stack traces of a failed job, as a JSON array, along with
+    /// the reason of its last failure. Returns false if the job does not
+    /// exist.
+    pub fn run(
+        &self,
+        prefix: &str,
+        mut client: &mut impl ConnectionLike,
+        job_id: &str,
+        stacktrace: &[String],
+        failed_reason: &str,
+    ) -> Result<bool> {
+        let res = self
+            .prepare_invoke()
+            .key(QueueKeys::job(job_id).with_prefix(prefix))
+            .arg(serde_json::to_string(stacktrace)?)
+            .arg(failed_reason)
+            .invoke::<i64>(&mut client)?;
+
+        Ok(res == 0)
+    }
+}
@@ -1,27 +1,77 @@
+#[cfg(feature = "debug-events")]
+use crate::worker_events::{self, WorkerEvent, WorkerEvents};
 use crate::{
-    job::Job,
+    backoff::{self, CustomBackoffFn, CustomStrategies},
+    commands::{Commands, RedisCommands},
+    completion_batcher::{BatchOptions, CompletionBatcher, PendingCompletion},
+    connection::{
+        redacted_addr, with_protocol, BlockingConnection, ConnectionLost, ProtocolVersion,
+        TimeoutClient,
+    },
+    context::HornetContext,
+    job::{Ack, AckHandle, Job, JobOptions, JobOutcome, KeepJobs, RawReturnValue},
+    keyspace_notifications,
+    lock_manager::{self, ActiveJobs, LockManagerOptions},
+    log,
+    prefix::{check_cluster_slots, Prefix},
+    queue_events::set_events_maxlen,
+    rate_limiter::LocalRateLimiter,
+    result_store::{record_reference, ResultStore, ResultStoreOptions},
     scripts::{
+        extend_lock::ExtendLock,
+        loader::ScriptLoaderError,
+        move_stalled_jobs_to_wait::MoveStalledJobsToWait,
         move_to_active::{MoveToActive, MoveToActiveArgs, MoveToActiveReturn},
+        move_to_delayed::{MoveToDelayed, MoveToDelayedReturn},
         move_to_finished::{
-            KeepJobs, MoveToFinished, MoveToFinishedArgs, MoveToFinishedReturn,
+            FinishedJob, MoveToFinished, MoveToFinishedArgs, MoveToFinishedReturn,
             MoveToFinishedTarget,
         },
+        promote_delayed_jobs::PromoteDelayedJobs,
+        record_idempotency_key::RecordIdempotencyKey,
+        remove_job::RemoveJob,
         retry_job::{RetryJob, RetryJobReturn},
+        save_stacktrace::SaveStacktrace,
     },
+    worker_registry::WorkerInfo,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
 use lazy_static::lazy_static;
-use redis::{Client, Commands};
-use serde::{de::DeserializeOwned, Serialize};
+use redis::{Client, RedisResult};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    any::Any,
+    collections::HashMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    sync::{oneshot, Notify, Semaphore, SemaphorePermit},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 lazy_static! {
-    static ref MOVE_TO_ACTIVE: MoveToActive = MoveToActive::new();
-    static ref MOVE_TO_FINISHED: MoveToFinished = MoveToFinished::new();
-    static ref RETRY_JOB: RetryJob = RetryJob::new();
+    static ref PROMOTE_DELAYED_JOBS: PromoteDelayedJobs = PromoteDelayedJobs::new();
+    static ref RECORD_IDEMPOTENCY_KEY: RecordIdempotencyKey = RecordIdempotencyKey::new();
+    static ref SAVE_STACKTRACE: SaveStacktrace = SaveStacktrace::new();
 }
 
 const DEFAULT_LOCK_DURATION: u64 = 30_000;
+const DEFAULT_STALLED_INTERVAL: Duration = Duration::from_secs(30);
+/// Seconds to block on the marker before waking up to promote delayed jobs
+const DELAYED_PROMOTION_INTERVAL: f64 = 5.;
+/// Seconds to block on the marker before checking for jobs added without
+/// one, e.g. by older clients
+const MARKER_FALLBACK_INTERVAL: f64 = 10.;
+/// How often the queue-level concurrency is read from the queue's meta
+const GLOBAL_CONCURRENCY_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
 
 struct WorkerToken {
     token: String,
@@ -42,182 +92,2984 @@ impl WorkerToken {
     }
 }
 
-enum TaskEvent {
-    Freed,
+/// Id of the worker that locked a job with `token`, i.e. the id it's
+/// registered with in the queue's `workers` hash.
+pub(crate) fn token_worker_id(token: &str) -> &str {
+    token.split_once(':').map_or(token, |(id, _)| id)
+}
+
+/// One of the queues a worker pulls jobs from, see
+/// `WorkerOptions::queue_priority`.
+#[derive(Clone)]
+struct WorkerQueue {
+    name: String,
+    prefix: String,
+    /// Jobs of this queue being processed, whose locks are kept fresh
+    active_jobs: ActiveJobs,
+    batcher: Option<CompletionBatcher>,
+}
+
+impl WorkerQueue {
+    fn new(name: String, prefix: &Prefix) -> Self {
+        WorkerQueue {
+            prefix: prefix.queue_key_prefix(&name),
+            name,
+            active_jobs: ActiveJobs::default(),
+            batcher: None,
+        }
+    }
+}
+
+/// State the processor tasks share with the worker's main loop. The worker
+/// spawns `concurrency` tasks once, each fetching and processing jobs until
+/// none is left, then waiting for the main loop to be woken by a new job.
+struct ProcessorPool {
+    /// Notified by the main loop when jobs may have been added, or the worker
+    /// was resumed or closed
+    jobs_added: Notify,
+    /// Notified by a task once it runs out of jobs
+    task_idle: Notify,
+    /// Number of tasks allowed to fetch jobs, see `Worker::concurrency`.
+    /// Tasks past it finish their job and wait until it's raised again.
+    concurrency: AtomicUsize,
+    /// One permit per concurrency unit, each job holding as many as its
+    /// weight while it's processed, see `JobOptions::weight`
+    capacity: Semaphore,
+    capacity_size: u32,
+}
+
+impl ProcessorPool {
+    fn new(concurrency: usize) -> Self {
+        ProcessorPool {
+            jobs_added: Notify::new(),
+            task_idle: Notify::new(),
+            concurrency: AtomicUsize::new(concurrency),
+            capacity: Semaphore::new(concurrency),
+            capacity_size: concurrency as u32,
+        }
+    }
+}
+
+/// Handle to a worker, used to control it while it runs. See
+/// `Worker::handle`.
+#[derive(Clone)]
+pub struct WorkerHandle {
+    paused: Arc<AtomicBool>,
+    /// Cancelled on `close`, handed to the jobs being processed
+    closing: CancellationToken,
+    wake: Arc<Notify>,
+    active_tasks: Arc<AtomicUsize>,
+}
+
+impl WorkerHandle {
+    fn new() -> Self {
+        WorkerHandle {
+            paused: Arc::new(AtomicBool::new(false)),
+            closing: CancellationToken::new(),
+            wake: Arc::new(Notify::new()),
+            active_tasks: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Stops this worker from fetching new jobs, letting the active ones
+    /// finish, e.g. to drain it before a restart. Unlike `Queue::pause`, the
+    /// other workers of the queue keep processing jobs.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Lets a paused worker fetch jobs again, waking it up right away.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        self.wake.notify_one();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Shuts the worker down gracefully: it stops fetching new jobs and `run`
+    /// returns once the active ones are finished and their completions
+    /// flushed. A worker waiting for new jobs notices within a few seconds.
+    /// The cancellation token of the jobs being processed is cancelled, see
+    /// `Job::cancellation_token`.
+    pub fn close(&self) {
+        self.closing.cancel();
+        self.wake.notify_one();
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.is_cancelled()
+    }
+
+    /// Number of processor tasks currently processing jobs, 0 once a paused
+    /// worker is drained or while it waits for new jobs.
+    pub fn active_count(&self) -> usize {
+        self.active_tasks.load(Ordering::Relaxed)
+    }
+
+    /// Whether the worker may fetch new jobs. Checked before every fetch, by
+    /// the main loop and by the processor tasks.
+    fn is_fetching(&self) -> bool {
+        !self.is_paused() && !self.is_closing()
+    }
+}
+
+type ProcessFn<Data, Return> = fn(Job<Data>) -> Result<Return>;
+/// Process function registered at runtime, see `Handlers`.
+pub type Handler<Data, Return> = Arc<dyn Fn(Job<Data>) -> Result<Return> + Send + Sync>;
+type ValidateFn<Data> = fn(&Data) -> Result<()>;
+
+/// Process functions of a worker for jobs of a given name, taking precedence
+/// over the one it was built with. They can be swapped while the worker
+/// runs, e.g. to load new logic from a plugin or a rules engine without
+/// restarting it. See `Worker::handlers`.
+///
+/// The worker looks the handler up when it starts processing each job: once
+/// `set` or `remove` returns, every job started afterwards, on any processor
+/// task, sees the change, while the jobs being processed finish with the
+/// handler they started with. Concurrent changes are applied one after the
+/// other, none is lost.
+pub struct Handlers<Data, Return> {
+    handlers: Arc<ArcSwap<HashMap<String, Handler<Data, Return>>>>,
+}
+
+impl<Data, Return> Clone for Handlers<Data, Return> {
+    fn clone(&self) -> Self {
+        Handlers {
+            handlers: self.handlers.clone(),
+        }
+    }
+}
+
+impl<Data, Return> Handlers<Data, Return> {
+    fn new() -> Self {
+        Handlers {
+            handlers: Arc::default(),
+        }
+    }
+
+    /// Processes the jobs named `name` with `handler` from now on.
+    pub fn set(&self, name: &str, handler: Handler<Data, Return>) {
+        self.handlers.rcu(|handlers| {
+            let mut handlers = HashMap::clone(handlers);
+            handlers.insert(name.to_string(), handler.clone());
+            handlers
+        });
+    }
+
+    /// Processes the jobs named `name` with the worker's process function
+    /// again.
+    pub fn remove(&self, name: &str) {
+        self.handlers.rcu(|handlers| {
+            let mut handlers = HashMap::clone(handlers);
+            handlers.remove(name);
+            handlers
+        });
+    }
+
+    /// Handler currently registered for the jobs named `name`.
+    pub fn get(&self, name: &str) -> Option<Handler<Data, Return>> {
+        self.handlers.load().get(name).cloned()
+    }
+
+    /// Handler to process the job with, falling back to `process_fn`.
+    fn resolve(&self, name: &str, process_fn: ProcessFn<Data, Return>) -> Handler<Data, Return>
+    where
+        Data: 'static,
+        Return: 'static,
+    {
+        self.get(name).unwrap_or_else(|| Arc::new(process_fn))
+    }
+}
+type OutcomeFn = fn(&JobOutcome);
+type SaturatedFn = fn(Duration);
+type StalledFn = fn(&str);
+type ConnectionLostFn = fn(&ConnectionLost);
+type ReadyFn = fn();
+
+#[derive(Clone, Copy, Default)]
+struct WorkerCallbacks {
+    on_completed: Option<OutcomeFn>,
+    on_failed: Option<OutcomeFn>,
+    on_saturated: Option<SaturatedFn>,
+    on_stalled: Option<StalledFn>,
+    on_connection_lost: Option<ConnectionLostFn>,
+    on_ready: Option<ReadyFn>,
+    after_process: Option<OutcomeFn>,
+}
+
+/// What the worker still needs of a job once it's moved into the process
+/// function.
+struct JobSummary {
+    id: String,
+    name: String,
+    opts: JobOptions,
+    timestamp: u128,
+    processed_on: u128,
+    attempts_made: Option<u32>,
+    stacktrace: Vec<String>,
+}
+
+impl JobSummary {
+    fn of<Data>(job: &Job<Data>) -> Self {
+        JobSummary {
+            id: job.id.clone(),
+            name: job.name.clone(),
+            opts: job.opts.clone(),
+            timestamp: job.timestamp,
+            processed_on: job.processed_on,
+            attempts_made: job.attempts_made,
+            stacktrace: job.stacktrace.clone(),
+        }
+    }
+
+    fn outcome(&self, started_at: Instant, failed_reason: Option<String>) -> JobOutcome {
+        let finished_on = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        JobOutcome {
+            job_id: self.id.clone(),
+            name: self.name.clone(),
+            wait_time: Duration::from_millis(
+                self.processed_on.saturating_sub(self.timestamp) as u64
+            ),
+            process_time: started_at.elapsed(),
+            finished_on,
+            failed_reason,
+            attempts_made: self.attempts_made.unwrap_or(0) + 1,
+            retry_in: None,
+        }
+    }
+}
+
+/// Runs the process function, turning a panic into an error so the job fails
+/// like with any other error instead of taking the processor task down.
+fn run_process_fn<Data, Return>(
+    process_fn: impl FnOnce(Job<Data>) -> Result<Return>,
+    job: Job<Data>,
+) -> Result<Return> {
+    panic::catch_unwind(AssertUnwindSafe(|| process_fn(job))).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+
+        Err(anyhow!("Process function panicked: {}", message))
+    })
+}
+
+/// Resolves on the first SIGINT (Ctrl+C) or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen to SIGTERM");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Permit of the shared semaphore held while a handler runs, see
+/// `WorkerOptions::semaphore`. A closed semaphore no longer gates anything.
+async fn acquire_permit(semaphore: Option<&Semaphore>) -> Option<SemaphorePermit<'_>> {
+    semaphore?.acquire().await.ok()
+}
+
+/// Runs the process function on the current task, or on tokio's blocking
+/// thread pool if `blocking` is set. Also returns whether the job may be
+/// retried if it failed.
+async fn execute<Data, Return>(
+    process_fn: Handler<Data, Return>,
+    validate_fn: Option<ValidateFn<Data>>,
+    blocking: bool,
+    job: Job<Data>,
+) -> (Result<Return>, bool)
+where
+    Data: Send + 'static,
+    Return: Send + 'static,
+{
+    // Invalid jobs would fail the same way on every attempt
+    match validate_fn.map(|validate| validate(&job.data)) {
+        Some(Err(err)) => (Err(anyhow!("Invalid job data: {}", err)), false),
+        _ if blocking => (
+            tokio::task::spawn_blocking(move || run_process_fn(&*process_fn, job))
+                .await
+                .unwrap_or_else(|err| Err(anyhow!("Process function failed: {}", err))),
+            true,
+        ),
+        _ => (run_process_fn(&*process_fn, job), true),
+    }
+}
+
+/// Same as `execute`, encoding the return value as it's stored: offloaded to
+/// the result store if set and the value is too large, its reference being
+/// recorded under `prefix`.
+async fn execute_encoded<Data, Return>(
+    process_fn: Handler<Data, Return>,
+    validate_fn: Option<ValidateFn<Data>>,
+    blocking: bool,
+    result_store: Option<&ResultStoreOptions>,
+    client: &TimeoutClient,
+    job: Job<Data>,
+) -> (Result<Vec<u8>>, bool)
+where
+    Data: Send + 'static,
+    Return: Serialize + Send + 'static,
+{
+    let job_id = job.id.clone();
+    let prefix = job.prefix.clone();
+    let (res, retryable) = execute(process_fn, validate_fn, blocking, job).await;
+
+    // A return value that can't be stored fails the same way on every attempt
+    let (res, retryable) = match res.map(|res| encode_return_value(&res)) {
+        Ok(Err(err)) => (Err(err), false),
+        res => (res.and_then(|res| res), retryable),
+    };
+
+    let (result_store, value) = match (result_store, res) {
+        (Some(result_store), Ok(value)) => (result_store.clone(), value),
+        (_, res) => return (res, retryable),
+    };
+    let mut client = client.clone();
+
+    // Stores are synchronous and may be slow, e.g. S3, so they are kept off
+    // the runtime's threads
+    let offloaded = tokio::task::spawn_blocking(move || {
+        let (value, reference) = result_store.offload(&job_id, value)?;
+
+        if let Some(reference) = reference {
+            record_reference(&mut client, &prefix, &job_id, &reference)?;
+        }
+
+        Ok(value)
+    })
+    .await
+    .unwrap_or_else(|err| Err(anyhow!("Storing the return value failed: {}", err)));
+
+    (offloaded, retryable)
+}
+
+/// The handler's result once the job is acked, see `WorkerOptions::manual_ack`.
+/// Failed handlers don't wait for it.
+/// Jobs not acked within `timeout` are nacked, see `WorkerOptions::ack_timeout`.
+async fn wait_for_ack(
+    res: Result<Vec<u8>>,
+    acked: Option<oneshot::Receiver<Ack>>,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>> {
+    let (return_value, acked) = match (res, acked) {
+        (Ok(return_value), Some(acked)) => (return_value, acked),
+        (res, _) => return res,
+    };
+    let acked = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, acked).await {
+            Ok(acked) => acked,
+            Err(_) => return Err(anyhow!("Job was not acked within {:?}", timeout)),
+        },
+        None => acked.await,
+    };
+
+    match acked {
+        Ok(Ack::Ack) => Ok(return_value),
+        Ok(Ack::Nack(reason)) => Err(anyhow!(reason)),
+        Err(_) => Err(anyhow!("Job was not acked")),
+    }
+}
+
+/// Concurrency of a worker, lowered to the queue-level concurrency if any.
+/// Never 0, so a worker keeps processing (slowly) rather than stalling.
+fn effective_concurrency(local: usize, global: Option<u32>) -> usize {
+    match global {
+        Some(global) => local.min(global as usize).max(1),
+        None => local,
+    }
+}
+
+/// Loads every script the worker runs, which would otherwise panic on their
+/// first use.
+fn check_scripts() -> Result<()> {
+    let loaded: Result<(), ScriptLoaderError> = (|| {
+        MoveToActive::try_new()?;
+        MoveToFinished::try_new()?;
+        MoveToDelayed::try_new()?;
+        RetryJob::try_new()?;
+        PromoteDelayedJobs::try_new()?;
+        ExtendLock::try_new()?;
+        MoveStalledJobsToWait::try_new()?;
+        RemoveJob::try_new()?;
+        RecordIdempotencyKey::try_new()?;
+        SaveStacktrace::try_new()?;
+
+        Ok(())
+    })();
+
+    loaded.map_err(|err| anyhow!("Failed to load the worker's scripts: {:?}", err))
+}
+
+/// Raw bytes for a `RawReturnValue`, JSON for anything else. Fails if the
+/// value can't be serialized, e.g. a map with non-string keys.
+fn encode_return_value<Return: Serialize + 'static>(result: &Return) -> Result<Vec<u8>> {
+    match (result as &dyn Any).downcast_ref::<RawReturnValue>() {
+        Some(raw) => Ok(raw.0.clone()),
+        None => serde_json::to_vec(result)
+            .map_err(|err| anyhow!("Failed to serialize the return value: {}", err)),
+    }
+}
+
+/// Redis side rate limit shared by every worker of the queue: at most `max`
+/// jobs are processed every `duration` milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimiterOptions {
+    pub max: u32,
+    pub duration: u64,
+}
+
+/// How many times a job may be handed to a process function.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Jobs stay in the queue, locked, while they are processed. Failed jobs
+    /// are retried according to their `attempts` and jobs of a worker that
+    /// crashed or lost its lock are recovered as stalled, so a job may be
+    /// processed more than once but is never lost.
+    #[default]
+    AtLeastOnce,
+    /// Jobs are removed from the queue as soon as they are fetched, before
+    /// being processed. They are never retried, locked or recovered: a job
+    /// whose handler fails, or whose worker crashes, is lost. No `completed`
+    /// or `failed` event is emitted, only `removed`, although the worker's
+    /// callbacks still run. Suits telemetry or log shipping, where processing
+    /// a job twice is worse than dropping it.
+    AtMostOnce,
+}
+
+/// What a worker does with a job whose data can't be deserialized as its
+/// `Data`, e.g. one added by another application to a shared queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeserializeErrorPolicy {
+    /// Moves the job to failed, with a "Could not deserialize job data"
+    /// reason, without retrying it, and goes on with the next job.
+    #[default]
+    SkipToFailed,
+    /// Logs the error and stops the processor task, leaving the job active
+    /// until it's recovered as stalled.
+    Crash,
+}
+
+/// How an idle worker learns that jobs were added.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FetchStrategy {
+    /// Blocks on the queue's marker, which producers set when adding jobs, as
+    /// BullMQ workers do.
+    #[default]
+    Marker,
+    /// Subscribes, from a dedicated thread, to the keyspace notifications of
+    /// the queue's `wait` list and `prioritized` set. Requires the server's
+    /// `notify-keyspace-events` to include `Klz`, which is off by default;
+    /// without it the worker only finds new jobs by polling, every 5 to 10
+    /// seconds.
+    KeyspaceNotifications,
+}
+
+/// Options used to build a `Worker` through `Worker::with_options`.
+#[derive(Debug, Clone)]
+pub struct WorkerOptions {
+    name: Option<String>,
+    concurrency: usize,
+    events_maxlen: Option<u64>,
+    saturation_threshold: Option<Duration>,
+    limiter: Option<RateLimiterOptions>,
+    protocol: Option<ProtocolVersion>,
+    promote_delayed: bool,
+    local_rate_limit: Option<u32>,
+    semaphore: Option<Arc<Semaphore>>,
+    stalled_interval: Option<Duration>,
+    max_stalled_count: u32,
+    batch_completions: Option<BatchOptions>,
+    blocking: bool,
+    remove_on_complete_immediate: bool,
+    max_reconnect_attempts: Option<u32>,
+    delivery_mode: DeliveryMode,
+    manual_ack: bool,
+    ack_timeout: Option<Duration>,
+    namespace: Option<String>,
+    pub(crate) command_timeout: Option<Duration>,
+    blocking_command_timeout: Option<Duration>,
+    result_store: Option<ResultStoreOptions>,
+    on_deserialize_error: DeserializeErrorPolicy,
+    fetch_strategy: FetchStrategy,
+    queue_priority: Vec<String>,
+    prefix: Prefix,
+}
+
+impl Default for WorkerOptions {
+    fn default() -> Self {
+        WorkerOptions {
+            name: None,
+            concurrency: 1,
+            events_maxlen: None,
+            saturation_threshold: None,
+            limiter: None,
+            protocol: None,
+            promote_delayed: true,
+            local_rate_limit: None,
+            semaphore: None,
+            stalled_interval: Some(DEFAULT_STALLED_INTERVAL),
+            max_stalled_count: 1,
+            batch_completions: None,
+            blocking: false,
+            remove_on_complete_immediate: false,
+            max_reconnect_attempts: None,
+            delivery_mode: DeliveryMode::AtLeastOnce,
+            manual_ack: false,
+            ack_timeout: None,
+            namespace: None,
+            command_timeout: None,
+            blocking_command_timeout: None,
+            result_store: None,
+            on_deserialize_error: DeserializeErrorPolicy::SkipToFailed,
+            fetch_strategy: FetchStrategy::Marker,
+            queue_priority: vec![],
+            prefix: Prefix::default(),
+        }
+    }
 }
 
-type ProcessFn<Data, Return> = fn(&Job<Data>) -> Result<Return>;
+impl WorkerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name the worker is listed under in `Queue::get_workers`, also stored in
+    /// the `pb` (processed by) field of the jobs it processes.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Maximum number of jobs processed at the same time (at least 1).
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sets the concurrency to the number of CPUs available to the process,
+    /// which suits CPU-bound handlers. Falls back to 1 if it can't be known.
+    pub fn concurrency_from_parallelism(self) -> Self {
+        let parallelism = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        self.concurrency(parallelism)
+    }
+
+    /// Approximate maximum length of the queue's `events` stream, written to
+    /// the queue's meta key when the worker starts. When unset, the value
+    /// configured by the queue is kept (10000 by default).
+    pub fn events_maxlen(mut self, events_maxlen: u64) -> Self {
+        self.events_maxlen = Some(events_maxlen);
+        self
+    }
+
+    /// Warns when the worker has been running at full concurrency for longer
+    /// than `threshold`, a sign it is under-provisioned. See
+    /// `Worker::on_saturated`.
+    pub fn saturation_threshold(mut self, threshold: Duration) -> Self {
+        self.saturation_threshold = Some(threshold);
+        self
+    }
+
+    /// Limits the queue to `max` jobs every `duration` milliseconds, across
+    /// all workers using the same limiter.
+    pub fn limiter(mut self, max: u32, duration: u64) -> Self {
+        self.limiter = Some(RateLimiterOptions { max, duration });
+        self
+    }
+
+    /// Protocol used to talk to Redis. RESP2 unless the url selects RESP3.
+    pub fn protocol(mut self, protocol: ProtocolVersion) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// Whether the worker moves due delayed jobs to wait by itself, at least
+    /// every few seconds, so no separate scheduler is needed. Enabled by
+    /// default.
+    ///
+    /// The promotion is atomic, so several workers doing it is safe but
+    /// redundant: with many workers it can be disabled on all but one.
+    pub fn promote_delayed(mut self, promote_delayed: bool) -> Self {
+        self.promote_delayed = promote_delayed;
+        self
+    }
+
+    /// Limits this worker to `max_per_sec` jobs per second across all of its
+    /// concurrency slots, e.g. to protect a local resource such as a GPU.
+    /// Unlike `limiter`, which is enforced in Redis for every worker of the
+    /// queue, this limit is per process.
+    pub fn local_rate_limit(mut self, max_per_sec: u32) -> Self {
+        self.local_rate_limit = Some(max_per_sec);
+        self
+    }
+
+    /// Runs the handler only with a permit of `semaphore`, shared with other
+    /// workers of the process to cap the jobs they process at once, e.g. 20
+    /// across 5 queues using the same database pool. The job is fetched, and
+    /// locked, before the permit is acquired.
+    pub fn semaphore(mut self, semaphore: Arc<Semaphore>) -> Self {
+        self.semaphore = Some(semaphore);
+        self
+    }
+
+    /// How often the worker looks for stalled jobs: active jobs whose lock
+    /// expired, e.g. because their worker died. They are moved back to wait.
+    /// 30 seconds by default.
+    pub fn stalled_interval(mut self, interval: Duration) -> Self {
+        self.stalled_interval = Some(interval);
+        self
+    }
+
+    /// Disables the stalled jobs check, e.g. if other workers already do it.
+    pub fn skip_stalled_check(mut self) -> Self {
+        self.stalled_interval = None;
+        self
+    }
+
+    /// Number of times a job can stall before being moved to failed instead
+    /// of back to wait. 1 by default.
+    pub fn max_stalled_count(mut self, max_stalled_count: u32) -> Self {
+        self.max_stalled_count = max_stalled_count;
+        self
+    }
+
+    /// Moves completed jobs to completed in batches of up to `max_size`, in a
+    /// single round trip, instead of one call per job. Pending jobs are also
+    /// flushed every `flush_interval`, which delays their completion by as
+    /// much. Meant for very fast handlers, where Redis round trips dominate.
+    /// Failed jobs are never batched. Disabled by default.
+    pub fn batch_completions(mut self, max_size: usize, flush_interval: Duration) -> Self {
+        self.batch_completions = Some(BatchOptions {
+            max_size,
+            flush_interval,
+        });
+        self
+    }
+
+    /// Runs the process function on tokio's blocking thread pool instead of
+    /// on the worker's async tasks, so CPU-bound handlers (image processing,
+    /// compression...) don't stall the runtime's other tasks. At most
+    /// `concurrency` jobs are processed at the same time either way. Disabled
+    /// by default, as it costs a thread hand-off per job.
+    pub fn blocking(mut self, blocking: bool) -> Self {
+        self.blocking = blocking;
+        self
+    }
+
+    /// Removes successful jobs right away instead of moving them to
+    /// completed, whatever their `removeOnComplete` option, e.g. for high
+    /// volume jobs nobody looks back at. The `completed` event, with the
+    /// return value, is still emitted. Failed jobs are not affected.
+    pub fn remove_on_complete_immediate(mut self, remove: bool) -> Self {
+        self.remove_on_complete_immediate = remove;
+        self
+    }
+
+    /// Number of consecutive failed attempts to reconnect to Redis after
+    /// which `Worker::try_run` gives up. `run` keeps trying forever, as does
+    /// `try_run` when unset (the default).
+    pub fn max_reconnect_attempts(mut self, attempts: u32) -> Self {
+        self.max_reconnect_attempts = Some(attempts);
+        self
+    }
+
+    /// Whether jobs are processed at least once (the default) or at most
+    /// once, see `DeliveryMode`.
+    pub fn delivery_mode(mut self, mode: DeliveryMode) -> Self {
+        self.delivery_mode = mode;
+        self
+    }
+
+    /// Leaves the jobs whose handler succeeded active, their lock renewed,
+    /// until they are acked with the `AckHandle` taken from the job with
+    /// `Job::ack_handle`, e.g. once the transaction recording their result is
+    /// committed. A crash between the handler returning and the commit then
+    /// can't complete a job whose result was lost: the job is recovered as
+    /// stalled once its lock expires and processed again, so the commit
+    /// should be idempotent. Nacking the job, or dropping its handle, fails
+    /// the attempt. Jobs are held, along with their concurrency slot, until
+    /// acked, and closing the worker waits for them, unless `ack_timeout` is
+    /// set. Ignored with `DeliveryMode::AtMostOnce`.
+    pub fn manual_ack(mut self, manual_ack: bool) -> Self {
+        self.manual_ack = manual_ack;
+        self
+    }
+
+    /// Nacks the jobs processed with `manual_ack` that aren't acked within
+    /// `timeout` of their handler returning, e.g. because their handle was
+    /// leaked, instead of holding them and their concurrency slot forever.
+    /// Unset (no deadline) by default.
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// Only processes the jobs of the given namespace, added with
+    /// `JobOptions::namespace`, e.g. to run a tenant's jobs on its own
+    /// workers while tenants share a queue. Instead of popping the next job,
+    /// the worker looks for the oldest job of its namespace in wait, then
+    /// for the one with the highest priority, among the first 1000 of each:
+    /// it gets slower as other namespaces' jobs pile up, and misses its jobs
+    /// while more than 1000 others are ahead of them. A worker of another
+    /// namespace may also take the marker a job was added with, delaying the
+    /// pickup by up to 10 seconds. Separate queues avoid these costs and
+    /// isolate the tenants' backlogs, rate limits and pauses, at the price of
+    /// a queue, and workers, per tenant.
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// Fails the worker's commands, scripts included, that Redis takes longer
+    /// than `timeout` to answer instead of waiting forever, e.g. after a
+    /// failover left the connection half-open. Failed commands are logged and
+    /// retried like any other error. Unset (no timeout) by default.
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = Some(timeout.max(Duration::from_millis(1)));
+        self
+    }
+
+    /// Same as `command_timeout`, for the command waiting for new jobs: how
+    /// long it may take on top of the few seconds it asks Redis to block.
+    /// Timing out goes through the reconnection logic, see
+    /// `Worker::on_connection_lost`.
+    pub fn blocking_command_timeout(mut self, timeout: Duration) -> Self {
+        self.blocking_command_timeout = Some(timeout.max(Duration::from_millis(1)));
+        self
+    }
+
+    /// Offloads return values larger than `threshold` bytes, once encoded, to
+    /// `store`, keeping only a reference to them in Redis. Jobs whose return
+    /// value can't be offloaded fail. Return values are stored inline by
+    /// default.
+    pub fn result_store(mut self, store: Arc<dyn ResultStore>, threshold: usize) -> Self {
+        self.result_store = Some(ResultStoreOptions { store, threshold });
+        self
+    }
+
+    /// What to do with jobs whose data can't be deserialized, see
+    /// `DeserializeErrorPolicy`.
+    pub fn on_deserialize_error(mut self, policy: DeserializeErrorPolicy) -> Self {
+        self.on_deserialize_error = policy;
+        self
+    }
+
+    /// How the worker waits for new jobs once the queue is drained, see
+    /// `FetchStrategy`.
+    pub fn fetch_strategy(mut self, strategy: FetchStrategy) -> Self {
+        self.fetch_strategy = strategy;
+        self
+    }
+
+    /// Queues the worker pulls jobs from instead of the one it's built for,
+    /// highest priority first. A job is only taken from a queue once every
+    /// queue before it is empty (or rate limited), e.g. to always drain
+    /// `urgent` before `bulk`. The queue-level concurrency is read from the
+    /// first queue.
+    pub fn queue_priority(mut self, queues: Vec<String>) -> Self {
+        self.queue_priority = queues;
+        self
+    }
+
+    /// Prefix of the keys of the worker's queues, `bull` by default, which
+    /// must match the one of their producers, see `Queue::prefix`.
+    pub fn prefix(mut self, prefix: Prefix) -> Self {
+        self.prefix = prefix;
+        self
+    }
+}
 
 pub struct Worker<Data, Return>
 where
-    Data: DeserializeOwned + 'static,
-    Return: Serialize + 'static,
+    Data: DeserializeOwned + Send + 'static,
+    Return: Serialize + Send + 'static,
 {
     queue_name: String,
+    name: Option<String>,
     concurrency: usize,
-    active_tasks: usize,
+    events_maxlen: Option<u64>,
+    saturation_threshold: Option<Duration>,
+    limiter: Option<RateLimiterOptions>,
+    promote_delayed: bool,
+    local_limiter: Option<LocalRateLimiter>,
+    semaphore: Option<Arc<Semaphore>>,
+    stalled_interval: Option<Duration>,
+    max_stalled_count: u32,
+    batch_completions: Option<BatchOptions>,
+    blocking: bool,
+    remove_on_complete_immediate: bool,
+    max_reconnect_attempts: Option<u32>,
+    delivery_mode: DeliveryMode,
+    manual_ack: bool,
+    ack_timeout: Option<Duration>,
+    namespace: Option<String>,
+    command_timeout: Option<Duration>,
+    blocking_command_timeout: Option<Duration>,
+    result_store: Option<ResultStoreOptions>,
+    on_deserialize_error: DeserializeErrorPolicy,
+    fetch_strategy: FetchStrategy,
+    /// Notified of new jobs with `FetchStrategy::KeyspaceNotifications`
+    wakeups: Option<Arc<Notify>>,
+    /// Queues jobs are pulled from, highest priority first
+    queues: Vec<WorkerQueue>,
+    prefix: Prefix,
+    handle: WorkerHandle,
     client: Client,
-    receiver: tokio::sync::mpsc::Receiver<TaskEvent>,
-    sender: tokio::sync::mpsc::Sender<TaskEvent>,
+    /// Shared by the processor tasks and the worker's background tasks, see
+    /// `command_client`
+    command_client: TimeoutClient,
+    pool: Arc<ProcessorPool>,
     process_fn: ProcessFn<Data, Return>,
+    handlers: Handlers<Data, Return>,
+    validate_fn: Option<ValidateFn<Data>>,
     token: WorkerToken,
-    drained: bool,
+    /// `concurrency` field of the queue's meta, see `effective_concurrency`
+    global_concurrency: Option<u32>,
+    global_concurrency_read_at: Option<Instant>,
+    callbacks: WorkerCallbacks,
+    backoff_strategies: Arc<CustomStrategies>,
+    /// Scripts run by the processor tasks, mocked in tests
+    commands: Arc<dyn Commands<Data>>,
+    #[cfg(feature = "debug-events")]
+    events: WorkerEvents,
+}
+
+impl<Data, Return> std::fmt::Debug for Worker<Data, Return>
+where
+    Data: DeserializeOwned + Send + 'static,
+    Return: Serialize + Send + 'static,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Worker")
+            .field("id", &self.token.token)
+            .field("name", &self.name)
+            .field("queue_name", &self.queue_name)
+            .field("prefix", &self.get_prefixed_key(""))
+            .field(
+                "queues",
+                &self
+                    .queues
+                    .iter()
+                    .map(|queue| &queue.name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("redis", &redacted_addr(&self.client))
+            .field("concurrency", &self.concurrency)
+            .finish_non_exhaustive()
+    }
 }
 
 impl<JobData, ReturnType> Worker<JobData, ReturnType>
 where
-    JobData: DeserializeOwned + 'static,
-    ReturnType: Serialize + 'static,
+    JobData: DeserializeOwned + Send + 'static,
+    ReturnType: Serialize + Send + 'static,
 {
+    /// Builds a worker for the queue whose keys start with `prefix`, which
+    /// must match its producers' one. On Redis Cluster, the worker stops
+    /// unless the prefix holds a hash tag.
     pub fn new(
         queue_name: String,
         redis_url: String,
+        prefix: impl Into<Prefix>,
         concurrency: usize,
         process_fn: ProcessFn<JobData, ReturnType>,
     ) -> Self {
-        let client = Client::open(redis_url).unwrap();
-        let (sender, receiver) = tokio::sync::mpsc::channel(concurrency);
+        Self::with_options(
+            queue_name,
+            redis_url,
+            WorkerOptions::new()
+                .prefix(prefix.into())
+                .concurrency(concurrency),
+            process_fn,
+        )
+    }
 
-        Worker {
+    pub fn with_options(
+        queue_name: String,
+        redis_url: String,
+        opts: WorkerOptions,
+        process_fn: ProcessFn<JobData, ReturnType>,
+    ) -> Self {
+        Self::from_client(
             queue_name,
-            concurrency,
-            active_tasks: 0,
-            client,
-            receiver,
-            sender,
+            Client::open(redis_url).unwrap(),
+            opts,
             process_fn,
-            token: WorkerToken::new(),
-            drained: false,
-        }
+        )
     }
 
-    fn start_processor_task(&mut self) {
-        let prefix = self.get_prefixed_key("");
-        let token = self.token.next();
-        let mut client = self.client.clone();
-        let sender = self.sender.clone();
-        let process_fn = self.process_fn;
+    /// Builds a worker using the context's client and worker options,
+    /// sharing the context's connections for its commands.
+    pub fn from_context(
+        ctx: &HornetContext,
+        queue_name: String,
+        process_fn: ProcessFn<JobData, ReturnType>,
+    ) -> Self {
+        let opts = ctx.default_worker_options();
+        // Set by the options, the protocol only applies to this worker
+        let shares_commands = opts.protocol.is_none();
+        let mut worker = Self::from_client(queue_name, ctx.client(), opts, process_fn);
 
-        let _ = tokio::spawn(async move {
-            // Move to active script
-            while let Ok(job) = MOVE_TO_ACTIVE.run::<JobData>(
-                &prefix,
-                &mut client,
-                MoveToActiveArgs {
-                    token: token.clone(),
-                    lock_duration: 10_000,
-                },
-            ) {
-                match job {
-                    MoveToActiveReturn::Job(job) => {
-                        match process_fn(&job) {
-                            Ok(result) => {
-                                // Move job to completed
-                                let stringified_result = serde_json::to_string(&result).unwrap();
-
-                                match MOVE_TO_FINISHED.run(
-                                    &prefix,
-                                    &mut client,
-                                    &job.id,
-                                    stringified_result.as_str(),
-                                    MoveToFinishedTarget::Completed,
-                                    MoveToFinishedArgs {
-                                        token: token.clone(),
-                                        keep_jobs: KeepJobs { count: -1 },
-                                        lock_duration: DEFAULT_LOCK_DURATION,
-                                        max_attempts: 1,
-                                        max_metrics_size: 100,
-                                        fail_parent_on_fail: false,
-                                        remove_dependency_on_fail: false,
-                                    },
-                                ) {
-                                    Ok(MoveToFinishedReturn::Ok) => {}
-                                    res => {
-                                        println!("Error moving job to completed: {:?}", res);
-                                    }
-                                }
-                            }
-                            Err(err) => {
-                                // Check if we should retry
-                                if job.attempts_made.unwrap_or(0) + 1 < job.opts.attempts {
-                                    match RETRY_JOB.run(&prefix, &mut client, &job.id, &token) {
-                                        Ok(RetryJobReturn::Ok) => {
-                                            println!("Retrying job");
-                                        }
-                                        res => {
-                                            println!("Error retrying job: {:?}", res);
-                                        }
-                                    }
-                                } else {
-                                    // Move job to failed
-                                    match MOVE_TO_FINISHED.run(
-                                        &prefix,
-                                        &mut client,
-                                        &job.id,
-                                        err.to_string().as_str(),
-                                        MoveToFinishedTarget::Failed,
-                                        MoveToFinishedArgs {
-                                            token: token.clone(),
-                                            keep_jobs: KeepJobs { count: -1 },
-                                            lock_duration: DEFAULT_LOCK_DURATION,
-                                            max_attempts: job.opts.attempts,
-                                            max_metrics_size: 100,
-                                            fail_parent_on_fail: false,
-                                            remove_dependency_on_fail: false,
-                                        },
-                                    ) {
-                                        Ok(MoveToFinishedReturn::Ok) => {}
-                                        res => {
-                                            println!("Error moving job to failed: {:?}", res);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    MoveToActiveReturn::None => {
-                        // No job to process
-                        break;
-                    }
-                }
-            }
+        if shares_commands {
+            worker.command_client = ctx.command_client();
+        }
 
-            // Emits a signal to the worker that it's done processing jobs
-            let _ = sender.send(TaskEvent::Freed).await;
-        });
+        worker
     }
 
-    pub async fn run(&mut self) {
-        let mut connection = self.client.get_connection().unwrap();
+    fn from_client(
+        queue_name: String,
+        mut client: Client,
+        opts: WorkerOptions,
+        process_fn: ProcessFn<JobData, ReturnType>,
+    ) -> Self {
+        if let Some(protocol) = opts.protocol {
+            client = with_protocol(&client, protocol);
+        }
 
-        loop {
-            // Does not clear all the buffer
-            // What if a message is dropped?
-            while self.active_tasks >= self.concurrency {
-                if let Some(TaskEvent::Freed) = self.receiver.recv().await {
-                    self.active_tasks -= 1;
-                    self.drained = true;
-                }
-            }
+        let queue_names = if opts.queue_priority.is_empty() {
+            vec![queue_name]
+        } else {
+            opts.queue_priority
+        };
 
-            if self.drained {
+        Worker {
+            queue_name: queue_names[0].clone(),
+            name: opts.name,
+            concurrency: opts.concurrency,
+            events_maxlen: opts.events_maxlen,
+            saturation_threshold: opts.saturation_threshold,
+            limiter: opts.limiter,
+            promote_delayed: opts.promote_delayed,
+            local_limiter: opts.local_rate_limit.map(LocalRateLimiter::new),
+            semaphore: opts.semaphore,
+            stalled_interval: opts.stalled_interval,
+            max_stalled_count: opts.max_stalled_count,
+            batch_completions: opts.batch_completions,
+            blocking: opts.blocking,
+            remove_on_complete_immediate: opts.remove_on_complete_immediate,
+            max_reconnect_attempts: opts.max_reconnect_attempts,
+            delivery_mode: opts.delivery_mode,
+            manual_ack: opts.manual_ack,
+            ack_timeout: opts.ack_timeout,
+            namespace: opts.namespace,
+            command_timeout: opts.command_timeout,
+            blocking_command_timeout: opts.blocking_command_timeout,
+            result_store: opts.result_store,
+            on_deserialize_error: opts.on_deserialize_error,
+            fetch_strategy: opts.fetch_strategy,
+            wakeups: None,
+            queues: queue_names
+                .into_iter()
+                .map(|name| WorkerQueue::new(name, &opts.prefix))
+                .collect(),
+            prefix: opts.prefix,
+            handle: WorkerHandle::new(),
+            command_client: TimeoutClient::new(client.clone(), opts.command_timeout),
+            client,
+            pool: Arc::new(ProcessorPool::new(opts.concurrency)),
+            process_fn,
+            handlers: Handlers::new(),
+            validate_fn: None,
+            token: WorkerToken::new(),
+            global_concurrency: None,
+            global_concurrency_read_at: None,
+            callbacks: WorkerCallbacks::default(),
+            backoff_strategies: Arc::default(),
+            commands: Arc::new(RedisCommands),
+            #[cfg(feature = "debug-events")]
+            events: WorkerEvents::new(),
+        }
+    }
+
+    /// Registers a callback invoked with the job's timing information every
+    /// time a job is successfully moved to completed.
+    pub fn on_completed(mut self, callback: OutcomeFn) -> Self {
+        self.callbacks.on_completed = Some(callback);
+        self
+    }
+
+    /// Registers a callback invoked with the job's timing information every
+    /// time the process function returns an error. `JobOutcome::will_retry`
+    /// tells failures that will be retried from final ones.
+    pub fn on_failed(mut self, callback: OutcomeFn) -> Self {
+        self.callbacks.on_failed = Some(callback);
+        self
+    }
+
+    /// Registers a callback invoked, with how long the worker has been
+    /// saturated, once every time the worker stays at full concurrency for
+    /// longer than `WorkerOptions::saturation_threshold`.
+    pub fn on_saturated(mut self, callback: SaturatedFn) -> Self {
+        self.callbacks.on_saturated = Some(callback);
+        self
+    }
+
+    /// Registers a callback invoked with the id of every job this worker finds
+    /// stalled and moves back to wait. Each stall is reported once.
+    pub fn on_stalled(mut self, callback: StalledFn) -> Self {
+        self.callbacks.on_stalled = Some(callback);
+        self
+    }
+
+    /// Registers a callback invoked every time the connection the worker
+    /// waits for new jobs on fails. The worker keeps reconnecting on its own.
+    pub fn on_connection_lost(mut self, callback: ConnectionLostFn) -> Self {
+        self.callbacks.on_connection_lost = Some(callback);
+        self
+    }
+
+    /// Registers a callback invoked once the worker reaches Redis and its
+    /// scripts are loaded, before it pulls its first job, and again every
+    /// time its connection is back after `on_connection_lost`, e.g. to log
+    /// that it's online or to back a readiness probe. With
+    /// `FetchStrategy::KeyspaceNotifications`, it's called again once the
+    /// subscription to the notifications is back instead.
+    pub fn on_ready(mut self, callback: ReadyFn) -> Self {
+        self.callbacks.on_ready = Some(callback);
+        self
+    }
+
+    /// Registers a callback invoked once the job has been moved to completed,
+    /// failed or back for a retry, whatever the outcome, including when the
+    /// process function panics. Meant for cleanup such as removing temporary
+    /// files.
+    pub fn after_process(mut self, callback: OutcomeFn) -> Self {
+        self.callbacks.after_process = Some(callback);
+        self
+    }
+
+    /// Registers a backoff strategy for the jobs whose `backoff.type` is
+    /// `name`, BullMQ's `settings.backoffStrategy`. See `CustomBackoffFn`.
+    /// Jobs using a strategy that isn't registered are failed instead of
+    /// retried.
+    pub fn backoff_strategy(mut self, name: &str, strategy: CustomBackoffFn) -> Self {
+        Arc::make_mut(&mut self.backoff_strategies).insert(name.to_string(), strategy);
+        self
+    }
+
+    /// Unique id of the worker, the key of its entry in the queue's `workers`
+    /// hash.
+    pub fn id(&self) -> &str {
+        &self.token.token
+    }
+
+    /// Number of processor tasks currently running, at most the concurrency.
+    pub fn active_count(&self) -> usize {
+        self.handle.active_count()
+    }
+
+    /// Returns a handle to pause and resume the worker while `run` is
+    /// running, e.g. from another task.
+    pub fn handle(&self) -> WorkerHandle {
+        self.handle.clone()
+    }
+
+    /// Returns the handlers overriding the worker's process function for
+    /// some job names, which can be changed while `run` is running.
+    pub fn handlers(&self) -> Handlers<JobData, ReturnType> {
+        self.handlers.clone()
+    }
+
+    /// Subscribes to the worker's internal transitions: jobs pulled, handlers
+    /// started and finished, slots freed, time spent waiting for jobs...
+    /// Events emitted before subscribing are not received.
+    #[cfg(feature = "debug-events")]
+    pub fn events(&self) -> tokio::sync::broadcast::Receiver<WorkerEvent> {
+        self.events.subscribe()
+    }
+
+    /// See `WorkerHandle::pause`.
+    pub fn pause(&self) {
+        self.handle.pause();
+    }
+
+    /// See `WorkerHandle::resume`.
+    pub fn resume(&self) {
+        self.handle.resume();
+    }
+
+    /// Spawns the processor task at `index` in the pool, which fetches jobs
+    /// while `index` is below the effective concurrency.
+    fn spawn_processor_task(&mut self, index: usize) -> JoinHandle<()> {
+        let queues = self.queues.clone();
+        let token = self.token.next();
+        let mut client = self.command_client();
+        let pool = self.pool.clone();
+        let process_fn = self.process_fn;
+        let handlers = self.handlers.clone();
+        let validate_fn = self.validate_fn;
+        let callbacks = self.callbacks;
+        let backoff_strategies = self.backoff_strategies.clone();
+        let result_store = self.result_store.clone();
+        let on_deserialize_error = self.on_deserialize_error;
+        let limiter = self.limiter;
+        let name = self.name.clone();
+        let namespace = self.namespace.clone();
+        let local_limiter = self.local_limiter.clone();
+        let semaphore = self.semaphore.clone();
+        let blocking = self.blocking;
+        let remove_on_complete_immediate = self.remove_on_complete_immediate;
+        let at_most_once = self.delivery_mode == DeliveryMode::AtMostOnce;
+        let manual_ack = self.manual_ack;
+        let ack_timeout = self.ack_timeout;
+        let handle = self.handle.clone();
+        let commands = self.commands.clone();
+        #[cfg(feature = "debug-events")]
+        let events = self.events.clone();
+
+        tokio::spawn(async move {
+            // Jobs removed right away are not locked
+            let fetch_token = if at_most_once {
+                "0".to_string()
+            } else {
+                token.clone()
+            };
+            // Jobs fetched on completion would skip the local rate limit, or
+            // belong to another namespace
+            let fetch_next = local_limiter.is_none() && namespace.is_none();
+
+            loop {
+                // Enabled before fetching, so a job added in the meantime is not
+                // missed
+                let jobs_added = pool.jobs_added.notified();
+                tokio::pin!(jobs_added);
+                jobs_added.as_mut().enable();
+
+                if handle.is_closing() {
+                    break;
+                }
+
+                if index >= pool.concurrency.load(Ordering::Relaxed) || !handle.is_fetching() {
+                    jobs_added.await;
+                    continue;
+                }
+
+                handle.active_tasks.fetch_add(1, Ordering::Relaxed);
+                let mut rate_limit_ttl = 0;
+
+                'fetch: loop {
+                    // Lowered while the task was processing a job
+                    if index >= pool.concurrency.load(Ordering::Relaxed) {
+                        break;
+                    }
+
+                    // Waits for a free unit, and behind the jobs waiting for
+                    // several, before pulling another job
+                    drop(pool.capacity.acquire().await);
+
+                    // Taken before fetching the job so it's not locked while waiting
+                    if let Some(local_limiter) = &local_limiter {
+                        local_limiter.acquire().await;
+                    }
+
+                    if !handle.is_fetching() {
+                        break;
+                    }
+
+                    let mut next = MoveToActiveReturn::None;
+                    let mut fetched_from = 0;
+
+                    rate_limit_ttl = 0;
+
+                    // Move to active script, on each queue in order of priority
+                    // until one has a job
+                    for (i, queue) in queues.iter().enumerate() {
+                        let res = match commands.move_to_active(
+                            &queue.prefix,
+                            &mut client,
+                            MoveToActiveArgs {
+                                token: fetch_token.clone(),
+                                lock_duration: DEFAULT_LOCK_DURATION as u32,
+                                name: name.clone(),
+                                limiter,
+                                namespace: namespace.clone(),
+                            },
+                        ) {
+                            Ok(res) => res,
+                            Err(err) => {
+                                log::warning!("Error fetching the next job: {:?}", err);
+                                break 'fetch;
+                            }
+                        };
+
+                        match res {
+                            MoveToActiveReturn::None => {}
+                            // Lower priority queues may still have jobs
+                            MoveToActiveReturn::RateLimited(ttl) => {
+                                rate_limit_ttl = match rate_limit_ttl {
+                                    0 => ttl,
+                                    shortest => shortest.min(ttl),
+                                };
+                            }
+                            res => {
+                                next = res;
+                                fetched_from = i;
+                                break;
+                            }
+                        }
+                    }
+
+                    let queue = &queues[fetched_from];
+                    let prefix = &queue.prefix;
+                    let active_jobs = &queue.active_jobs;
+                    let batcher = &queue.batcher;
+                    // The next job of a lower priority queue could skip jobs added
+                    // to the queues before it in the meantime
+                    let fetch_next = fetch_next && fetched_from == 0;
+
+                    // Keeps processing the jobs fetched along with the previous
+                    // job's completion, without a separate round trip
+                    loop {
+                        if let MoveToActiveReturn::Job(full_job) = &mut next {
+                            full_job.cancellation_token = handle.closing.clone();
+
+                            #[cfg(feature = "debug-events")]
+                            events.emit(WorkerEvent::PulledJob {
+                                queue: queue.name.clone(),
+                                job_id: full_job.id.clone(),
+                            });
+                        }
+
+                        match next {
+                            MoveToActiveReturn::Job(full_job) if at_most_once => {
+                                let job = JobSummary::of(&full_job);
+
+                                match commands.remove_job(prefix, &mut client, &job.id, false) {
+                                    Ok(true) => {}
+                                    // Losing the job beats risking processing it twice
+                                    res => {
+                                        log::warning!("Error removing job {}: {:?}", job.id, res);
+                                        continue 'fetch;
+                                    }
+                                }
+
+                                // Held until the job is processed
+                                let _units = pool
+                                    .capacity
+                                    .acquire_many(full_job.opts.units(pool.capacity_size))
+                                    .await;
+
+                                #[cfg(feature = "metrics")]
+                                crate::metrics::job_started(&queue.name);
+
+                                let handler = handlers.resolve(&full_job.name, process_fn);
+                                let permit = acquire_permit(semaphore.as_deref()).await;
+                                let started_at = Instant::now();
+
+                                #[cfg(feature = "debug-events")]
+                                events.emit(WorkerEvent::StartedHandler {
+                                    job_id: job.id.clone(),
+                                });
+
+                                let (res, _) =
+                                    execute(handler, validate_fn, blocking, *full_job).await;
+                                drop(permit);
+
+                                #[cfg(feature = "debug-events")]
+                                events.emit(worker_events::handler_finished(&job.id, &res));
+                                let outcome = job.outcome(
+                                    started_at,
+                                    res.as_ref().err().map(|err| err.to_string()),
+                                );
+
+                                if res.is_ok() {
+                                    #[cfg(feature = "metrics")]
+                                    crate::metrics::job_completed(&queue.name, &outcome);
+
+                                    if let Some(on_completed) = callbacks.on_completed {
+                                        on_completed(&outcome);
+                                    }
+                                } else {
+                                    #[cfg(feature = "metrics")]
+                                    crate::metrics::job_failed(&queue.name, &outcome);
+
+                                    if let Some(on_failed) = callbacks.on_failed {
+                                        on_failed(&outcome);
+                                    }
+                                }
+
+                                if let Some(after_process) = callbacks.after_process {
+                                    after_process(&outcome);
+                                }
+
+                                #[cfg(feature = "metrics")]
+                                crate::metrics::job_ended(&queue.name);
+
+                                continue 'fetch;
+                            }
+                            MoveToActiveReturn::Job(mut full_job) => {
+                                let job = JobSummary::of(&full_job);
+                                let idempotency_key = full_job.idempotency_key.clone();
+                                let mut fetched: Option<MoveToActiveReturn<JobData>> = None;
+                                // Batched jobs are untracked once flushed
+                                let mut batched = false;
+
+                                active_jobs.track(&job.id, &token);
+
+                                // Held until the job is processed, the job being
+                                // locked in the meantime
+                                let _units = pool
+                                    .capacity
+                                    .acquire_many(full_job.opts.units(pool.capacity_size))
+                                    .await;
+                                // Waiting for the units is not processing
+                                let mut started_at = Instant::now();
+
+                                let acked = manual_ack.then(|| {
+                                    let (ack_handle, acked) = AckHandle::new(&job.id);
+                                    *full_job.ack.lock().unwrap() = Some(ack_handle);
+                                    acked
+                                });
+
+                                #[cfg(feature = "metrics")]
+                                crate::metrics::job_started(&queue.name);
+
+                                let (res, retryable) = match full_job.recorded_result.take() {
+                                    // An earlier attempt succeeded but the job was
+                                    // not completed, e.g. its worker died
+                                    Some((key, return_value)) => {
+                                        log::debug!(
+                                            "Job {} already succeeded with idempotency key {}, \
+                                         completing it without processing it again",
+                                            job.id,
+                                            key
+                                        );
+
+                                        (Ok(return_value), false)
+                                    }
+                                    None => {
+                                        let permit = acquire_permit(semaphore.as_deref()).await;
+                                        // Waiting for the permit is not processing
+                                        started_at = Instant::now();
+
+                                        #[cfg(feature = "debug-events")]
+                                        events.emit(WorkerEvent::StartedHandler {
+                                            job_id: job.id.clone(),
+                                        });
+
+                                        let res = execute_encoded(
+                                            handlers.resolve(&job.name, process_fn),
+                                            validate_fn,
+                                            blocking,
+                                            result_store.as_ref(),
+                                            &client,
+                                            *full_job,
+                                        )
+                                        .await;
+                                        drop(permit);
+
+                                        #[cfg(feature = "debug-events")]
+                                        events
+                                            .emit(worker_events::handler_finished(&job.id, &res.0));
+
+                                        (wait_for_ack(res.0, acked, ack_timeout).await, res.1)
+                                    }
+                                };
+                                let registered_key = idempotency_key.lock().unwrap().take();
+
+                                if let (Ok(return_value), Some(key)) = (&res, registered_key) {
+                                    match RECORD_IDEMPOTENCY_KEY.run(
+                                        prefix,
+                                        &mut client,
+                                        &job.id,
+                                        &key,
+                                        return_value,
+                                    ) {
+                                        Ok(true) => {}
+                                        res => log::warning!(
+                                            "Error recording idempotency key of job {}: {:?}",
+                                            job.id,
+                                            res
+                                        ),
+                                    }
+                                }
+                                let mut outcome = job.outcome(
+                                    started_at,
+                                    res.as_ref().err().map(|err| err.to_string()),
+                                );
+
+                                match res {
+                                    Ok(return_value) => {
+                                        // Move job to completed
+                                        let args = MoveToFinishedArgs {
+                                            token: token.clone(),
+                                            keep_jobs: if remove_on_complete_immediate {
+                                                KeepJobs::count(0)
+                                            } else {
+                                                job.opts.keep_completed()
+                                            },
+                                            lock_duration: DEFAULT_LOCK_DURATION,
+                                            max_attempts: 1,
+                                            max_metrics_size: 100,
+                                            fail_parent_on_fail: false,
+                                            remove_dependency_on_fail: false,
+                                            // A batched completion can't hand the next job back
+                                            fetch_next: fetch_next
+                                                && batcher.is_none()
+                                                && handle.is_fetching(),
+                                            name: name.clone(),
+                                            limiter,
+                                        };
+
+                                        if let Some(batcher) = &batcher {
+                                            batched = true;
+                                            batcher.push(PendingCompletion {
+                                                job: FinishedJob {
+                                                    job_id: job.id.clone(),
+                                                    return_value,
+                                                    args,
+                                                },
+                                                outcome: outcome.clone(),
+                                            });
+                                        } else {
+                                            match commands.move_to_finished(
+                                                prefix,
+                                                &mut client,
+                                                &job.id,
+                                                &return_value,
+                                                MoveToFinishedTarget::Completed,
+                                                args,
+                                            ) {
+                                                Ok(
+                                                    res @ (MoveToFinishedReturn::Ok
+                                                    | MoveToFinishedReturn::Next(_)),
+                                                ) => {
+                                                    #[cfg(feature = "debug-events")]
+                                                    events.emit(WorkerEvent::MovedToCompleted {
+                                                        job_id: job.id.clone(),
+                                                    });
+
+                                                    #[cfg(feature = "metrics")]
+                                                    crate::metrics::job_completed(
+                                                        &queue.name,
+                                                        &outcome,
+                                                    );
+
+                                                    if let Some(on_completed) =
+                                                        callbacks.on_completed
+                                                    {
+                                                        on_completed(&outcome);
+                                                    }
+
+                                                    if let MoveToFinishedReturn::Next(next) = res {
+                                                        fetched = Some(next);
+                                                    }
+                                                }
+                                                // Parents are completed once their last child is
+                                                Ok(
+                                                    MoveToFinishedReturn::JobHasPendingDependencies,
+                                                ) => {}
+                                                res => {
+                                                    log::warning!(
+                                                        "Error moving job to completed: {:?}",
+                                                        res
+                                                    );
+                                                }
+                                            }
+                                        }
+                                    }
+                                    Err(mut err) => {
+                                        // Check if we should retry
+                                        let attempts_made = outcome.attempts_made;
+
+                                        let delay =
+                                            if retryable && attempts_made < job.opts.attempts {
+                                                backoff::retry_delay(
+                                                    &job.opts,
+                                                    attempts_made,
+                                                    &err,
+                                                    &backoff_strategies,
+                                                )
+                                            } else {
+                                                Ok(None)
+                                            };
+                                        let delay = match delay {
+                                            Ok(delay) => delay,
+                                            // Failed with the reason it's not retried
+                                            Err(unknown) => {
+                                                err = err.context(unknown.to_string());
+                                                outcome.failed_reason = Some(err.to_string());
+                                                None
+                                            }
+                                        };
+
+                                        outcome.retry_in = delay.map(Duration::from_millis);
+
+                                        #[cfg(feature = "metrics")]
+                                        crate::metrics::job_failed(&queue.name, &outcome);
+
+                                        if let Some(on_failed) = callbacks.on_failed {
+                                            on_failed(&outcome);
+                                        }
+
+                                        // The error and its causes stand for the stack trace
+                                        let mut stacktrace = job.stacktrace.clone();
+                                        job.opts
+                                            .push_stacktrace(&mut stacktrace, format!("{:?}", err));
+
+                                        if let Err(err) = SAVE_STACKTRACE.run(
+                                            prefix,
+                                            &mut client,
+                                            &job.id,
+                                            &stacktrace,
+                                            &err.to_string(),
+                                        ) {
+                                            log::warning!("Error saving stack trace: {:?}", err);
+                                        }
+
+                                        if let Some(delay) = delay {
+                                            // Jobs with a backoff wait in the delayed set
+                                            if delay > 0 {
+                                                match commands.move_to_delayed(
+                                                    prefix,
+                                                    &mut client,
+                                                    &job.id,
+                                                    &token,
+                                                    delay,
+                                                ) {
+                                                    Ok(MoveToDelayedReturn::Ok) => {
+                                                        log::debug!("Retrying job in {}ms", delay);
+                                                    }
+                                                    res => {
+                                                        log::warning!(
+                                                            "Error delaying job: {:?}",
+                                                            res
+                                                        );
+                                                    }
+                                                }
+                                            } else {
+                                                match commands.retry_job(
+                                                    prefix,
+                                                    &mut client,
+                                                    &job.id,
+                                                    &token,
+                                                ) {
+                                                    Ok(RetryJobReturn::Ok) => {
+                                                        log::debug!("Retrying job");
+                                                    }
+                                                    res => {
+                                                        log::warning!(
+                                                            "Error retrying job: {:?}",
+                                                            res
+                                                        );
+                                                    }
+                                                }
+                                            }
+                                        } else {
+                                            // Move job to failed
+                                            match commands.move_to_finished(
+                                                prefix,
+                                                &mut client,
+                                                &job.id,
+                                                err.to_string().as_bytes(),
+                                                MoveToFinishedTarget::Failed,
+                                                MoveToFinishedArgs {
+                                                    token: token.clone(),
+                                                    keep_jobs: job.opts.keep_failed(),
+                                                    lock_duration: DEFAULT_LOCK_DURATION,
+                                                    max_attempts: job.opts.attempts,
+                                                    max_metrics_size: 100,
+                                                    fail_parent_on_fail: false,
+                                                    remove_dependency_on_fail: false,
+                                                    fetch_next: fetch_next && handle.is_fetching(),
+                                                    name: name.clone(),
+                                                    limiter,
+                                                },
+                                            ) {
+                                                Ok(MoveToFinishedReturn::Ok) => {}
+                                                Ok(MoveToFinishedReturn::Next(next)) => {
+                                                    fetched = Some(next);
+                                                }
+                                                // Parents can't fail before their children
+                                                // finish, the stalled jobs checker gives it
+                                                // back once its lock expires
+                                                Ok(
+                                                    MoveToFinishedReturn::JobHasPendingDependencies,
+                                                ) => {
+                                                    log::warning!(
+                                                        "Job {} failed with pending dependencies",
+                                                        job.id
+                                                    );
+                                                }
+                                                res => {
+                                                    log::warning!(
+                                                        "Error moving job to failed: {:?}",
+                                                        res
+                                                    );
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+
+                                if !batched {
+                                    active_jobs.untrack(&job.id);
+
+                                    if let Some(after_process) = callbacks.after_process {
+                                        after_process(&outcome);
+                                    }
+                                }
+
+                                #[cfg(feature = "metrics")]
+                                crate::metrics::job_ended(&queue.name);
+
+                                match fetched {
+                                    Some(fetched) => next = fetched,
+                                    None => continue 'fetch,
+                                }
+                            }
+                            MoveToActiveReturn::Undecodable {
+                                job_id,
+                                opts,
+                                reason,
+                            } => {
+                                let reason = format!("Could not deserialize job data: {}", reason);
+
+                                if on_deserialize_error == DeserializeErrorPolicy::Crash {
+                                    log::warning!("Error processing job {}: {}", job_id, reason);
+                                    break 'fetch;
+                                }
+
+                                // Retrying would fail the same way
+                                match commands.move_to_finished(
+                                    prefix,
+                                    &mut client,
+                                    &job_id,
+                                    reason.as_bytes(),
+                                    MoveToFinishedTarget::Failed,
+                                    MoveToFinishedArgs {
+                                        token: fetch_token.clone(),
+                                        keep_jobs: opts.keep_failed(),
+                                        lock_duration: DEFAULT_LOCK_DURATION,
+                                        max_attempts: 1,
+                                        max_metrics_size: 100,
+                                        fail_parent_on_fail: false,
+                                        remove_dependency_on_fail: false,
+                                        fetch_next: fetch_next && handle.is_fetching(),
+                                        name: name.clone(),
+                                        limiter,
+                                    },
+                                ) {
+                                    Ok(MoveToFinishedReturn::Next(fetched)) => next = fetched,
+                                    Ok(MoveToFinishedReturn::Ok) => continue 'fetch,
+                                    res => {
+                                        log::warning!("Error moving job to failed: {:?}", res);
+                                        continue 'fetch;
+                                    }
+                                }
+                            }
+                            MoveToActiveReturn::RateLimited(ttl) => {
+                                rate_limit_ttl = ttl;
+                                break 'fetch;
+                            }
+                            MoveToActiveReturn::None => {
+                                // No job to process
+                                break 'fetch;
+                            }
+                        }
+                    }
+                }
+
+                // The last token taken was not used by any job
+                if let Some(local_limiter) = &local_limiter {
+                    local_limiter.release().await;
+                }
+
+                // Give the slot back once the rate limit resets
+                if rate_limit_ttl > 0 {
+                    tokio::time::sleep(Duration::from_millis(rate_limit_ttl)).await;
+                }
+
+                handle.active_tasks.fetch_sub(1, Ordering::Relaxed);
+                pool.task_idle.notify_one();
+
+                #[cfg(feature = "debug-events")]
+                events.emit(WorkerEvent::SlotFreed);
+
+                jobs_added.await;
+            }
+        })
+    }
+
+    /// Processes jobs forever, logging errors and retrying, e.g. reconnecting
+    /// to Redis, until they go away. See `try_run` to stop on errors that
+    /// won't.
+    pub async fn run(&mut self) {
+        if let Err(err) = self.process(false).await {
+            log::warning!("Worker stopped: {:?}", err);
+        }
+    }
+
+    /// Same as `run`, but returns an error instead of retrying forever when
+    /// the worker can't recover on its own: its scripts can't be loaded, Redis
+    /// rejects its credentials or it ran out of `max_reconnect_attempts`. It
+    /// lets a supervisor restart or crash the process with the root cause.
+    pub async fn try_run(&mut self) -> Result<()> {
+        // Fails fast on unreachable servers and wrong credentials
+        redis::cmd("PING").query::<String>(&mut self.command_client())?;
+
+        self.process(true).await
+    }
+
+    /// Same as `run`, closing the worker (see `WorkerHandle::close`) on
+    /// SIGINT or SIGTERM, e.g. when a deploy stops the process, so the jobs
+    /// being processed are finished instead of left to stall. Returns once
+    /// they are.
+    pub async fn run_until_ctrl_c(&mut self) {
+        let handle = self.handle();
+
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            log::debug!("Closing worker, waiting for the active jobs to finish");
+            handle.close();
+        });
+
+        self.run().await;
+    }
+
+    async fn process(&mut self, stop_on_fatal_error: bool) -> Result<()> {
+        // Before `on_ready`, as the worker can't process anything without them
+        check_scripts()?;
+
+        let command_client = self.command_client();
+
+        match check_cluster_slots(&mut command_client.clone(), &self.prefix) {
+            Err(err) if err.downcast_ref::<redis::RedisError>().is_some() => {
+                log::warning!("Error checking the cluster mode: {:?}", err);
+            }
+            res => res?,
+        }
+
+        let mut connection = BlockingConnection::new(
+            self.client.clone(),
+            self.command_timeout,
+            self.blocking_command_timeout,
+        );
+
+        if let Some(opts) = self.batch_completions {
+            for queue in self.queues.iter_mut() {
+                let batcher = CompletionBatcher::new(
+                    opts,
+                    queue.prefix.clone(),
+                    command_client.clone(),
+                    queue.active_jobs.clone(),
+                    self.callbacks.on_completed,
+                    self.callbacks.after_process,
+                    #[cfg(feature = "metrics")]
+                    queue.name.clone(),
+                );
+
+                batcher.spawn_flusher();
+                queue.batcher = Some(batcher);
+            }
+        }
+
+        if let Some(events_maxlen) = self.events_maxlen {
+            for queue in self.queues.iter() {
+                if let Err(err) = set_events_maxlen(&mut self.client, &queue.prefix, events_maxlen)
+                {
+                    log::warning!("Error setting events max length: {:?}", err);
+                }
+            }
+        }
+
+        if self.fetch_strategy == FetchStrategy::KeyspaceNotifications {
+            self.wakeups = Some(keyspace_notifications::spawn(
+                self.queues
+                    .iter()
+                    .map(|queue| queue.prefix.clone())
+                    .collect(),
+                self.client.clone(),
+                self.callbacks.on_ready,
+            ));
+        }
+
+        // The worker is registered and its jobs are locked on every queue
+        for queue in self.queues.iter() {
+            lock_manager::spawn(
+                queue.prefix.clone(),
+                command_client.clone(),
+                &queue.active_jobs,
+                LockManagerOptions {
+                    lock_duration: DEFAULT_LOCK_DURATION,
+                    stalled_interval: self.stalled_interval,
+                    max_stalled_count: self.max_stalled_count,
+                    on_stalled: self.callbacks.on_stalled,
+                    worker: WorkerInfo::new(self.id().to_string(), self.name.clone()),
+                    result_store: self
+                        .result_store
+                        .as_ref()
+                        .map(|options| options.store.clone()),
+                },
+            );
+        }
+
+        // Jobs are only pulled once Redis answers
+        loop {
+            if self.handle.is_closing() {
+                self.drain(vec![]).await;
+
+                return Ok(());
+            }
+
+            match connection.ping().await {
+                Ok(()) => break,
+                Err(err) => self.connection_lost(err, stop_on_fatal_error)?,
+            }
+        }
+
+        if let Some(on_ready) = self.callbacks.on_ready {
+            on_ready();
+        }
+
+        self.pool
+            .concurrency
+            .store(self.concurrency(), Ordering::Relaxed);
+
+        let tasks: Vec<JoinHandle<()>> = (0..self.concurrency)
+            .map(|index| self.spawn_processor_task(index))
+            .collect();
+
+        loop {
+            if !self.handle.is_fetching() {
+                self.wait_while_paused().await;
+                self.pool.jobs_added.notify_waiters();
+            }
+
+            if self.handle.is_closing() {
+                self.drain(tasks).await;
+
+                return Ok(());
+            }
+
+            self.refresh_global_concurrency();
+
+            if self.active_count() >= self.concurrency() {
+                self.wait_for_free_slot().await;
+            }
+
+            let timeout = if self.promote_delayed {
+                for queue in self.queues.iter() {
+                    // Adds a marker if any job was promoted, so we don't block
+                    if let Err(err) =
+                        PROMOTE_DELAYED_JOBS.run(&queue.prefix, &mut self.command_client())
+                    {
+                        log::warning!("Error promoting delayed jobs: {:?}", err);
+                    }
+                }
+
+                DELAYED_PROMOTION_INTERVAL
+            } else {
+                MARKER_FALLBACK_INTERVAL
+            };
+
+            #[cfg(feature = "debug-events")]
+            let blocked_at = Instant::now();
+
+            #[cfg(feature = "debug-events")]
+            self.events.emit(WorkerEvent::Blocked);
+
+            let woken = match &self.wakeups {
+                Some(wakeups) => Ok(tokio::time::timeout(
+                    Duration::from_secs_f64(timeout),
+                    wakeups.notified(),
+                )
+                .await
+                .is_ok()),
                 // Marker is used to notify worker of new jobs
-                if let Err(_) = connection.bzpopmin::<String, (String, String, f64)>(
-                    self.get_prefixed_key("marker"),
-                    10000.,
-                ) {
+                None => {
+                    let markers: Vec<String> = self
+                        .queues
+                        .iter()
+                        .map(|queue| format!("{}marker", queue.prefix))
+                        .collect();
+
+                    let was_lost = connection.is_lost();
+                    let woken = connection.bzpopmin(&markers, timeout).await;
+
+                    if let (Ok(_), true, Some(on_ready)) =
+                        (&woken, was_lost, self.callbacks.on_ready)
+                    {
+                        on_ready();
+                    }
+
+                    woken
+                }
+            };
+
+            #[cfg(feature = "debug-events")]
+            self.events.emit(WorkerEvent::Woke {
+                blocked_for: blocked_at.elapsed(),
+            });
+
+            match woken {
+                Ok(true) => {}
+                // Jobs added without a marker would otherwise wait forever
+                Ok(false) if self.has_waiting_jobs() => {}
+                Ok(false) => continue,
+                Err(err) => {
+                    self.connection_lost(err, stop_on_fatal_error)?;
+
                     continue;
                 }
+            }
+
+            self.pool.jobs_added.notify_waiters();
+        }
+    }
+
+    /// Reports a failure of the blocking connection, which is retried unless
+    /// it can't recover and `stop_on_fatal_error` is set, see `try_run`.
+    fn connection_lost(&self, err: ConnectionLost, stop_on_fatal_error: bool) -> Result<()> {
+        log::warning!("{}", err);
+
+        if let Some(on_connection_lost) = self.callbacks.on_connection_lost {
+            on_connection_lost(&err);
+        }
+
+        let fatal = err.source.kind() == redis::ErrorKind::AuthenticationFailed
+            || self
+                .max_reconnect_attempts
+                .is_some_and(|max| err.failures >= max);
+
+        if stop_on_fatal_error && fatal {
+            return Err(err.into());
+        }
 
-                self.drained = false;
+        Ok(())
+    }
+
+    /// Maximum number of jobs processed at once: the worker's concurrency,
+    /// lowered to the queue-level `concurrency` of the queue's meta if set.
+    /// Operators can set the latter, e.g. with `Queue::set_global_concurrency`,
+    /// to throttle every worker of the queue at once.
+    pub fn concurrency(&self) -> usize {
+        effective_concurrency(self.concurrency, self.global_concurrency)
+    }
+
+    /// Reads the queue-level concurrency again if it was last read more than
+    /// `GLOBAL_CONCURRENCY_REFRESH_INTERVAL` ago.
+    fn refresh_global_concurrency(&mut self) {
+        if self
+            .global_concurrency_read_at
+            .is_some_and(|read_at| read_at.elapsed() < GLOBAL_CONCURRENCY_REFRESH_INTERVAL)
+        {
+            return;
+        }
+
+        self.global_concurrency_read_at = Some(Instant::now());
+
+        match redis::cmd("HGET")
+            .arg(self.get_prefixed_key("meta"))
+            .arg("concurrency")
+            .query::<Option<u32>>(&mut self.command_client())
+        {
+            Ok(global_concurrency) => self.global_concurrency = global_concurrency,
+            Err(err) => log::warning!("Error reading the queue's concurrency: {:?}", err),
+        }
+
+        let previous = self
+            .pool
+            .concurrency
+            .swap(self.concurrency(), Ordering::Relaxed);
+
+        // Tasks past the previous concurrency wait to be woken up
+        if self.concurrency() > previous {
+            self.pool.jobs_added.notify_waiters();
+        }
+    }
+
+    /// Waits until a processor task runs out of jobs, warning once if that
+    /// takes longer than the saturation threshold.
+    async fn wait_for_free_slot(&mut self) {
+        let saturated_since = Instant::now();
+        let mut warned = self.saturation_threshold.is_none();
+
+        while self.active_count() >= self.concurrency() && !self.handle.is_closing() {
+            match self.saturation_threshold {
+                Some(threshold) if !warned => {
+                    let remaining = threshold.saturating_sub(saturated_since.elapsed());
+
+                    if tokio::time::timeout(remaining, self.pool.task_idle.notified())
+                        .await
+                        .is_err()
+                    {
+                        warned = true;
+                        self.warn_saturated(saturated_since.elapsed());
+                    }
+                }
+                _ => self.pool.task_idle.notified().await,
             }
+        }
+    }
+
+    /// Waits until the worker is resumed or closed.
+    async fn wait_while_paused(&mut self) {
+        while !self.handle.is_fetching() && !self.handle.is_closing() {
+            self.handle.wake.notified().await;
+        }
+    }
+
+    /// Wakes the processor tasks up so they notice the worker is closing,
+    /// waits for them to finish their jobs, then flushes the completions they
+    /// left in the batcher.
+    async fn drain(&mut self, tasks: Vec<JoinHandle<()>>) {
+        self.pool.jobs_added.notify_waiters();
+
+        for task in tasks {
+            let _ = task.await;
+        }
+
+        for batcher in self
+            .queues
+            .iter()
+            .filter_map(|queue| queue.batcher.as_ref())
+        {
+            batcher.flush();
+        }
+    }
+
+    fn warn_saturated(&self, saturated_for: Duration) {
+        log::warning!(
+            "Worker for queue {} has been at full concurrency ({}) for {:?}",
+            self.queue_name,
+            self.concurrency(),
+            saturated_for
+        );
+
+        if let Some(on_saturated) = self.callbacks.on_saturated {
+            on_saturated(saturated_for);
+        }
+    }
+
+    /// Whether jobs are waiting to be fetched, whether or not a marker was
+    /// added for them.
+    fn has_waiting_jobs(&self) -> bool {
+        let mut pipe = redis::pipe();
+
+        for queue in self.queues.iter() {
+            pipe.llen(format!("{}wait", queue.prefix))
+                .zcard(format!("{}prioritized", queue.prefix));
+        }
 
-            self.active_tasks += 1;
-            self.start_processor_task();
+        let res: RedisResult<Vec<u64>> = pipe.query(&mut self.command_client());
+
+        match res {
+            Ok(counts) => counts.iter().sum::<u64>() > 0,
+            Err(err) => {
+                log::warning!("Error checking for waiting jobs: {:?}", err);
+                false
+            }
         }
     }
 
+    /// Client for the worker's non-blocking commands, see
+    /// `WorkerOptions::command_timeout`. Its clones share their idle
+    /// connections, so the processor tasks don't open one per command.
+    fn command_client(&self) -> TimeoutClient {
+        self.command_client.clone()
+    }
+
     fn get_prefixed_key(&self, key: &str) -> String {
-        format!("bull:{}:{}", self.queue_name, key)
+        format!("{}{}", self.queues[0].prefix, key)
+    }
+}
+
+impl<Data, Return> Worker<Data, Return>
+where
+    Data: DeserializeOwned + Send + crate::job::JobData + 'static,
+    Return: Serialize + Send + 'static,
+{
+    /// Checks every job's data with `JobData::validate` before processing it.
+    /// Jobs failing the check are moved to failed with the validation error,
+    /// without being retried.
+    pub fn validate_data(mut self) -> Self {
+        self.validate_fn = Some(|data: &Data| data.validate());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        commands::{Call, MockCommands},
+        job::JobBuilder,
+    };
+    use std::sync::Mutex;
+
+    fn panicking_processor(_job: Job<()>) -> Result<()> {
+        panic!("boom")
+    }
+
+    fn failing_processor(_job: Job<u32>) -> Result<()> {
+        Err(anyhow!("boom"))
+    }
+
+    fn doubling_processor(job: Job<u32>) -> Result<u32> {
+        Ok(job.data * 2)
+    }
+
+    fn fetched(id: &str, attempts: u32, data: u32) -> MoveToActiveReturn<u32> {
+        MoveToActiveReturn::Job(Box::new(
+            JobBuilder::new()
+                .id(id.to_string())
+                .name("test".to_string())
+                .data(data)
+                .opts(format!(r#"{{"attempts":{}}}"#, attempts))
+                .build(),
+        ))
+    }
+
+    fn mocked_worker<Return: Serialize + Send + 'static>(
+        process_fn: ProcessFn<u32, Return>,
+        commands: Arc<MockCommands<u32>>,
+    ) -> Worker<u32, Return> {
+        // Nothing listens there, the commands left unmocked fail
+        let mut worker = Worker::new(
+            "mocked".to_string(),
+            "redis://127.0.0.1:1".to_string(),
+            Prefix::default(),
+            1,
+            process_fn,
+        );
+
+        worker.commands = commands;
+        worker
+    }
+
+    /// Runs a processor task until it runs out of jobs.
+    async fn run_processor_task<Return: Serialize + Send + 'static>(
+        worker: &mut Worker<u32, Return>,
+    ) {
+        let task = worker.spawn_processor_task(0);
+
+        tokio::time::timeout(Duration::from_secs(5), worker.pool.task_idle.notified())
+            .await
+            .unwrap();
+
+        worker.handle.close();
+        worker.pool.jobs_added.notify_waiters();
+        task.await.unwrap();
+    }
+
+    /// Runs a processor task against `commands`, until it runs out of jobs.
+    async fn process_with<Return: Serialize + Send + 'static>(
+        process_fn: ProcessFn<u32, Return>,
+        commands: Arc<MockCommands<u32>>,
+    ) -> Vec<Call> {
+        run_processor_task(&mut mocked_worker(process_fn, commands.clone())).await;
+
+        commands.calls()
+    }
+
+    #[cfg(feature = "debug-events")]
+    #[tokio::test]
+    async fn emits_state_transitions() {
+        let commands = MockCommands::new().job(fetched("1", 1, 1));
+        let mut worker = mocked_worker(doubling_processor, Arc::new(commands));
+        let mut events = worker.events();
+
+        run_processor_task(&mut worker).await;
+
+        let job_id = "1".to_string();
+        let mut received = vec![];
+
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+
+        assert_eq!(
+            received,
+            [
+                WorkerEvent::PulledJob {
+                    queue: "mocked".to_string(),
+                    job_id: job_id.clone(),
+                },
+                WorkerEvent::StartedHandler {
+                    job_id: job_id.clone()
+                },
+                WorkerEvent::HandlerSucceeded {
+                    job_id: job_id.clone()
+                },
+                WorkerEvent::MovedToCompleted { job_id },
+                WorkerEvent::SlotFreed,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_failed_jobs_until_out_of_attempts() {
+        let commands = MockCommands::new()
+            .job(fetched("1", 2, 0))
+            .job(fetched("2", 1, 0));
+
+        assert_eq!(
+            process_with(failing_processor, Arc::new(commands)).await,
+            [
+                Call::MoveToActive,
+                Call::RetryJob {
+                    job_id: "1".to_string()
+                },
+                Call::MoveToActive,
+                Call::MoveToFinished {
+                    job_id: "2".to_string(),
+                    return_value: b"boom".to_vec(),
+                    target: MoveToFinishedTarget::Failed,
+                },
+                Call::MoveToActive,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn delays_retries_with_a_backoff() {
+        // Second attempt of the job, failing again
+        let job = JobBuilder::new()
+            .id("1".to_string())
+            .name("test".to_string())
+            .data(0)
+            .opts(r#"{"attempts":3,"backoff":{"type":"exponential","delay":1000}}"#.to_string())
+            .attempts_made(1)
+            .build();
+
+        let commands = MockCommands::new().job(MoveToActiveReturn::Job(Box::new(job)));
+
+        assert_eq!(
+            process_with(failing_processor, Arc::new(commands)).await,
+            [
+                Call::MoveToActive,
+                Call::MoveToDelayed {
+                    job_id: "1".to_string(),
+                    delay: 2000,
+                },
+                Call::MoveToActive,
+            ]
+        );
+    }
+
+    static RETRY_OUTCOMES: Mutex<Vec<JobOutcome>> = Mutex::new(vec![]);
+
+    fn record_retry_outcome(outcome: &JobOutcome) {
+        RETRY_OUTCOMES.lock().unwrap().push(outcome.clone());
+    }
+
+    /// Fails jobs of 0, doubles the others.
+    fn failing_on_zero_processor(job: Job<u32>) -> Result<u32> {
+        match job.data {
+            0 => Err(anyhow!("boom")),
+            data => Ok(data * 2),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_retries_to_the_outcome_callbacks() {
+        let attempt = |id: &str, data: u32, attempts_made: u32| {
+            MoveToActiveReturn::Job(Box::new(
+                JobBuilder::new()
+                    .id(id.to_string())
+                    .name("test".to_string())
+                    .data(data)
+                    .opts(
+                        r#"{"attempts":3,"backoff":{"type":"exponential","delay":1000}}"#
+                            .to_string(),
+                    )
+                    .attempts_made(attempts_made)
+                    .build(),
+            ))
+        };
+        // Second attempt failing again, then the third one succeeding
+        let commands = MockCommands::new()
+            .job(attempt("1", 0, 1))
+            .job(attempt("1", 1, 2));
+        let mut worker = mocked_worker(failing_on_zero_processor, Arc::new(commands))
+            .on_failed(record_retry_outcome)
+            .on_completed(record_retry_outcome);
+
+        run_processor_task(&mut worker).await;
+
+        let outcomes = RETRY_OUTCOMES.lock().unwrap();
+        let [failed, completed] = outcomes.as_slice() else {
+            panic!("unexpected outcomes {:?}", outcomes);
+        };
+
+        assert!(failed.will_retry());
+        assert_eq!(failed.retry_in, Some(Duration::from_millis(2000)));
+        assert_eq!(failed.attempts_made, 2);
+        assert!(completed.is_completed());
+        assert!(!completed.will_retry());
+        assert_eq!(completed.attempts_made, 3);
+    }
+
+    #[tokio::test]
+    async fn keeps_processing_after_failing_a_parent_with_pending_children() {
+        let commands = MockCommands::new()
+            .job(fetched("1", 1, 0))
+            .job(fetched("2", 1, 0))
+            .pending_dependencies("1");
+
+        assert_eq!(
+            process_with(failing_processor, Arc::new(commands)).await,
+            [
+                Call::MoveToActive,
+                Call::MoveToFinished {
+                    job_id: "1".to_string(),
+                    return_value: b"boom".to_vec(),
+                    target: MoveToFinishedTarget::Failed,
+                },
+                Call::MoveToActive,
+                Call::MoveToFinished {
+                    job_id: "2".to_string(),
+                    return_value: b"boom".to_vec(),
+                    target: MoveToFinishedTarget::Failed,
+                },
+                Call::MoveToActive,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_jobs_with_an_unknown_backoff_strategy() {
+        let job = JobBuilder::new()
+            .id("1".to_string())
+            .name("test".to_string())
+            .data(0)
+            .opts(r#"{"attempts":3,"backoff":{"type":"unregistered"}}"#.to_string())
+            .build();
+
+        let commands = MockCommands::new().job(MoveToActiveReturn::Job(Box::new(job)));
+
+        assert_eq!(
+            process_with(failing_processor, Arc::new(commands)).await,
+            [
+                Call::MoveToActive,
+                Call::MoveToFinished {
+                    job_id: "1".to_string(),
+                    return_value: b"Unknown backoff strategy unregistered, register it with \
+                        Worker::backoff_strategy"
+                        .to_vec(),
+                    target: MoveToFinishedTarget::Failed,
+                },
+                Call::MoveToActive,
+            ]
+        );
+    }
+
+    static HANDLER_THREADS: Mutex<Vec<std::thread::ThreadId>> = Mutex::new(vec![]);
+
+    fn thread_recording_processor(job: Job<u32>) -> Result<u32> {
+        HANDLER_THREADS
+            .lock()
+            .unwrap()
+            .push(std::thread::current().id());
+
+        Ok(job.data)
+    }
+
+    #[tokio::test]
+    async fn runs_handlers_on_the_blocking_pool_in_blocking_mode() {
+        for blocking in [false, true] {
+            let commands = Arc::new(MockCommands::new().job(fetched("1", 1, 0)));
+            let mut worker = mocked_worker(thread_recording_processor, commands.clone());
+
+            worker.blocking = blocking;
+            run_processor_task(&mut worker).await;
+
+            // Completed either way
+            assert!(commands.calls().contains(&Call::MoveToFinished {
+                job_id: "1".to_string(),
+                return_value: b"0".to_vec(),
+                target: MoveToFinishedTarget::Completed,
+            }));
+        }
+
+        // The test's runtime runs its tasks on this very thread, so only the
+        // handler run in blocking mode is off it
+        let current = std::thread::current().id();
+        let threads = HANDLER_THREADS.lock().unwrap();
+
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0], current);
+        assert_ne!(threads[1], current);
+    }
+
+    static AT_MOST_ONCE_COMMANDS: std::sync::OnceLock<Arc<MockCommands<u32>>> =
+        std::sync::OnceLock::new();
+    static REMOVED_BEFORE_HANDLER: AtomicBool = AtomicBool::new(false);
+
+    /// Fails, recording whether its job was already removed.
+    fn removal_checking_processor(job: Job<u32>) -> Result<()> {
+        let removed = AT_MOST_ONCE_COMMANDS
+            .get()
+            .unwrap()
+            .calls()
+            .contains(&Call::RemoveJob { job_id: job.id });
+
+        REMOVED_BEFORE_HANDLER.store(removed, Ordering::SeqCst);
+        Err(anyhow!("boom"))
+    }
+
+    #[tokio::test]
+    async fn removes_at_most_once_jobs_before_processing() {
+        let commands = AT_MOST_ONCE_COMMANDS
+            .get_or_init(|| Arc::new(MockCommands::new().job(fetched("1", 3, 0))))
+            .clone();
+        let mut worker = mocked_worker(removal_checking_processor, commands.clone());
+        worker.delivery_mode = DeliveryMode::AtMostOnce;
+
+        run_processor_task(&mut worker).await;
+
+        assert!(REMOVED_BEFORE_HANDLER.load(Ordering::SeqCst));
+        // Neither retried nor moved to failed, despite its attempts left
+        assert_eq!(
+            commands.calls(),
+            [
+                Call::MoveToActive,
+                Call::RemoveJob {
+                    job_id: "1".to_string()
+                },
+                Call::MoveToActive,
+            ]
+        );
+    }
+
+    /// Acks even numbers and nacks odd ones, from another thread, once its
+    /// result is "committed". Drops the handle of jobs numbered 2 or more.
+    fn committing_processor(job: Job<u32>) -> Result<u32> {
+        let ack_handle = job.ack_handle().unwrap();
+
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+
+            match job.data {
+                0 => ack_handle.ack(),
+                1 => ack_handle.nack("rolled back"),
+                _ => drop(ack_handle),
+            }
+        });
+
+        Ok(job.data)
+    }
+
+    #[tokio::test]
+    async fn completes_jobs_once_acked() {
+        let commands = Arc::new(
+            MockCommands::new()
+                .job(fetched("1", 1, 0))
+                .job(fetched("2", 1, 1))
+                .job(fetched("3", 1, 2)),
+        );
+        let mut worker = mocked_worker(committing_processor, commands.clone());
+
+        worker.manual_ack = true;
+        run_processor_task(&mut worker).await;
+
+        assert_eq!(
+            commands.calls(),
+            [
+                Call::MoveToActive,
+                Call::MoveToFinished {
+                    job_id: "1".to_string(),
+                    return_value: b"0".to_vec(),
+                    target: MoveToFinishedTarget::Completed,
+                },
+                Call::MoveToActive,
+                Call::MoveToFinished {
+                    job_id: "2".to_string(),
+                    return_value: b"rolled back".to_vec(),
+                    target: MoveToFinishedTarget::Failed,
+                },
+                Call::MoveToActive,
+                Call::MoveToFinished {
+                    job_id: "3".to_string(),
+                    return_value: b"Job was not acked".to_vec(),
+                    target: MoveToFinishedTarget::Failed,
+                },
+                Call::MoveToActive,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn nacks_jobs_not_acked_in_time() {
+        let commands = Arc::new(MockCommands::new().job(fetched("1", 1, 0)));
+        let mut worker = mocked_worker(
+            |job: Job<u32>| {
+                // Never acked nor dropped
+                std::mem::forget(job.ack_handle());
+                Ok(job.data)
+            },
+            commands.clone(),
+        );
+
+        worker.manual_ack = true;
+        worker.ack_timeout = Some(Duration::from_millis(50));
+        run_processor_task(&mut worker).await;
+
+        assert_eq!(
+            commands.calls()[1],
+            Call::MoveToFinished {
+                job_id: "1".to_string(),
+                return_value: b"Job was not acked within 50ms".to_vec(),
+                target: MoveToFinishedTarget::Failed,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn processes_jobs_fetched_on_completion() {
+        let commands = MockCommands::new()
+            .job(fetched("1", 1, 1))
+            .next(fetched("2", 1, 2));
+
+        assert_eq!(
+            process_with(doubling_processor, Arc::new(commands)).await,
+            [
+                Call::MoveToActive,
+                Call::MoveToFinished {
+                    job_id: "1".to_string(),
+                    return_value: b"2".to_vec(),
+                    target: MoveToFinishedTarget::Completed,
+                },
+                Call::MoveToFinished {
+                    job_id: "2".to_string(),
+                    return_value: b"4".to_vec(),
+                    target: MoveToFinishedTarget::Completed,
+                },
+                Call::MoveToActive,
+            ]
+        );
+    }
+
+    #[test]
+    fn pulls_from_queues_in_priority_order() {
+        let processor = |_job: Job<()>| Ok(());
+        let worker = Worker::new(
+            "default".to_string(),
+            "redis://localhost:6379".to_string(),
+            Prefix::default(),
+            1,
+            processor,
+        );
+
+        assert_eq!(worker.queues.len(), 1);
+        assert_eq!(worker.queues[0].prefix, "bull:default:");
+
+        let worker = Worker::with_options(
+            "default".to_string(),
+            "redis://localhost:6379".to_string(),
+            WorkerOptions::new().queue_priority(vec!["urgent".to_string(), "bulk".to_string()]),
+            processor,
+        );
+        let prefixes: Vec<&str> = worker
+            .queues
+            .iter()
+            .map(|queue| queue.prefix.as_str())
+            .collect();
+
+        assert_eq!(prefixes, vec!["bull:urgent:", "bull:bulk:"]);
+        assert_eq!(worker.get_prefixed_key("meta"), "bull:urgent:meta");
+
+        let worker = Worker::with_options(
+            "default".to_string(),
+            "redis://localhost:6379".to_string(),
+            WorkerOptions::new().prefix(Prefix::new("{app}").unwrap()),
+            processor,
+        );
+
+        assert_eq!(worker.get_prefixed_key("meta"), "{app}:default:meta");
+    }
+
+    #[tokio::test]
+    async fn fetches_from_higher_priority_queues_first() {
+        // `urgent` gets a job once `bulk` was polled
+        let commands = Arc::new(
+            MockCommands::new()
+                .job_on("bull:urgent:", MoveToActiveReturn::None)
+                .job_on("bull:urgent:", fetched("u1", 1, 1))
+                .job_on("bull:bulk:", fetched("b1", 1, 2))
+                .job_on("bull:bulk:", fetched("b2", 1, 3)),
+        );
+        let mut worker = Worker::with_options(
+            "mocked".to_string(),
+            "redis://127.0.0.1:1".to_string(),
+            WorkerOptions::new().queue_priority(vec!["urgent".to_string(), "bulk".to_string()]),
+            doubling_processor,
+        );
+        worker.commands = commands.clone();
+
+        run_processor_task(&mut worker).await;
+
+        let finished: Vec<String> = commands
+            .calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::MoveToFinished { job_id, .. } => Some(job_id),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(finished, ["b1", "u1", "b2"]);
+        assert_eq!(
+            commands
+                .calls()
+                .iter()
+                .filter(|call| **call == Call::MoveToActive)
+                .count(),
+            // urgent, bulk; urgent; urgent, bulk; urgent, bulk
+            7
+        );
+    }
+
+    #[test]
+    fn swaps_handlers_at_runtime() {
+        let handlers = Handlers::new();
+        let run = |name: &str| {
+            let job = JobBuilder::new()
+                .id("1".to_string())
+                .name(name.to_string())
+                .data(3)
+                .build();
+
+            run_process_fn(&*handlers.resolve(name, doubling_processor), job).unwrap()
+        };
+
+        assert_eq!(run("rule"), 6);
+
+        handlers.set("rule", Arc::new(|job: Job<u32>| Ok(job.data * 3)));
+
+        assert_eq!(run("rule"), 9);
+        assert_eq!(run("other"), 6);
+
+        handlers.remove("rule");
+
+        assert_eq!(run("rule"), 6);
+    }
+
+    #[test]
+    fn maps_tokens_to_worker_ids() {
+        let mut token = WorkerToken::new();
+        let first = token.next();
+
+        assert_eq!(token_worker_id(&first), token.token);
+        assert_eq!(token_worker_id("0"), "0");
+    }
+
+    #[test]
+    fn turns_panics_into_errors() {
+        let job = crate::job::JobBuilder::new()
+            .id("1".to_string())
+            .name("panics".to_string())
+            .data(())
+            .build();
+
+        assert_eq!(
+            run_process_fn(panicking_processor, job)
+                .unwrap_err()
+                .to_string(),
+            "Process function panicked: boom"
+        );
+    }
+
+    #[test]
+    fn lowers_concurrency_to_queue_level_concurrency() {
+        assert_eq!(effective_concurrency(8, None), 8);
+        assert_eq!(effective_concurrency(8, Some(2)), 2);
+        assert_eq!(effective_concurrency(2, Some(8)), 2);
+        assert_eq!(effective_concurrency(8, Some(0)), 1);
+    }
+
+    #[test]
+    fn loads_worker_scripts() {
+        assert!(check_scripts().is_ok());
+    }
+
+    #[test]
+    fn clamps_concurrency() {
+        assert_eq!(WorkerOptions::new().concurrency, 1);
+        assert_eq!(WorkerOptions::new().concurrency(0).concurrency, 1);
+        assert!(
+            WorkerOptions::new()
+                .concurrency_from_parallelism()
+                .concurrency
+                >= 1
+        );
+    }
+
+    #[test]
+    fn stores_raw_return_values_as_is() {
+        assert_eq!(encode_return_value(&"done").unwrap(), b"\"done\"".to_vec());
+        assert_eq!(
+            encode_return_value(&RawReturnValue(vec![0, 159, 146, 150])).unwrap(),
+            vec![0, 159, 146, 150]
+        );
+    }
+
+    #[test]
+    fn fails_on_return_values_that_cant_be_serialized() {
+        let map = std::collections::HashMap::from([((1, 2), "point")]);
+
+        assert!(encode_return_value(&map)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Failed to serialize the return value"));
+    }
+
+    #[tokio::test]
+    async fn resume_wakes_paused_worker() {
+        let handle = WorkerHandle::new();
+
+        handle.pause();
+        assert!(handle.is_paused());
+        assert!(!handle.is_fetching());
+
+        let waiter = handle.clone();
+        let woken = tokio::spawn(async move { waiter.wake.notified().await });
+
+        handle.resume();
+
+        tokio::time::timeout(Duration::from_secs(1), woken)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(handle.is_fetching());
+    }
+
+    #[tokio::test]
+    async fn close_stops_the_worker() {
+        let mut worker = Worker::new(
+            "closing".to_string(),
+            "redis://127.0.0.1:1".to_string(),
+            Prefix::default(),
+            1,
+            panicking_processor,
+        );
+
+        worker.handle().close();
+        assert!(!worker.handle().is_fetching());
+
+        tokio::time::timeout(Duration::from_secs(1), worker.run())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_permit_of_the_shared_semaphore() {
+        let commands = Arc::new(MockCommands::new().job(fetched("1", 1, 1)));
+        static PROCESS_TIME_MS: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+        let mut worker = mocked_worker(doubling_processor, commands.clone());
+        let semaphore = Arc::new(Semaphore::new(0));
+        worker.semaphore = Some(semaphore.clone());
+        worker.callbacks.on_completed = Some(|outcome| {
+            PROCESS_TIME_MS.store(outcome.process_time.as_millis() as usize, Ordering::Relaxed)
+        });
+
+        let task = tokio::spawn(async move { run_processor_task(&mut worker).await });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(commands.calls(), [Call::MoveToActive]);
+
+        semaphore.add_permits(1);
+        task.await.unwrap();
+
+        // The wait for the permit is not counted
+        assert!(PROCESS_TIME_MS.load(Ordering::Relaxed) < 100);
+
+        assert!(matches!(
+            commands.calls()[1],
+            Call::MoveToFinished {
+                target: MoveToFinishedTarget::Completed,
+                ..
+            }
+        ));
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn holds_as_many_units_as_the_job_weight() {
+        let heavy = MoveToActiveReturn::Job(Box::new(
+            JobBuilder::new()
+                .id("1".to_string())
+                .name("transcode".to_string())
+                .data(1)
+                .opts(r#"{"attempts":1,"weight":3}"#.to_string())
+                .build(),
+        ));
+        let commands = Arc::new(MockCommands::new().job(heavy));
+        static PROCESS_TIME_MS: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+        let mut worker = mocked_worker(doubling_processor, commands.clone());
+        worker.pool = Arc::new(ProcessorPool::new(4));
+        worker.callbacks.on_completed = Some(|outcome| {
+            PROCESS_TIME_MS.store(outcome.process_time.as_millis() as usize, Ordering::Relaxed)
+        });
+        let pool = worker.pool.clone();
+
+        // Two units taken by other jobs
+        let taken = pool.capacity.try_acquire_many(2).unwrap();
+        let task = tokio::spawn(async move { run_processor_task(&mut worker).await });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(commands.calls(), [Call::MoveToActive]);
+
+        drop(taken);
+        task.await.unwrap();
+
+        // The wait for the units is not counted
+        assert!(PROCESS_TIME_MS.load(Ordering::Relaxed) < 100);
+
+        assert!(matches!(
+            commands.calls()[1],
+            Call::MoveToFinished {
+                target: MoveToFinishedTarget::Completed,
+                ..
+            }
+        ));
+        assert_eq!(pool.capacity.available_permits(), 4);
+    }
+
+    #[tokio::test]
+    async fn is_not_ready_until_redis_answers() {
+        static READY: AtomicBool = AtomicBool::new(false);
+
+        let mut worker = Worker::with_options(
+            "unreachable".to_string(),
+            "redis://127.0.0.1:1".to_string(),
+            WorkerOptions::new().max_reconnect_attempts(2),
+            panicking_processor,
+        )
+        .on_ready(|| READY.store(true, Ordering::Relaxed));
+
+        let err = tokio::time::timeout(Duration::from_secs(5), worker.process(true))
+            .await
+            .unwrap()
+            .unwrap_err();
+
+        assert_eq!(err.downcast_ref::<ConnectionLost>().unwrap().failures, 2);
+        assert!(!READY.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn parks_tasks_past_the_concurrency() {
+        let commands = Arc::new(MockCommands::new().job(fetched("1", 1, 1)));
+        let mut worker = mocked_worker(doubling_processor, commands.clone());
+        let task = worker.spawn_processor_task(1);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(commands.calls().is_empty());
+
+        worker.pool.concurrency.store(2, Ordering::Relaxed);
+        worker.pool.jobs_added.notify_waiters();
+
+        tokio::time::timeout(Duration::from_secs(5), worker.pool.task_idle.notified())
+            .await
+            .unwrap();
+        assert_eq!(commands.calls()[0], Call::MoveToActive);
+
+        worker.handle.close();
+        worker.pool.jobs_added.notify_waiters();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_fetching_once_the_concurrency_is_lowered() {
+        let commands = Arc::new(
+            MockCommands::new()
+                .job(fetched("1", 1, 1))
+                .job(fetched("2", 1, 2)),
+        );
+        let mut worker = mocked_worker(doubling_processor, commands.clone());
+        // Holds the first job until the concurrency is lowered
+        let semaphore = Arc::new(Semaphore::new(0));
+        worker.semaphore = Some(semaphore.clone());
+        let pool = worker.pool.clone();
+
+        let task = tokio::spawn(async move { run_processor_task(&mut worker).await });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        pool.concurrency.store(0, Ordering::Relaxed);
+        semaphore.add_permits(1);
+        task.await.unwrap();
+
+        assert_eq!(commands.calls().len(), 2);
+        assert!(matches!(
+            &commands.calls()[1],
+            Call::MoveToFinished { job_id, .. } if job_id == "1"
+        ));
+    }
+
+    static CANCELLABLE_STARTED: AtomicBool = AtomicBool::new(false);
+    static CANCELLATION_SEEN: AtomicBool = AtomicBool::new(false);
+
+    /// Runs until its job is cancelled, for 5s at most, recording whether it
+    /// was.
+    fn cancellable_processor(job: Job<u32>) -> Result<u32> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        CANCELLABLE_STARTED.store(true, Ordering::SeqCst);
+
+        while !job.cancellation_token().is_cancelled() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        CANCELLATION_SEEN.store(job.cancellation_token().is_cancelled(), Ordering::SeqCst);
+        Ok(job.data)
+    }
+
+    #[tokio::test]
+    async fn close_cancels_the_active_jobs() {
+        let commands = Arc::new(MockCommands::new().job(fetched("1", 1, 0)));
+        let mut worker = mocked_worker(cancellable_processor, commands.clone());
+
+        // Off the test's runtime thread, which the handler would block
+        worker.blocking = true;
+        let task = worker.spawn_processor_task(0);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !CANCELLABLE_STARTED.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        worker.handle().close();
+        worker.pool.jobs_added.notify_waiters();
+        task.await.unwrap();
+
+        assert!(CANCELLATION_SEEN.load(Ordering::SeqCst));
+        // Still finished once its handler returns
+        assert_eq!(
+            commands.calls()[..2],
+            [
+                Call::MoveToActive,
+                Call::MoveToFinished {
+                    job_id: "1".to_string(),
+                    return_value: b"0".to_vec(),
+                    target: MoveToFinishedTarget::Completed,
+                },
+            ]
+        );
     }
 }
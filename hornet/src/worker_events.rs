@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+/// Events buffered for a subscriber that falls behind before the oldest are
+/// dropped, see `broadcast::error::RecvError::Lagged`.
+const CAPACITY: usize = 1024;
+
+/// Internal transition of a worker, emitted with the `debug-events` feature
+/// to trace or test how its main loop and processor tasks interact. See
+/// `Worker::events`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WorkerEvent {
+    /// A job was moved to active, by a fetch or along with the completion of
+    /// the previous job
+    PulledJob {
+        queue: String,
+        job_id: String,
+    },
+    StartedHandler {
+        job_id: String,
+    },
+    HandlerSucceeded {
+        job_id: String,
+    },
+    HandlerFailed {
+        job_id: String,
+        error: String,
+    },
+    /// Not emitted for the completions of `WorkerOptions::batch_completions`
+    MovedToCompleted {
+        job_id: String,
+    },
+    /// A processor task ran out of jobs and gave its slot back
+    SlotFreed,
+    /// The worker is waiting for new jobs
+    Blocked,
+    /// The worker stopped waiting, either woken by a new job or on timeout
+    Woke {
+        blocked_for: Duration,
+    },
+}
+
+#[derive(Clone)]
+pub(crate) struct WorkerEvents {
+    sender: broadcast::Sender<WorkerEvent>,
+}
+
+impl WorkerEvents {
+    pub fn new() -> Self {
+        WorkerEvents {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<WorkerEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Sends the event to the current subscribers, if any.
+    pub fn emit(&self, event: WorkerEvent) {
+        let _ = self.sender.send(event);
+    }
+}
+
+/// `HandlerSucceeded` or `HandlerFailed`, depending on the handler's result.
+pub(crate) fn handler_finished<T>(job_id: &str, res: &anyhow::Result<T>) -> WorkerEvent {
+    match res {
+        Ok(_) => WorkerEvent::HandlerSucceeded {
+            job_id: job_id.to_string(),
+        },
+        Err(err) => WorkerEvent::HandlerFailed {
+            job_id: job_id.to_string(),
+            error: err.to_string(),
+        },
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::Result;
+use redis::{Commands, ConnectionLike};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::SystemTime};
+
+/// Hash of the queue, keyed by worker id, holding the live workers.
+pub(crate) const WORKERS_KEY: &str = "workers";
+/// Milliseconds without a heartbeat after which a worker is considered gone.
+/// Workers send one every half lock duration (15s by default).
+pub(crate) const HEARTBEAT_TTL: u64 = 60_000;
+
+/// A worker registered in its queue's `workers` hash, refreshed on every
+/// heartbeat.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkerInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub hostname: String,
+    /// Timestamp in ms of when the worker started
+    #[serde(rename = "startedAt")]
+    pub started_at: u128,
+    /// Timestamp in ms of the worker's last heartbeat
+    #[serde(rename = "heartbeatAt")]
+    pub heartbeat_at: u128,
+}
+
+impl WorkerInfo {
+    pub(crate) fn new(id: String, name: Option<String>) -> Self {
+        let now = now();
+
+        WorkerInfo {
+            id,
+            name,
+            hostname: hostname(),
+            started_at: now,
+            heartbeat_at: now,
+        }
+    }
+
+    /// Whether the worker sent a heartbeat in the last `ttl` milliseconds.
+    pub fn is_alive(&self, ttl: u64) -> bool {
+        now().saturating_sub(self.heartbeat_at) <= ttl as u128
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Writes the worker to the `workers` hash and refreshes the hash's TTL, so it
+/// goes away once every worker of the queue is gone.
+pub(crate) fn heartbeat(
+    prefix: &str,
+    client: &mut impl ConnectionLike,
+    info: &mut WorkerInfo,
+    ttl: u64,
+) -> Result<()> {
+    let key = format!("{}{}", prefix, WORKERS_KEY);
+
+    info.heartbeat_at = now();
+
+    redis::pipe()
+        .atomic()
+        .hset(&key, &info.id, serde_json::to_string(info)?)
+        .ignore()
+        .pexpire(&key, ttl as i64)
+        .ignore()
+        .query::<()>(client)?;
+
+    Ok(())
+}
+
+pub(crate) fn unregister(prefix: &str, client: &mut impl ConnectionLike, id: &str) -> Result<()> {
+    let _: () = client.hdel(format!("{}{}", prefix, WORKERS_KEY), id)?;
+
+    Ok(())
+}
+
+/// Returns the workers that sent a heartbeat in the last `ttl` milliseconds,
+/// removing the others from the hash.
+pub(crate) fn list(
+    prefix: &str,
+    client: &mut impl ConnectionLike,
+    ttl: u64,
+) -> Result<Vec<WorkerInfo>> {
+    let key = format!("{}{}", prefix, WORKERS_KEY);
+    let hash: HashMap<String, String> = client.hgetall(&key)?;
+    let mut workers = Vec::with_capacity(hash.len());
+
+    for (id, raw) in hash {
+        match serde_json::from_str::<WorkerInfo>(&raw) {
+            Ok(info) if info.is_alive(ttl) => workers.push(info),
+            _ => {
+                let _: () = client.hdel(&key, id)?;
+            }
+        }
+    }
+
+    workers.sort_by_key(|info| info.started_at);
+
+    Ok(workers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_workers_without_heartbeat() {
+        let mut info = WorkerInfo::new("id".to_string(), Some("worker".to_string()));
+
+        assert!(info.is_alive(1000));
+
+        info.heartbeat_at -= 2000;
+
+        assert!(!info.is_alive(1000));
+        assert!(info.is_alive(5000));
+    }
+}
@@ -0,0 +1,3 @@
+--- @include "fixture_shared_include_child"
+--- @include "includes/fixture_shared_include_child_2"
+--- file: fixture_shared_include.lua
@@ -0,0 +1,2 @@
+--- @include "includes/strings"
+--- file: fixture_shared_include_child.lua